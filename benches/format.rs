@@ -0,0 +1,88 @@
+use cel_fmt::doc::Doc;
+use cel_fmt::{format_cel, to_doc, FormatOptions};
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+
+/// Small expressions of the kind policy engines format by the thousand
+const SMALL: &[&str] = &[
+    "x",
+    "a && b",
+    "size(items) > 0",
+    "user.age >= 18 && user.verified",
+    "request.auth.claims.email.endsWith('@example.com')",
+    "has(resource.labels.env) ? resource.labels.env : 'dev'",
+    "[1, 2, 3].exists(n, n > 2)",
+    "{'role': 'admin', 'level': 3}",
+];
+
+fn bench_small(c: &mut Criterion) {
+    let options = FormatOptions::default();
+
+    c.bench_function("format_cel small", |b| {
+        b.iter(|| {
+            for source in SMALL {
+                black_box(format_cel(black_box(source), &options).unwrap());
+            }
+        })
+    });
+
+    // The fast path against the group-by-group render it replaces
+    let docs: Vec<_> = SMALL
+        .iter()
+        .map(|source| to_doc(source, &options).unwrap())
+        .collect();
+    c.bench_function("render small", |b| {
+        b.iter(|| {
+            for doc in &docs {
+                black_box(doc.render(options.max_width, "  "));
+            }
+        })
+    });
+    c.bench_function("measure_flat + render_flat small", |b| {
+        b.iter(|| {
+            for doc in &docs {
+                if doc.measure_flat().is_some_and(|w| w <= options.max_width) {
+                    black_box(doc.render_flat());
+                }
+            }
+        })
+    });
+}
+
+/// One large policy: two hundred clauses with calls, lists, and macros
+fn large_source() -> String {
+    (0..200)
+        .map(|i| {
+            format!(
+                "(has(request.auth.claims.role_{i}) && request.auth.claims.role_{i} in \
+                 ['admin', 'editor'] && request.items.filter(x, x.size() > {i}).all(y, y != {i}))"
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(" || ")
+}
+
+fn bench_large(c: &mut Criterion) {
+    let options = FormatOptions::default();
+    let source = large_source();
+
+    c.bench_function("format_cel large", |b| {
+        b.iter(|| black_box(format_cel(black_box(&source), &options).unwrap()))
+    });
+
+    // Flattening is a separate pass, so it pays off for a document that is
+    // rendered more than once; the output is the same either way
+    let doc = to_doc(&source, &options).unwrap();
+    let flat = doc.clone().flatten();
+    c.bench_function("render large nested", |b| {
+        b.iter(|| black_box(doc.render(options.max_width, "  ")))
+    });
+    c.bench_function("render large flattened", |b| {
+        b.iter(|| black_box(flat.render(options.max_width, "  ")))
+    });
+    c.bench_function("flatten large", |b| {
+        b.iter_batched(|| doc.clone(), Doc::flatten, BatchSize::LargeInput)
+    });
+}
+
+criterion_group!(benches, bench_small, bench_large);
+criterion_main!(benches);
@@ -0,0 +1,276 @@
+use cel::common::ast::{EntryExpr, Expr, IdedExpr};
+use std::collections::BTreeSet;
+
+use crate::depth::check_depth;
+use crate::error::FormatError;
+use crate::formatter::{new_parser, to_doc};
+use crate::options::FormatOptions;
+
+/// Metrics about a CEL expression, as returned by [`analyze`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FormatStats {
+    /// Number of nodes of each kind in the parsed expression
+    pub nodes: NodeCounts,
+
+    /// Deepest nesting of nodes; a lone identifier has depth 1
+    pub max_depth: usize,
+
+    /// Number of macros such as `has()`, `all()`, or `map()`
+    pub macros: usize,
+
+    /// Number of groups that break across lines at the default width
+    pub broken_groups: usize,
+}
+
+/// Node counts by kind
+///
+/// These count the parsed syntax tree, so macros contribute the nodes of
+/// their expansion as well as what was written in the source.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NodeCounts {
+    pub idents: usize,
+    pub literals: usize,
+    pub selects: usize,
+    pub calls: usize,
+    pub lists: usize,
+    pub maps: usize,
+    pub structs: usize,
+    pub comprehensions: usize,
+}
+
+impl NodeCounts {
+    /// Total number of nodes
+    pub fn total(&self) -> usize {
+        self.idents
+            + self.literals
+            + self.selects
+            + self.calls
+            + self.lists
+            + self.maps
+            + self.structs
+            + self.comprehensions
+    }
+}
+
+/// Collect complexity metrics for a CEL expression
+///
+/// This parses and lays out the expression like `format_cel` with default
+/// options, but keeps the extra bookkeeping out of the formatting path.
+pub fn analyze(source: &str) -> anyhow::Result<FormatStats> {
+    let options = FormatOptions::default();
+    check_depth(source, &options)?;
+
+    let ast = new_parser(&options)
+        .parse(source)
+        .map_err(|e| FormatError::from_parse_errors(source, &e))?;
+
+    let mut stats = FormatStats::default();
+    count_nodes(&ast, 1, &mut stats);
+
+    let indent_str = " ".repeat(options.indent_width);
+    stats.broken_groups =
+        to_doc(source, &options)?.count_broken_groups(options.max_width, &indent_str);
+    Ok(stats)
+}
+
+/// The free variables of a CEL expression: the identifiers it references that
+/// no macro binds, sorted and without duplicates
+///
+/// In `items.all(i, i.price < limit)` these are `items` and `limit`. For a
+/// field path such as `request.auth.uid` only the root, `request`, is a
+/// variable.
+pub fn free_variables(source: &str) -> anyhow::Result<Vec<String>> {
+    let options = FormatOptions::default();
+    check_depth(source, &options)?;
+
+    let ast = new_parser(&options)
+        .parse(source)
+        .map_err(|e| FormatError::from_parse_errors(source, &e))?;
+
+    let mut free = BTreeSet::new();
+    collect_free(&ast, &mut Vec::new(), &mut free);
+    Ok(free.into_iter().collect())
+}
+
+/// Add the identifiers in `expr` that aren't in `bound` to `free`
+fn collect_free<'a>(expr: &'a IdedExpr, bound: &mut Vec<&'a str>, free: &mut BTreeSet<String>) {
+    match &expr.expr {
+        Expr::Unspecified | Expr::Literal(_) => {}
+        Expr::Ident(name) => {
+            if !bound.contains(&name.as_str()) {
+                free.insert(name.clone());
+            }
+        }
+        Expr::Select(select) => collect_free(&select.operand, bound, free),
+        Expr::Call(call) => {
+            if let Some(target) = &call.target {
+                collect_free(target, bound, free);
+            }
+            for arg in &call.args {
+                collect_free(arg, bound, free);
+            }
+        }
+        Expr::List(list) => {
+            for element in &list.elements {
+                collect_free(element, bound, free);
+            }
+        }
+        Expr::Map(map) => {
+            for entry in &map.entries {
+                if let EntryExpr::MapEntry(entry) = &entry.expr {
+                    collect_free(&entry.key, bound, free);
+                    collect_free(&entry.value, bound, free);
+                }
+            }
+        }
+        Expr::Struct(s) => {
+            for entry in &s.entries {
+                if let EntryExpr::StructField(field) = &entry.expr {
+                    collect_free(&field.value, bound, free);
+                }
+            }
+        }
+        Expr::Comprehension(comp) => {
+            // The range and initial value are outside the macro's scope
+            collect_free(&comp.iter_range, bound, free);
+            collect_free(&comp.accu_init, bound, free);
+
+            let outer = bound.len();
+            bound.push(&comp.accu_var);
+            collect_free(&comp.result, bound, free);
+            bound.push(&comp.iter_var);
+            if let Some(iter_var2) = &comp.iter_var2 {
+                bound.push(iter_var2);
+            }
+            collect_free(&comp.loop_cond, bound, free);
+            collect_free(&comp.loop_step, bound, free);
+            bound.truncate(outer);
+        }
+    }
+}
+
+/// Deepest nesting of nodes in an expression; a lone identifier has depth 1
+pub(crate) fn expr_depth(expr: &IdedExpr) -> usize {
+    let mut stats = FormatStats::default();
+    count_nodes(expr, 1, &mut stats);
+    stats.max_depth
+}
+
+fn count_nodes(expr: &IdedExpr, depth: usize, stats: &mut FormatStats) {
+    stats.max_depth = stats.max_depth.max(depth);
+    let nodes = &mut stats.nodes;
+    let depth = depth + 1;
+
+    match &expr.expr {
+        Expr::Unspecified => {}
+        Expr::Ident(_) => nodes.idents += 1,
+        Expr::Literal(_) => nodes.literals += 1,
+        Expr::Select(select) => {
+            nodes.selects += 1;
+            if select.test {
+                stats.macros += 1;
+            }
+            count_nodes(&select.operand, depth, stats);
+        }
+        Expr::Call(call) => {
+            nodes.calls += 1;
+            if let Some(target) = &call.target {
+                count_nodes(target, depth, stats);
+            }
+            for arg in &call.args {
+                count_nodes(arg, depth, stats);
+            }
+        }
+        Expr::List(list) => {
+            nodes.lists += 1;
+            for element in &list.elements {
+                count_nodes(element, depth, stats);
+            }
+        }
+        Expr::Map(map) => {
+            nodes.maps += 1;
+            for entry in &map.entries {
+                if let EntryExpr::MapEntry(entry) = &entry.expr {
+                    count_nodes(&entry.key, depth, stats);
+                    count_nodes(&entry.value, depth, stats);
+                }
+            }
+        }
+        Expr::Struct(s) => {
+            nodes.structs += 1;
+            for entry in &s.entries {
+                if let EntryExpr::StructField(field) = &entry.expr {
+                    count_nodes(&field.value, depth, stats);
+                }
+            }
+        }
+        Expr::Comprehension(comp) => {
+            nodes.comprehensions += 1;
+            stats.macros += 1;
+            for part in [
+                &comp.iter_range,
+                &comp.accu_init,
+                &comp.loop_cond,
+                &comp.loop_step,
+                &comp.result,
+            ] {
+                count_nodes(part, depth, stats);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_analyze() {
+        let stats = analyze("a.b > 1 && has(c.d)").unwrap();
+        assert_eq!(
+            stats.nodes,
+            NodeCounts {
+                idents: 2,
+                literals: 1,
+                selects: 2,
+                calls: 2,
+                ..NodeCounts::default()
+            }
+        );
+        assert_eq!(stats.nodes.total(), 7);
+        assert_eq!(stats.max_depth, 4);
+        assert_eq!(stats.macros, 1);
+        assert_eq!(stats.broken_groups, 0);
+    }
+
+    #[test]
+    fn test_analyze_macros_and_breaks() {
+        let source =
+            "items.all(item, item.enabled) && items.exists(item, item.name == 'a-very-long-name')";
+        let stats = analyze(source).unwrap();
+        assert_eq!(stats.nodes.comprehensions, 2);
+        assert_eq!(stats.macros, 2);
+        assert!(stats.broken_groups > 0);
+        assert!(analyze("a +").is_err());
+    }
+
+    #[test]
+    fn test_free_variables() {
+        assert_eq!(
+            free_variables("items.all(i, i.price < limit) && request.auth.uid == owner").unwrap(),
+            ["items", "limit", "owner", "request"]
+        );
+        // Only the macro body is in scope of the iteration variable
+        assert_eq!(
+            free_variables("x.exists(x, x > 0) && x.size() > 0 || [y].map(y, y + z)[0] > 1")
+                .unwrap(),
+            ["x", "y", "z"]
+        );
+        assert_eq!(
+            free_variables("items.map(i, items.filter(j, j < i)).size()").unwrap(),
+            ["items"]
+        );
+        assert_eq!(free_variables("1 + 2").unwrap(), Vec::<String>::new());
+        assert!(free_variables("a +").is_err());
+    }
+}
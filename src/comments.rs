@@ -0,0 +1,245 @@
+use crate::doc::Doc;
+use crate::formatter::wrap_collection;
+use crate::literals::{scan_number, skip_string};
+use crate::options::{CommentStyle, FormatOptions};
+
+/// Write a `//` comment according to `options.comment_style`
+///
+/// CEL only has line comments, so there are no block comments to convert.
+/// A comment that is nothing but slashes, like a `////` rule, is kept as is.
+pub(crate) fn format_comment(comment: &str, options: &FormatOptions) -> String {
+    let comment = comment.trim_end();
+    match options.comment_style {
+        CommentStyle::Preserve => comment.to_string(),
+        CommentStyle::Normalize => {
+            let text = comment.trim_start_matches('/');
+            let slashes = &comment[..comment.len() - text.len()];
+            match text.trim_start() {
+                "" => slashes.to_string(),
+                text => format!("{} {}", slashes, text),
+            }
+        }
+    }
+}
+
+/// Keep `//` comments that directly follow the opening bracket of a list, map,
+/// or struct
+///
+/// The parser drops comments, so they are recovered by scanning the source
+/// for collection brackets and matched to the collections in the document in
+/// source order. A collection with such a comment is forced to break, with
+/// the comment at the end of its opening line:
+///
+/// ```text
+/// [ // allowed values
+///   1,
+///   2,
+/// ]
+/// ```
+///
+/// If the number of collections in the document doesn't match the source
+/// (say, a custom formatter dropped one), the comments are left out rather
+/// than attached to the wrong collections.
+pub(crate) fn attach_open_comments(doc: Doc, source: &str, options: &FormatOptions) -> Doc {
+    let comments = open_bracket_comments(source);
+    if comments.iter().all(Option::is_none) || count_collections(&doc) != comments.len() {
+        return doc;
+    }
+
+    let mut comments = comments
+        .into_iter()
+        .map(|comment| comment.map(|comment| format_comment(&comment, options)));
+    attach(doc, &mut comments, options)
+}
+
+fn attach(
+    doc: Doc,
+    comments: &mut impl Iterator<Item = Option<String>>,
+    options: &FormatOptions,
+) -> Doc {
+    match doc {
+        Doc::Group(inner) if opens_collection(&inner) => {
+            let comment = comments.next().flatten();
+            let group = Doc::Group(Box::new(attach(*inner, comments, options)));
+            match comment {
+                Some(comment) => with_open_comment(group, comment),
+                None => group,
+            }
+        }
+        Doc::Concat(mut parts) if is_inline_list(&parts) => {
+            let comment = comments.next().flatten();
+            let elements = attach(parts.swap_remove(1), comments, options);
+            match comment {
+                Some(comment) => {
+                    // Elements are joined with ", " separators at odd indices
+                    let docs = match elements {
+                        Doc::Concat(docs) => docs.into_iter().step_by(2).collect(),
+                        doc => vec![doc],
+                    };
+                    with_open_comment(wrap_collection("[", docs, "]", options), comment)
+                }
+                None => Doc::concat(vec![Doc::text("["), elements, Doc::text("]")]),
+            }
+        }
+        Doc::Text(text) if text == "[]" || text == "{}" => match comments.next().flatten() {
+            Some(comment) => Doc::group(Doc::concat(vec![
+                Doc::text(&text[..1]),
+                Doc::text(format!(" {}", comment)),
+                Doc::break_parent(),
+                Doc::soft_line(),
+                Doc::text(&text[1..]),
+            ])),
+            None => Doc::Text(text),
+        },
+        Doc::Concat(docs) => Doc::Concat(
+            docs.into_iter()
+                .map(|d| attach(d, comments, options))
+                .collect(),
+        ),
+        Doc::Indent(d) => Doc::Indent(Box::new(attach(*d, comments, options))),
+        Doc::Align(width, d) => Doc::Align(width, Box::new(attach(*d, comments, options))),
+        Doc::Group(d) => Doc::Group(Box::new(attach(*d, comments, options))),
+        Doc::BrokenGroup(d) => Doc::BrokenGroup(Box::new(attach(*d, comments, options))),
+        Doc::IfBreak {
+            break_doc,
+            flat_doc,
+        } => Doc::IfBreak {
+            break_doc: Box::new(attach(*break_doc, comments, options)),
+            flat_doc: Box::new(attach(*flat_doc, comments, options)),
+        },
+        other => other,
+    }
+}
+
+/// Put a comment after the opening bracket of a collection group and force
+/// the group to break
+fn with_open_comment(group: Doc, comment: String) -> Doc {
+    let Doc::Group(inner) = group else {
+        return group;
+    };
+    let Doc::Concat(mut parts) = *inner else {
+        return Doc::Group(inner);
+    };
+    parts.splice(
+        1..1,
+        [Doc::text(format!(" {}", comment)), Doc::break_parent()],
+    );
+    Doc::group(Doc::concat(parts))
+}
+
+/// Count the collections in a document, as matched by `attach`
+fn count_collections(doc: &Doc) -> usize {
+    let own = usize::from(match doc {
+        Doc::Group(inner) => opens_collection(inner),
+        Doc::Concat(parts) => is_inline_list(parts),
+        Doc::Text(text) => text == "[]" || text == "{}",
+        _ => false,
+    });
+    let nested = match doc {
+        Doc::Concat(docs) => docs.iter().map(count_collections).sum(),
+        Doc::Indent(d) | Doc::Align(_, d) | Doc::Group(d) | Doc::BrokenGroup(d) => {
+            count_collections(d)
+        }
+        Doc::IfBreak {
+            break_doc,
+            flat_doc,
+        } => count_collections(break_doc) + count_collections(flat_doc),
+        _ => 0,
+    };
+    own + nested
+}
+
+/// Check if a group's contents are a list, map, or struct body that may
+/// break, which opens with `[` or `{`
+fn opens_collection(body: &Doc) -> bool {
+    matches!(
+        body,
+        Doc::Concat(parts)
+            if matches!(parts.first(), Some(Doc::Text(open)) if open == "[" || open == "{")
+    )
+}
+
+/// Check for a short list of literals and identifiers, which is always kept on
+/// one line
+fn is_inline_list(parts: &[Doc]) -> bool {
+    parts.len() == 3
+        && matches!(&parts[0], Doc::Text(open) if open == "[")
+        && matches!(&parts[2], Doc::Text(close) if close == "]")
+}
+
+/// For each bracket that opens a list, map, or struct, in source order, the
+/// `//` comment that follows it on the same line, if any
+///
+/// A `[` right after an operand (an identifier, literal, or closing bracket)
+/// is an index, not a list, and is skipped.
+fn open_bracket_comments(source: &str) -> Vec<Option<String>> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut comments = Vec::new();
+    let mut after_operand = false;
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+
+        if c == '/' && next == Some('/') {
+            while i < chars.len() && chars[i] != '\n' {
+                i += 1;
+            }
+        } else if c == '"' || c == '\'' {
+            i = skip_string(&chars, i, false);
+            after_operand = true;
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            let word: String = chars[start..i].iter().collect::<String>().to_lowercase();
+            let is_prefix = matches!(word.as_str(), "r" | "b" | "rb" | "br");
+            if is_prefix && matches!(chars.get(i), Some('"') | Some('\'')) {
+                i = skip_string(&chars, i, word.contains('r'));
+            }
+            after_operand = word != "in";
+        } else if c.is_ascii_digit() || (c == '.' && next.is_some_and(|n| n.is_ascii_digit())) {
+            i = scan_number(&chars, i);
+            after_operand = true;
+        } else if c == '{' || (c == '[' && !after_operand) {
+            i += 1;
+            comments.push(trailing_comment(&chars, i));
+            after_operand = false;
+        } else if matches!(c, ')' | ']' | '}') {
+            i += 1;
+            after_operand = true;
+        } else {
+            if !c.is_whitespace() {
+                after_operand = false;
+            }
+            i += 1;
+        }
+    }
+
+    comments
+}
+
+/// The `//` comment starting at `start`, after spaces, on the same line
+fn trailing_comment(chars: &[char], start: usize) -> Option<String> {
+    let mut i = start;
+    while chars.get(i).is_some_and(|&c| c == ' ' || c == '\t') {
+        i += 1;
+    }
+    if chars.get(i) != Some(&'/') || chars.get(i + 1) != Some(&'/') {
+        return None;
+    }
+
+    let end = chars[i..]
+        .iter()
+        .position(|&c| c == '\n')
+        .map_or(chars.len(), |n| i + n);
+    Some(
+        chars[i..end]
+            .iter()
+            .collect::<String>()
+            .trim_end()
+            .to_string(),
+    )
+}
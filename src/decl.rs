@@ -0,0 +1,182 @@
+use crate::depth::check_depth;
+use crate::error::FormatError;
+use crate::options::{ColonSpacing, FormatOptions};
+
+/// Format a variable declaration such as `request: map(string, dyn)`
+///
+/// The `cel` crate has no syntax for environment declarations, so this covers
+/// the smallest useful subset: a name, a colon, and a type. A type is a
+/// possibly qualified name (`int`, `google.protobuf.Timestamp`) with optional
+/// type parameters in parentheses (`list(string)`, `map(string, int)`).
+/// Spacing is normalized and the colon follows `options.colon_spacing`.
+/// Types that nest deeper than `options.max_depth` are refused.
+pub fn format_decl(source: &str, options: &FormatOptions) -> anyhow::Result<String> {
+    check_depth(source, options)?;
+    let mut parser = DeclParser::new(source);
+    let name = parser.ident()?;
+    parser.expect(':')?;
+    let ty = parser.ty()?;
+    parser.end()?;
+
+    let colon = match options.colon_spacing {
+        ColonSpacing::After => ": ",
+        ColonSpacing::None => ":",
+        ColonSpacing::Around => " : ",
+    };
+    Ok(format!("{}{}{}", name, colon, ty))
+}
+
+/// Recursive-descent parser over the characters of a declaration
+struct DeclParser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl DeclParser {
+    fn new(source: &str) -> Self {
+        Self {
+            chars: source.chars().collect(),
+            pos: 0,
+        }
+    }
+
+    /// Parse a type, returning it in normalized form
+    fn ty(&mut self) -> Result<String, FormatError> {
+        let mut name = self.ident()?;
+        while self.eat('.') {
+            name.push('.');
+            name.push_str(&self.ident()?);
+        }
+
+        if self.eat('(') {
+            let mut params = vec![self.ty()?];
+            while self.eat(',') {
+                params.push(self.ty()?);
+            }
+            self.expect(')')?;
+            name = format!("{}({})", name, params.join(", "));
+        }
+        Ok(name)
+    }
+
+    fn ident(&mut self) -> Result<String, FormatError> {
+        self.skip_whitespace();
+        let start = self.pos;
+        while self
+            .chars
+            .get(self.pos)
+            .is_some_and(|&c| c.is_ascii_alphanumeric() || c == '_')
+        {
+            self.pos += 1;
+        }
+
+        if start == self.pos || self.chars[start].is_ascii_digit() {
+            return Err(self.error("expected an identifier"));
+        }
+        Ok(self.chars[start..self.pos].iter().collect())
+    }
+
+    /// Consume `c` if it is the next non-whitespace character
+    fn eat(&mut self, c: char) -> bool {
+        self.skip_whitespace();
+        if self.chars.get(self.pos) == Some(&c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), FormatError> {
+        if self.eat(c) {
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected `{}`", c)))
+        }
+    }
+
+    fn end(&mut self) -> Result<(), FormatError> {
+        self.skip_whitespace();
+        if self.pos < self.chars.len() {
+            return Err(self.error("unexpected input after the type"));
+        }
+        Ok(())
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.chars.get(self.pos).is_some_and(|c| c.is_whitespace()) {
+            self.pos += 1;
+        }
+    }
+
+    /// A parse error at the current position
+    fn error(&self, message: &str) -> FormatError {
+        let before = &self.chars[..self.pos];
+        let line_start = before.iter().rposition(|&c| c == '\n').map_or(0, |i| i + 1);
+        FormatError::Parse {
+            line: before.iter().filter(|&&c| c == '\n').count() + 1,
+            column: self.pos - line_start + 1,
+            message: message.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_decl() {
+        let options = FormatOptions::default();
+        let cases = [
+            ("x:int", "x: int"),
+            (
+                "  request :  map( string ,dyn )",
+                "request: map(string, dyn)",
+            ),
+            (
+                "ts: google.protobuf.Timestamp",
+                "ts: google.protobuf.Timestamp",
+            ),
+            ("m: map(string, list(int))", "m: map(string, list(int))"),
+        ];
+        for (source, expected) in cases {
+            assert_eq!(format_decl(source, &options).unwrap(), expected);
+        }
+
+        let options = options.with_colon_spacing(ColonSpacing::Around);
+        assert_eq!(format_decl("x:int", &options).unwrap(), "x : int");
+    }
+
+    #[test]
+    fn test_format_decl_errors() {
+        let options = FormatOptions::default();
+        let error = |source| {
+            format_decl(source, &options)
+                .unwrap_err()
+                .downcast::<FormatError>()
+                .unwrap()
+        };
+
+        assert_eq!(
+            error("x int"),
+            FormatError::Parse {
+                line: 1,
+                column: 3,
+                message: "expected `:`".to_string(),
+            }
+        );
+        assert!(matches!(
+            error("x: list(int"),
+            FormatError::Parse { column: 12, .. }
+        ));
+        assert!(matches!(error("1x: int"), FormatError::Parse { .. }));
+
+        let deep = format!("x: {}int{}", "list(".repeat(5000), ")".repeat(5000));
+        assert!(matches!(
+            error(&deep),
+            FormatError::TooDeep { limit: 256, .. }
+        ));
+        assert!(matches!(error("x: int y"), FormatError::Parse { .. }));
+    }
+}
@@ -0,0 +1,146 @@
+use crate::error::FormatError;
+use crate::literals::{scan_number, skip_string};
+use crate::options::FormatOptions;
+
+/// Reject sources that nest deeper than `options.max_depth`
+///
+/// The parser and formatter both recurse once per level of nesting, so this
+/// runs on the raw source before either of them sees it.
+pub(crate) fn check_depth(source: &str, options: &FormatOptions) -> Result<(), FormatError> {
+    let depth = nesting_depth(source);
+    if depth > options.max_depth {
+        return Err(FormatError::TooDeep {
+            depth,
+            limit: options.max_depth,
+        });
+    }
+    Ok(())
+}
+
+/// Estimate the deepest nesting of a CEL source without parsing it
+///
+/// Every open bracket counts as one level, and so does every other operator
+/// within one operand of `&&` or `||`. A repeat of an operator already open
+/// at this level, as in `a + b + c` or `a.b.c`, continues its chain instead:
+/// the whole chain counts as one level, plus one for every
+/// `CHAIN_TERMS_PER_LEVEL` terms, since each term still costs the parser a
+/// little stack. The parser balances chains of `&&` and `||`, so those only
+/// add the logarithm of their length. A `,` or closing bracket drops back to
+/// the enclosing level.
+fn nesting_depth(source: &str) -> usize {
+    let chars: Vec<char> = source.chars().collect();
+    // For each open bracket, the enclosing `Level` and depth to return to
+    let mut frames: Vec<(Level, usize)> = Vec::new();
+    let mut level = Level::default();
+    let mut depth = 0;
+    let mut max_depth = 0;
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+
+        if c == '/' && next == Some('/') {
+            while i < chars.len() && chars[i] != '\n' {
+                i += 1;
+            }
+        } else if c == '"' || c == '\'' {
+            i = skip_string(&chars, i, false);
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            let word: String = chars[start..i].iter().collect::<String>().to_lowercase();
+            let is_prefix = matches!(word.as_str(), "r" | "b" | "rb" | "br");
+            if is_prefix && matches!(chars.get(i), Some('"') | Some('\'')) {
+                i = skip_string(&chars, i, word.contains('r'));
+            } else if word == "in" {
+                depth += 1;
+            }
+        } else if c.is_ascii_digit() || (c == '.' && next.is_some_and(|n| n.is_ascii_digit())) {
+            i = scan_number(&chars, i);
+        } else if matches!(c, '(' | '[' | '{') {
+            frames.push((std::mem::take(&mut level), depth));
+            depth += 1;
+            level = Level {
+                base: depth,
+                ..Level::default()
+            };
+            i += 1;
+        } else if matches!(c, ')' | ']' | '}') {
+            (level, depth) = frames.pop().unwrap_or_default();
+            i += 1;
+        } else if c == ',' {
+            level.clauses = 0;
+            level.chains.clear();
+            depth = level.base;
+            i += 1;
+        } else if is_operator_char(c) {
+            let start = i;
+            while i < chars.len() && is_operator_char(chars[i]) {
+                i += 1;
+            }
+            let op = &chars[start..i];
+            if matches!(op, ['&', '&'] | ['|', '|']) {
+                level.clauses += 1;
+                level.chains.clear();
+                depth = level.base + level.clauses.ilog2() as usize + 1;
+            } else if let Some(n) = level.chains.iter().position(|c| c.op == op) {
+                // Operators opened since belonged to the previous term
+                level.chains.truncate(n + 1);
+                let chain = &mut level.chains[n];
+                chain.terms += 1;
+                depth = chain.base + 1 + chain.terms / CHAIN_TERMS_PER_LEVEL;
+            } else {
+                level.chains.push(Chain {
+                    op: op.to_vec(),
+                    base: depth,
+                    terms: 1,
+                });
+                depth += 1;
+            }
+        } else {
+            i += 1;
+        }
+
+        max_depth = max_depth.max(depth);
+    }
+
+    max_depth
+}
+
+/// How many terms of a chain like `a + b + c` cost one more level
+const CHAIN_TERMS_PER_LEVEL: usize = 4;
+
+/// Where the operands inside one pair of brackets start
+#[derive(Debug, Clone, Default)]
+struct Level {
+    /// The depth of an operand that has no operators
+    base: usize,
+
+    /// The number of `&&` and `||` operators seen so far
+    clauses: usize,
+
+    /// The operator chains open in the current clause, outermost first
+    chains: Vec<Chain>,
+}
+
+/// A run of one repeated operator, such as the `+` in `a + b * c + d`
+#[derive(Debug, Clone)]
+struct Chain {
+    op: Vec<char>,
+
+    /// The depth before the chain's first operator
+    base: usize,
+
+    /// The number of times the operator has appeared
+    terms: usize,
+}
+
+fn is_operator_char(c: char) -> bool {
+    matches!(
+        c,
+        '+' | '-' | '*' | '/' | '%' | '<' | '>' | '=' | '!' | '&' | '|' | '?' | '.'
+    )
+}
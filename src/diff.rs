@@ -0,0 +1,172 @@
+use crate::literals::{scan_number, skip_string};
+
+/// How formatting changed a source
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeKind {
+    /// Only whitespace changed: line breaks, indentation, or spacing
+    Whitespace,
+
+    /// The tokens themselves changed, for example quotes, parentheses, or
+    /// literal spellings
+    Tokens,
+}
+
+/// Classify the change from `before` to `after`, or `None` if they are equal
+///
+/// Both are split into tokens, with `//` comments kept as tokens, and the
+/// change is whitespace-only when the token sequences are equal.
+pub fn classify_change(before: &str, after: &str) -> Option<ChangeKind> {
+    if before == after {
+        None
+    } else if tokens(before) == tokens(after) {
+        Some(ChangeKind::Whitespace)
+    } else {
+        Some(ChangeKind::Tokens)
+    }
+}
+
+/// Number of lines added and removed between two texts
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LineChanges {
+    pub added: usize,
+    pub removed: usize,
+}
+
+/// Count the lines added and removed going from `before` to `after`
+///
+/// Lines are matched by a longest common subsequence, as in a line diff, after
+/// setting aside the lines both texts start and end with.
+pub fn line_changes(before: &str, after: &str) -> LineChanges {
+    let before: Vec<&str> = before.lines().collect();
+    let after: Vec<&str> = after.lines().collect();
+
+    let prefix = before
+        .iter()
+        .zip(&after)
+        .take_while(|(a, b)| a == b)
+        .count();
+    let suffix = before[prefix..]
+        .iter()
+        .rev()
+        .zip(after[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let before = &before[prefix..before.len() - suffix];
+    let after = &after[prefix..after.len() - suffix];
+
+    let common = common_lines(before, after);
+    LineChanges {
+        added: after.len() - common,
+        removed: before.len() - common,
+    }
+}
+
+/// Length of the longest common subsequence of two lists of lines
+fn common_lines(a: &[&str], b: &[&str]) -> usize {
+    let mut row = vec![0; b.len() + 1];
+    for x in a {
+        let mut diagonal = 0;
+        for (j, y) in b.iter().enumerate() {
+            let above = row[j + 1];
+            row[j + 1] = if x == y {
+                diagonal + 1
+            } else {
+                above.max(row[j])
+            };
+            diagonal = above;
+        }
+    }
+    row[b.len()]
+}
+
+/// Split CEL source into tokens, dropping whitespace
+///
+/// This only needs to tell tokens apart, not check them, so anything it
+/// doesn't recognize becomes a one-character token.
+fn tokens(source: &str) -> Vec<String> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let start = i;
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        } else if c == '/' && next == Some('/') {
+            while i < chars.len() && chars[i] != '\n' {
+                i += 1;
+            }
+        } else if c == '"' || c == '\'' {
+            i = skip_string(&chars, i, false);
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            let prefix = chars[start..i].iter().collect::<String>().to_lowercase();
+            if matches!(prefix.as_str(), "r" | "b" | "rb" | "br")
+                && matches!(chars.get(i), Some('"') | Some('\''))
+            {
+                i = skip_string(&chars, i, prefix.contains('r'));
+            }
+        } else if c.is_ascii_digit() || (c == '.' && next.is_some_and(|n| n.is_ascii_digit())) {
+            i = scan_number(&chars, i);
+        } else if matches!(
+            (c, next),
+            ('&', Some('&')) | ('|', Some('|')) | ('=' | '!' | '<' | '>', Some('='))
+        ) {
+            i += 2;
+        } else {
+            i += 1;
+        }
+
+        let token: String = chars[start..i].iter().collect();
+        tokens.push(token.trim_end().to_string());
+    }
+
+    tokens
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify_change() {
+        assert_eq!(classify_change("a && b", "a && b"), None);
+        assert_eq!(
+            classify_change("a&&b ||\n  c", "a && b || c"),
+            Some(ChangeKind::Whitespace)
+        );
+        assert_eq!(
+            classify_change("[1,2]  // ids\n", "[1, 2] // ids"),
+            Some(ChangeKind::Whitespace)
+        );
+        assert_eq!(classify_change("'a b'", "'ab'"), Some(ChangeKind::Tokens));
+        assert_eq!(classify_change("'a'", "\"a\""), Some(ChangeKind::Tokens));
+        assert_eq!(
+            classify_change("(a) + b", "a + b"),
+            Some(ChangeKind::Tokens)
+        );
+        assert_eq!(
+            classify_change("a <= b", "a < = b"),
+            Some(ChangeKind::Tokens)
+        );
+    }
+
+    #[test]
+    fn test_line_changes() {
+        let changes = |before, after| {
+            let LineChanges { added, removed } = line_changes(before, after);
+            (added, removed)
+        };
+        assert_eq!(changes("a\nb\nc", "a\nb\nc"), (0, 0));
+        assert_eq!(changes("a && b", "a\n  && b"), (2, 1));
+        assert_eq!(changes("x\na\ny\nb\nz", "x\nA\ny\nB\nz"), (2, 2));
+        assert_eq!(changes("a\nb", "a\nb\nc"), (1, 0));
+        assert_eq!(changes("a\nb\nc", ""), (0, 3));
+    }
+}
@@ -1,3 +1,10 @@
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+
 /// A pretty-printer document representation
 /// Inspired by Wadler's "A prettier printer" and Prettier.js
 #[derive(Debug, Clone)]
@@ -8,6 +15,9 @@ pub enum Doc {
     /// A string literal (must not contain newlines)
     Text(String),
 
+    /// Text tagged with the kind of source token it came from
+    Token(TokenKind, String),
+
     /// Concatenation of documents
     Concat(Vec<Doc>),
 
@@ -20,14 +30,47 @@ pub enum Doc {
     /// Increase indentation level for the inner doc
     Indent(Box<Doc>),
 
+    /// Indent the inner doc by a number of spaces on top of the enclosing
+    /// indentation, rather than by a level of `indent_str`
+    Align(usize, Box<Doc>),
+
     /// A group - tries to fit on one line, breaks if it doesn't fit
     Group(Box<Doc>),
 
-    /// If-break: first doc if breaking, second if flat
+    /// A group that always breaks, without breaking the groups around it
+    ///
+    /// An enclosing group still stays flat if the line this one starts on and
+    /// the line it ends on both fit.
+    BrokenGroup(Box<Doc>),
+
+    /// If-break: first doc if the innermost enclosing group breaks, second
+    /// if it stays flat
     IfBreak {
         break_doc: Box<Doc>,
         flat_doc: Box<Doc>,
     },
+
+    /// Forces every enclosing group to break; renders as nothing
+    BreakParent,
+}
+
+/// The kind of source token a `Doc::Token` holds, for custom renderers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenKind {
+    /// A macro name such as `has` or `map`
+    Keyword,
+
+    /// A literal value such as `42`, `"text"`, or `null`
+    Literal,
+
+    /// A variable, field, or type name
+    Identifier,
+
+    /// The name of a called function or method
+    Function,
+
+    /// An operator such as `&&` or `in`
+    Operator,
 }
 
 impl Doc {
@@ -41,6 +84,11 @@ impl Doc {
         Doc::Text(s.into())
     }
 
+    /// Create a text document tagged with its token kind
+    pub fn token<S: Into<String>>(kind: TokenKind, s: S) -> Self {
+        Doc::Token(kind, s.into())
+    }
+
     /// Create a line break
     pub fn line() -> Self {
         Doc::Line
@@ -61,6 +109,11 @@ impl Doc {
         Doc::Indent(Box::new(doc))
     }
 
+    /// Indent a document by `width` spaces
+    pub fn align(width: usize, doc: Doc) -> Self {
+        Doc::Align(width, Box::new(doc))
+    }
+
     /// Create a group
     pub fn group(doc: Doc) -> Self {
         Doc::Group(Box::new(doc))
@@ -74,6 +127,71 @@ impl Doc {
         }
     }
 
+    /// Make a group always break, without breaking the groups around it
+    ///
+    /// Anything other than a `Group` is wrapped in a new one.
+    pub fn force_break(self) -> Self {
+        match self {
+            Doc::Group(doc) => Doc::BrokenGroup(doc),
+            doc => Doc::BrokenGroup(Box::new(doc)),
+        }
+    }
+
+    /// Create a marker that breaks all enclosing groups
+    pub fn break_parent() -> Self {
+        Doc::BreakParent
+    }
+
+    /// Collapse nested `Concat`s into their parents and drop `Nil`s
+    ///
+    /// The builders nest a `Concat` for every piece of syntax, which costs a
+    /// level of recursion per piece when measuring and rendering. The
+    /// flattened document renders exactly the same.
+    pub fn flatten(mut self) -> Doc {
+        self.flatten_in_place();
+        self
+    }
+
+    /// `flatten` without moving the document, so boxes and vectors that are
+    /// already flat are reused
+    fn flatten_in_place(&mut self) {
+        match self {
+            Doc::Concat(docs) => {
+                docs.iter_mut().for_each(Doc::flatten_in_place);
+                if docs
+                    .iter()
+                    .any(|doc| matches!(doc, Doc::Nil | Doc::Concat(_)))
+                {
+                    let mut flat = Vec::with_capacity(docs.len());
+                    for doc in docs.drain(..) {
+                        match doc {
+                            Doc::Nil => {}
+                            Doc::Concat(inner) => flat.extend(inner),
+                            doc => flat.push(doc),
+                        }
+                    }
+                    *docs = flat;
+                }
+                match docs.len() {
+                    0 => *self = Doc::Nil,
+                    1 => *self = docs.swap_remove(0),
+                    _ => {}
+                }
+            }
+            Doc::Indent(doc) | Doc::Align(_, doc) | Doc::Group(doc) | Doc::BrokenGroup(doc) => {
+                doc.flatten_in_place()
+            }
+            Doc::IfBreak {
+                break_doc,
+                flat_doc,
+            } => {
+                break_doc.flatten_in_place();
+                flat_doc.flatten_in_place();
+            }
+            _ => {}
+        }
+    }
+
     /// Join documents with a separator
     pub fn join(docs: Vec<Doc>, sep: Doc) -> Self {
         if docs.is_empty() {
@@ -91,6 +209,9 @@ impl Doc {
     }
 
     /// Join with commas and optional trailing comma
+    ///
+    /// The trailing comma is only written when the enclosing group breaks and
+    /// there is more than one element.
     pub fn join_comma(docs: Vec<Doc>, trailing: bool) -> Self {
         if docs.is_empty() {
             return Doc::nil();
@@ -103,7 +224,7 @@ impl Doc {
             if i < len - 1 {
                 result.push(Doc::text(","));
                 result.push(Doc::line());
-            } else if trailing {
+            } else if trailing && len > 1 {
                 result.push(Doc::if_break(Doc::text(","), Doc::nil()));
             }
         }
@@ -111,97 +232,448 @@ impl Doc {
     }
 
     /// Render the document to a string
+    ///
+    /// A `max_width` of 0 means no limit: groups only break when they contain
+    /// a `BreakParent`.
     pub fn render(&self, max_width: usize, indent_str: &str) -> String {
         let mut buffer = String::new();
-        self.render_impl(&mut buffer, max_width, indent_str, 0, Mode::Flat);
+        // Writing to a String never fails
+        let _ = self.render_to(&mut buffer, max_width, indent_str);
         buffer
     }
 
-    fn render_impl(
+    /// Render the document into any `fmt::Write`, without building the whole
+    /// output in memory first
+    ///
+    /// Only the group being measured is buffered, to check if it fits.
+    pub fn render_to<W: fmt::Write>(
         &self,
-        buffer: &mut String,
+        out: &mut W,
         max_width: usize,
         indent_str: &str,
-        indent_level: usize,
-        mode: Mode,
-    ) {
+    ) -> fmt::Result {
+        let mut renderer = Renderer::new(self, out, max_width, indent_str);
+        self.render_impl(&mut renderer, Indentation::default(), Mode::Flat)
+    }
+
+    /// Count how many groups break across lines when rendered at `max_width`
+    pub fn count_broken_groups(&self, max_width: usize, indent_str: &str) -> usize {
+        let mut sink = String::new();
+        let mut renderer = Renderer::new(self, &mut sink, max_width, indent_str);
+        let _ = self.render_impl(&mut renderer, Indentation::default(), Mode::Flat);
+        renderer.broken_groups
+    }
+
+    /// Render the document on a single line, as if every group fit
+    pub fn render_flat(&self) -> String {
+        let mut buffer = String::new();
+        self.render_flat_impl(&mut buffer);
+        buffer
+    }
+
+    /// The width of the document rendered on a single line, or `None` if it
+    /// can't be: it holds a `BreakParent`, a `BrokenGroup`, or text with a
+    /// line break
+    ///
+    /// When this fits within `max_width`, `render` gives the same output as
+    /// `render_flat`.
+    pub fn measure_flat(&self) -> Option<usize> {
+        match self {
+            Doc::Nil | Doc::SoftLine => Some(0),
+            Doc::BreakParent | Doc::BrokenGroup(_) => None,
+            Doc::Text(s) | Doc::Token(_, s) => (!s.contains('\n')).then_some(s.len()),
+            Doc::Concat(docs) => docs.iter().map(Doc::measure_flat).sum(),
+            Doc::Line => Some(1),
+            Doc::Indent(doc) | Doc::Align(_, doc) | Doc::Group(doc) => doc.measure_flat(),
+            // A `BreakParent` in the break branch still breaks the group
+            Doc::IfBreak {
+                break_doc,
+                flat_doc,
+            } => {
+                if break_doc.has_break_parent() {
+                    None
+                } else {
+                    flat_doc.measure_flat()
+                }
+            }
+        }
+    }
+
+    fn render_flat_impl(&self, buffer: &mut String) {
         match self {
-            Doc::Nil => {}
+            Doc::Nil | Doc::SoftLine | Doc::BreakParent => {}
+            Doc::Text(s) | Doc::Token(_, s) => buffer.push_str(s),
+            Doc::Concat(docs) => {
+                for doc in docs {
+                    doc.render_flat_impl(buffer);
+                }
+            }
+            Doc::Line => buffer.push(' '),
+            Doc::Indent(doc) | Doc::Align(_, doc) | Doc::Group(doc) | Doc::BrokenGroup(doc) => {
+                doc.render_flat_impl(buffer)
+            }
+            Doc::IfBreak { flat_doc, .. } => flat_doc.render_flat_impl(buffer),
+        }
+    }
 
-            Doc::Text(s) => buffer.push_str(s),
+    /// Check if a `BreakParent` appears anywhere in this document
+    fn has_break_parent(&self) -> bool {
+        match self {
+            Doc::BreakParent => true,
+            Doc::Concat(docs) => docs.iter().any(Doc::has_break_parent),
+            Doc::Indent(doc) | Doc::Align(_, doc) | Doc::Group(doc) | Doc::BrokenGroup(doc) => {
+                doc.has_break_parent()
+            }
+            Doc::IfBreak {
+                break_doc,
+                flat_doc,
+            } => break_doc.has_break_parent() || flat_doc.has_break_parent(),
+            _ => false,
+        }
+    }
 
+    /// Measure every group in the document, bottom-up, so rendering looks
+    /// each one up instead of walking its contents again
+    ///
+    /// Also returns whether the document holds a `BreakParent`.
+    fn measure(&self, groups: &mut BTreeMap<*const Doc, Measure>) -> (Measure, bool) {
+        match self {
+            Doc::Nil | Doc::SoftLine => (Measure::Flat(0), false),
+            Doc::Line => (Measure::Flat(1), false),
+            Doc::BreakParent => (Measure::Broken, true),
+            Doc::Text(s) | Doc::Token(_, s) if s.contains('\n') => (Measure::Broken, false),
+            Doc::Text(s) | Doc::Token(_, s) => (Measure::Flat(s.len()), false),
             Doc::Concat(docs) => {
+                let mut measure = Measure::Flat(0);
+                let mut break_parent = false;
                 for doc in docs {
-                    doc.render_impl(buffer, max_width, indent_str, indent_level, mode);
+                    let (next, has_break_parent) = doc.measure(groups);
+                    break_parent |= has_break_parent;
+                    measure = measure.then(next);
+                }
+                (measure, break_parent)
+            }
+            Doc::Indent(doc) | Doc::Align(_, doc) => doc.measure(groups),
+            Doc::Group(doc) => {
+                let (measure, break_parent) = doc.measure(groups);
+                groups.insert(self, measure);
+                (measure, break_parent)
+            }
+            Doc::BrokenGroup(doc) => match doc.measure(groups) {
+                (Measure::Broken, break_parent) => (Measure::Broken, break_parent),
+                (_, break_parent) => {
+                    let (Ok(first) | Err(first)) = doc.edge_width(groups, false);
+                    let (Ok(last) | Err(last)) = doc.edge_width(groups, true);
+                    let measure = Measure::Hug {
+                        first,
+                        middle: 0,
+                        last,
+                    };
+                    (measure, break_parent)
+                }
+            },
+            // A `BreakParent` in the break branch still breaks the group
+            Doc::IfBreak {
+                break_doc,
+                flat_doc,
+            } => {
+                let (_, break_parent) = break_doc.measure(groups);
+                let (measure, flat_break_parent) = flat_doc.measure(groups);
+                if break_parent {
+                    (Measure::Broken, true)
+                } else {
+                    (measure, flat_break_parent)
                 }
             }
+        }
+    }
 
-            Doc::Line => match mode {
-                Mode::Flat => buffer.push(' '),
-                Mode::Break => {
-                    buffer.push('\n');
-                    for _ in 0..indent_level {
-                        buffer.push_str(indent_str);
+    /// The width of the document in break mode up to its first line break,
+    /// or after its last one with `from_end`, as `Err`; or its whole width as
+    /// `Ok` if it has no line break
+    ///
+    /// Nested groups that fit are taken to stay flat.
+    fn edge_width(
+        &self,
+        groups: &BTreeMap<*const Doc, Measure>,
+        from_end: bool,
+    ) -> Result<usize, usize> {
+        match self {
+            Doc::Nil | Doc::BreakParent => Ok(0),
+            Doc::Line | Doc::SoftLine => Err(0),
+            Doc::Text(s) | Doc::Token(_, s) => {
+                let edge = if from_end {
+                    s.rsplit('\n').next()
+                } else {
+                    s.split('\n').next()
+                };
+                match edge {
+                    Some(edge) if edge.len() < s.len() => Err(edge.len()),
+                    _ => Ok(s.len()),
+                }
+            }
+            Doc::Concat(docs) => {
+                let mut width = 0;
+                for i in 0..docs.len() {
+                    let doc = &docs[if from_end { docs.len() - 1 - i } else { i }];
+                    match doc.edge_width(groups, from_end) {
+                        Ok(w) => width += w,
+                        Err(w) => return Err(width + w),
                     }
                 }
+                Ok(width)
+            }
+            Doc::Indent(doc) | Doc::Align(_, doc) | Doc::BrokenGroup(doc) => {
+                doc.edge_width(groups, from_end)
+            }
+            Doc::Group(doc) => match groups.get(&(self as *const Doc)) {
+                Some(Measure::Flat(w)) => Ok(*w),
+                Some(Measure::Hug { first, last, .. }) => {
+                    Err(if from_end { *last } else { *first })
+                }
+                _ => doc.edge_width(groups, from_end),
             },
+            Doc::IfBreak { break_doc, .. } => break_doc.edge_width(groups, from_end),
+        }
+    }
 
-            Doc::SoftLine => match mode {
-                Mode::Flat => {}
-                Mode::Break => {
-                    buffer.push('\n');
-                    for _ in 0..indent_level {
-                        buffer.push_str(indent_str);
-                    }
+    fn render_impl<W: fmt::Write>(
+        &self,
+        r: &mut Renderer<'_, W>,
+        indent: Indentation,
+        mode: Mode,
+    ) -> fmt::Result {
+        match self {
+            Doc::Nil | Doc::BreakParent => Ok(()),
+
+            Doc::Text(s) | Doc::Token(_, s) => r.write(s),
+
+            Doc::Concat(docs) => {
+                for doc in docs {
+                    doc.render_impl(r, indent, mode)?;
                 }
+                Ok(())
+            }
+
+            Doc::Line => match mode {
+                Mode::Flat | Mode::Inline => r.write(" "),
+                Mode::Break => r.newline(indent),
             },
 
-            Doc::Indent(doc) => {
-                doc.render_impl(buffer, max_width, indent_str, indent_level + 1, mode);
+            Doc::SoftLine => match mode {
+                Mode::Flat | Mode::Inline => Ok(()),
+                Mode::Break => r.newline(indent),
+            },
+
+            // A flat group only starts new lines for a `BrokenGroup` inside
+            // it, which lines up with the line the group is on
+            Doc::Indent(doc) | Doc::Align(_, doc) if matches!(mode, Mode::Inline) => {
+                doc.render_impl(r, indent, mode)
             }
 
+            Doc::Indent(doc) => doc.render_impl(r, indent.deeper(), mode),
+
+            Doc::Align(width, doc) => doc.render_impl(r, indent.aligned(*width), mode),
+
             Doc::Group(doc) => {
-                // Try flat mode first
-                let mut flat_buffer = String::new();
-                doc.render_impl(
-                    &mut flat_buffer,
-                    max_width,
-                    indent_str,
-                    indent_level,
-                    Mode::Flat,
-                );
-
-                // Check if it fits on current line
-                let current_line_len = buffer.lines().last().map(|l| l.len()).unwrap_or(0);
-                let fits = current_line_len + flat_buffer.len() <= max_width
-                    && !flat_buffer.contains('\n');
-
-                if fits {
-                    buffer.push_str(&flat_buffer);
-                } else {
-                    doc.render_impl(buffer, max_width, indent_str, indent_level, Mode::Break);
+                // Try flat mode first. Measuring before rendering keeps a
+                // group that can't fit from being rendered flat, which for the
+                // outer groups of a large document is most of its output. A
+                // group only fits if everything nested in it fits too, so the
+                // flat render can skip their checks
+                match r.groups.get(&(self as *const Doc)).copied() {
+                    Some(Measure::Flat(width)) if r.fits(width) => {
+                        let mut flat_buffer = String::new();
+                        doc.render_flat_impl(&mut flat_buffer);
+                        r.write(&flat_buffer)
+                    }
+                    // A `BrokenGroup` inside breaks on its own; the rest of
+                    // this group stays flat around it
+                    Some(Measure::Hug {
+                        first,
+                        middle,
+                        last,
+                    }) if r.fits(first) && r.fits_line(indent, middle.max(last)) => {
+                        doc.render_impl(r, indent, Mode::Inline)
+                    }
+                    _ => {
+                        r.broken_groups += 1;
+                        doc.render_impl(r, indent, Mode::Break)
+                    }
                 }
             }
 
+            Doc::BrokenGroup(doc) => {
+                r.broken_groups += 1;
+                doc.render_impl(r, indent, Mode::Break)
+            }
+
             Doc::IfBreak {
                 break_doc,
                 flat_doc,
             } => match mode {
-                Mode::Break => {
-                    break_doc.render_impl(buffer, max_width, indent_str, indent_level, mode)
-                }
-                Mode::Flat => {
-                    flat_doc.render_impl(buffer, max_width, indent_str, indent_level, mode)
-                }
+                Mode::Break => break_doc.render_impl(r, indent, mode),
+                Mode::Flat | Mode::Inline => flat_doc.render_impl(r, indent, mode),
             },
         }
     }
 }
 
+/// How a document measures for the groups around it
+#[derive(Debug, Clone, Copy)]
+enum Measure {
+    /// Fits on one line this wide
+    Flat(usize),
+
+    /// Holds a `BrokenGroup`, which starts new lines: the widths of the
+    /// first line, the widest line in between that isn't inside a
+    /// `BrokenGroup`, and the last line, without indentation
+    Hug {
+        first: usize,
+        middle: usize,
+        last: usize,
+    },
+
+    /// Breaks every group around it
+    Broken,
+}
+
+impl Measure {
+    /// The measure of this document followed by `next`
+    fn then(self, next: Measure) -> Measure {
+        match (self, next) {
+            (Measure::Broken, _) | (_, Measure::Broken) => Measure::Broken,
+            (Measure::Flat(width), Measure::Flat(w)) => Measure::Flat(width + w),
+            (
+                Measure::Flat(width),
+                Measure::Hug {
+                    first,
+                    middle,
+                    last,
+                },
+            ) => Measure::Hug {
+                first: width + first,
+                middle,
+                last,
+            },
+            (
+                Measure::Hug {
+                    first,
+                    middle,
+                    last,
+                },
+                Measure::Flat(w),
+            ) => Measure::Hug {
+                first,
+                middle,
+                last: last + w,
+            },
+            (
+                Measure::Hug {
+                    first,
+                    middle,
+                    last,
+                },
+                Measure::Hug {
+                    first: f,
+                    middle: m,
+                    last: l,
+                },
+            ) => Measure::Hug {
+                first,
+                middle: middle.max(last + f).max(m),
+                last: l,
+            },
+        }
+    }
+}
+
+/// Output state while rendering: where text goes and the current column
+struct Renderer<'a, W> {
+    out: &'a mut W,
+    max_width: usize,
+    indent_str: &'a str,
+    column: usize,
+    broken_groups: usize,
+    groups: BTreeMap<*const Doc, Measure>,
+}
+
+impl<'a, W: fmt::Write> Renderer<'a, W> {
+    fn new(doc: &Doc, out: &'a mut W, max_width: usize, indent_str: &'a str) -> Self {
+        let mut groups = BTreeMap::new();
+        doc.measure(&mut groups);
+        Self {
+            out,
+            max_width,
+            indent_str,
+            column: 0,
+            broken_groups: 0,
+            groups,
+        }
+    }
+
+    /// Check if something this wide fits on the current line
+    fn fits(&self, width: usize) -> bool {
+        self.max_width == 0 || self.column + width <= self.max_width
+    }
+
+    /// Check if a line this wide fits after `indent`
+    fn fits_line(&self, indent: Indentation, width: usize) -> bool {
+        let indent = indent.levels * self.indent_str.len() + indent.spaces;
+        self.max_width == 0 || indent + width <= self.max_width
+    }
+
+    fn write(&mut self, s: &str) -> fmt::Result {
+        self.column = match s.rfind('\n') {
+            Some(i) => s.len() - i - 1,
+            None => self.column + s.len(),
+        };
+        self.out.write_str(s)
+    }
+
+    fn newline(&mut self, indent: Indentation) -> fmt::Result {
+        self.out.write_char('\n')?;
+        for _ in 0..indent.levels {
+            self.out.write_str(self.indent_str)?;
+        }
+        for _ in 0..indent.spaces {
+            self.out.write_char(' ')?;
+        }
+        self.column = indent.levels * self.indent_str.len() + indent.spaces;
+        Ok(())
+    }
+}
+
+/// The indentation of new lines: whole levels of `indent_str` from `Indent`,
+/// then single spaces from `Align`
+#[derive(Debug, Clone, Copy, Default)]
+struct Indentation {
+    levels: usize,
+    spaces: usize,
+}
+
+impl Indentation {
+    fn deeper(self) -> Self {
+        Indentation {
+            levels: self.levels + 1,
+            ..self
+        }
+    }
+
+    fn aligned(self, width: usize) -> Self {
+        Indentation {
+            spaces: self.spaces + width,
+            ..self
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 enum Mode {
     Flat,
     Break,
+    /// Flat around a `BrokenGroup`
+    Inline,
 }
 
 // Helper functions for common patterns
@@ -250,4 +722,14 @@ impl Doc {
             Doc::text("}"),
         ]))
     }
+
+    /// Wrap with possible line breaks inside, keeping the closing delimiter on
+    /// the last line of content
+    pub fn wrap_hugging(open: &str, doc: Doc, close: &str) -> Self {
+        Doc::group(Doc::concat(vec![
+            Doc::text(open),
+            Doc::indent(Doc::concat(vec![Doc::soft_line(), doc])),
+            Doc::text(close),
+        ]))
+    }
 }
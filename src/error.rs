@@ -0,0 +1,133 @@
+use std::fmt;
+
+use cel::parser::ParseErrors;
+
+/// Errors raised while formatting
+///
+/// These are returned inside `anyhow::Error`, so callers that need to tell
+/// them apart can use `err.downcast_ref::<FormatError>()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FormatError {
+    /// The source isn't valid CEL; `line` and `column` are 1-based
+    Parse {
+        line: usize,
+        column: usize,
+        message: String,
+    },
+
+    /// The expression nests deeper than `FormatOptions::max_depth`
+    TooDeep { depth: usize, limit: usize },
+
+    /// The output would be larger than `FormatOptions::max_output_bytes`
+    OutputTooLarge { limit: usize },
+
+    /// With `FormatOptions::verify`, the output didn't parse back to the same
+    /// expression as the source
+    ChangedMeaning,
+
+    /// `FormatOptions::indent_string` contains a line break
+    InvalidIndentString,
+
+    /// The source uses syntax that `FormatOptions::target_version` doesn't
+    /// have and that can't be written another way, or that no version of CEL
+    /// can write, such as a comprehension that no macro expands to. `syntax`
+    /// describes what can't be written.
+    UnsupportedSyntax { syntax: &'static str },
+
+    /// A failure that isn't about the source, such as an error from the
+    /// writer output goes to, with its message
+    Other(String),
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FormatError::Parse {
+                line,
+                column,
+                message,
+            } => write!(
+                f,
+                "Parse error at line {}, column {}: {}",
+                line, column, message
+            ),
+            FormatError::TooDeep { depth, limit } => write!(
+                f,
+                "Expression is nested too deeply (depth {} exceeds the limit of {})",
+                depth, limit
+            ),
+            FormatError::OutputTooLarge { limit } => write!(
+                f,
+                "Formatted output would be larger than the limit of {} bytes",
+                limit
+            ),
+            FormatError::ChangedMeaning => write!(
+                f,
+                "Formatting would change the meaning of the expression; output discarded"
+            ),
+            FormatError::InvalidIndentString => {
+                write!(f, "The indent string must not contain a line break")
+            }
+            FormatError::UnsupportedSyntax { syntax } => {
+                write!(f, "Can't write {}", syntax)
+            }
+            FormatError::Other(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for FormatError {}
+
+/// Problems worth pointing out that don't stop formatting
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FormatWarning {
+    /// The expression starting on `line` (1-based) nests deeper than
+    /// `FormatOptions::complexity_warn_depth`
+    TooComplex {
+        line: usize,
+        depth: usize,
+        limit: usize,
+    },
+}
+
+impl fmt::Display for FormatWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FormatWarning::TooComplex { line, depth, limit } => write!(
+                f,
+                "Expression at line {} is nested {} levels deep (more than {}); consider simplifying it",
+                line, depth, limit
+            ),
+        }
+    }
+}
+
+impl FormatError {
+    /// Build a parse error from the parser's first error, positioned in `source`
+    ///
+    /// The parser trims its input, so positions are shifted back past any
+    /// leading whitespace.
+    pub(crate) fn from_parse_errors(source: &str, errors: &ParseErrors) -> Self {
+        let Some(error) = errors.errors.first() else {
+            return FormatError::Parse {
+                line: 1,
+                column: 1,
+                message: "Syntax error".to_string(),
+            };
+        };
+
+        let trimmed = &source[..source.len() - source.trim_start().len()];
+        let skipped_lines = trimmed.matches('\n').count();
+        let line = error.pos.0.max(1) as usize;
+        let mut column = error.pos.1.max(1) as usize;
+        if line == 1 {
+            column += trimmed.len() - trimmed.rfind('\n').map_or(0, |i| i + 1);
+        }
+
+        FormatError::Parse {
+            line: line + skipped_lines,
+            column,
+            message: error.msg.clone(),
+        }
+    }
+}
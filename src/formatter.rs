@@ -4,156 +4,845 @@ use cel::common::ast::{
 };
 use cel::common::value::CelVal;
 use cel::parser::Parser;
+use std::borrow::Cow;
+use std::fmt;
+use std::io;
 
-use crate::doc::Doc;
-use crate::options::FormatOptions;
+use crate::analyze::expr_depth;
+use crate::comments::{attach_open_comments, format_comment};
+use crate::depth::check_depth;
+use crate::doc::{Doc, TokenKind};
+use crate::error::{FormatError, FormatWarning};
+use crate::literals::{
+    duration_string, encode_base64, preserve_spellings, scan_number, skip_string,
+    timestamp_argument,
+};
+use crate::options::{
+    BoolCase, BytesLiteralStyle, ClosingBracketStyle, ColonSpacing, FormatOptions, LineEnding,
+    OperatorBreak,
+};
+use crate::parens::{mark_arithmetic_parens, unwrap_parens, PARENS};
+use crate::simplify::{
+    lower_to_target, normalize_comparisons, rewrite, rewrite_key_presence, simplify, sort_lists,
+};
+use crate::verify::same_expr;
 
 /// Format a CEL expression string
+///
+/// The output keeps every operation of the source: the parser doesn't fold
+/// constants, so `1 + 2` stays `1 + 2`. A source with no code, such as an
+/// empty file, formats to its `//` comments, one per line, or to nothing.
+///
+/// Invalid input is reported as an error, never a panic, and the result
+/// depends only on `source` and `options`, so this can be called from build
+/// scripts and from several threads at once.
 pub fn format_cel(source: &str, options: &FormatOptions) -> anyhow::Result<String> {
+    let output = format_expression(source, options)?;
+    Ok(apply_line_ending(output, source, options))
+}
+
+/// Format a CEL expression, also returning warnings about it
+///
+/// With `options.complexity_warn_depth` set, an expression nested deeper than
+/// that gets a `FormatWarning::TooComplex`. Warnings never stop formatting.
+pub fn format_cel_with_warnings(
+    source: &str,
+    options: &FormatOptions,
+) -> anyhow::Result<(String, Vec<FormatWarning>)> {
+    let output = format_cel(source, options)?;
+    let warnings = complexity_warning(source, 1, options).into_iter().collect();
+    Ok((output, warnings))
+}
+
+/// Warn if the expression in `source`, which starts on `line`, nests deeper
+/// than `options.complexity_warn_depth`
+///
+/// The depth is measured on a separate parse, like `analyze` does, to keep
+/// the bookkeeping out of the formatting path.
+pub(crate) fn complexity_warning(
+    source: &str,
+    line: usize,
+    options: &FormatOptions,
+) -> Option<FormatWarning> {
+    let limit = options.complexity_warn_depth?;
+    let ast = new_parser(options)
+        .parse(strip_semicolon(source, options))
+        .ok()?;
+    let depth = expr_depth(&ast);
+    (depth > limit).then_some(FormatWarning::TooComplex { line, depth, limit })
+}
+
+/// Format a CEL expression, writing the output to `out` as it is rendered
+///
+/// This avoids holding the whole output in memory. With `options.verify` the
+/// output has to be checked before anything is written, so it is buffered.
+pub fn format_cel_to<W: io::Write>(
+    source: &str,
+    options: &FormatOptions,
+    out: &mut W,
+) -> anyhow::Result<()> {
+    if options.verify {
+        out.write_all(format_cel(source, options)?.as_bytes())?;
+        return Ok(());
+    }
+
+    let indent = indent_string(options)?;
+    let doc = to_doc(source, options)?;
+    let mut writer = IoWriter {
+        inner: out,
+        crlf: use_crlf(source, options),
+        remaining: options.max_output_bytes,
+        error: None,
+    };
+    if doc
+        .render_to(&mut writer, options.max_width, &indent)
+        .is_err()
+    {
+        return Err(match (writer.error, options.max_output_bytes) {
+            (Some(error), _) => error.into(),
+            (None, Some(limit)) => FormatError::OutputTooLarge { limit }.into(),
+            (None, None) => io::Error::other("formatter error").into(),
+        });
+    }
+    Ok(())
+}
+
+/// Adapts an `io::Write` for `Doc::render_to`, converting line endings
+///
+/// With a limit, writing fails once `remaining` bytes have been written; what
+/// was written up to then stays written.
+struct IoWriter<'a, W> {
+    inner: &'a mut W,
+    crlf: bool,
+    remaining: Option<usize>,
+    error: Option<io::Error>,
+}
+
+impl<W: io::Write> fmt::Write for IoWriter<'_, W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if let Some(remaining) = &mut self.remaining {
+            *remaining = remaining.checked_sub(s.len()).ok_or(fmt::Error)?;
+        }
+        let result = if self.crlf && s.contains('\n') {
+            self.inner.write_all(s.replace('\n', "\r\n").as_bytes())
+        } else {
+            self.inner.write_all(s.as_bytes())
+        };
+        result.map_err(|e| {
+            self.error = Some(e);
+            fmt::Error
+        })
+    }
+}
+
+/// Collects rendered output, failing once it would grow past a limit so that
+/// a huge document is never rendered in full
+struct LimitedWriter {
+    output: String,
+    remaining: usize,
+}
+
+impl fmt::Write for LimitedWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.remaining = self.remaining.checked_sub(s.len()).ok_or(fmt::Error)?;
+        self.output.push_str(s);
+        Ok(())
+    }
+}
+
+/// The string for one level of indentation
+fn indent_string(options: &FormatOptions) -> Result<String, FormatError> {
+    match &options.indent_string {
+        Some(indent) if indent.contains(['\n', '\r']) => Err(FormatError::InvalidIndentString),
+        Some(indent) => Ok(indent.clone()),
+        None if options.use_spaces => Ok(" ".repeat(options.indent_width)),
+        None => Ok("\t".to_string()),
+    }
+}
+
+/// Format a CEL expression with `\n` line endings
+pub(crate) fn format_expression(source: &str, options: &FormatOptions) -> anyhow::Result<String> {
+    let indent = indent_string(options)?;
+    let doc = to_doc(source, options)?;
+
+    // Most expressions fit on one line; those skip measuring group by group
+    let flat_width = doc
+        .measure_flat()
+        .filter(|&width| options.max_width == 0 || width <= options.max_width);
+    let output = match (flat_width, options.max_output_bytes) {
+        (Some(width), Some(limit)) if width > limit => {
+            return Err(FormatError::OutputTooLarge { limit }.into())
+        }
+        (Some(_), _) => doc.render_flat(),
+        (None, None) => doc.render(options.max_width, &indent),
+        (None, Some(limit)) => {
+            let mut writer = LimitedWriter {
+                output: String::new(),
+                remaining: limit,
+            };
+            doc.render_to(&mut writer, options.max_width, &indent)
+                .map_err(|_| FormatError::OutputTooLarge { limit })?;
+            writer.output
+        }
+    };
+    if options.verify && comment_lines(source).is_none() {
+        verify_output(source, &output, options)?;
+    }
+    Ok(output)
+}
+
+/// Convert `\n`-separated output to the configured line ending
+///
+/// `LineEnding::Auto` picks CRLF only when most of the source's line breaks
+/// are CRLF.
+pub(crate) fn apply_line_ending(output: String, source: &str, options: &FormatOptions) -> String {
+    if use_crlf(source, options) {
+        output.replace('\n', "\r\n")
+    } else {
+        output
+    }
+}
+
+/// Check if output for `source` should use CRLF line endings
+fn use_crlf(source: &str, options: &FormatOptions) -> bool {
+    match options.line_ending {
+        LineEnding::Lf => false,
+        LineEnding::Crlf => true,
+        LineEnding::Auto => {
+            let crlf_count = source.matches("\r\n").count();
+            crlf_count > source.matches('\n').count() - crlf_count
+        }
+    }
+}
+
+/// Check that formatted output parses back to the same expression as its
+/// source
+fn verify_output(source: &str, output: &str, options: &FormatOptions) -> Result<(), FormatError> {
+    let source = strip_semicolon(source, options);
+    let mut original = new_parser(options)
+        .parse(source)
+        .map_err(|e| FormatError::from_parse_errors(source, &e))?;
+    if options.simplify {
+        simplify(&mut original);
+    }
+    if options.normalize_comparisons {
+        normalize_comparisons(&mut original);
+    }
+    rewrite_key_presence(&mut original, options.key_presence_style);
+    lower_to_target(&mut original, options.target_version)
+        .map_err(|syntax| FormatError::UnsupportedSyntax { syntax })?;
+    check_depth(output, options)?;
+    let options = &*without_shadowed_aliases(&mut original, options);
+
+    let output = unalias_operators(output, options);
+    let same = new_parser(options)
+        .parse(&output)
+        .is_ok_and(|formatted| same_expr(&original, &formatted, options));
+    if same {
+        Ok(())
+    } else {
+        Err(FormatError::ChangedMeaning)
+    }
+}
+
+/// `options` without the operator aliases that are also names in `ast`
+///
+/// An alias spelled like an identifier, field, or function in the expression
+/// couldn't be told apart from it when mapping aliases back, so those
+/// operators keep their symbols: `and && b` stays as it is.
+fn without_shadowed_aliases<'a>(
+    ast: &mut IdedExpr,
+    options: &'a FormatOptions,
+) -> Cow<'a, FormatOptions> {
+    if options.operator_aliases.is_empty() {
+        return Cow::Borrowed(options);
+    }
+    let mut shadowed: Vec<String> = Vec::new();
+    rewrite(ast, &mut |expr| {
+        let names: Vec<&str> = match &expr.expr {
+            Expr::Ident(name) => vec![name],
+            Expr::Select(select) => vec![&select.field],
+            Expr::Call(call) => vec![&call.func_name],
+            Expr::Struct(s) => s
+                .type_name
+                .split('.')
+                .chain(s.entries.iter().filter_map(|entry| match &entry.expr {
+                    EntryExpr::StructField(field) => Some(field.field.as_str()),
+                    EntryExpr::MapEntry(_) => None,
+                }))
+                .collect(),
+            Expr::Comprehension(comp) => [&comp.iter_var, &comp.accu_var]
+                .into_iter()
+                .chain(&comp.iter_var2)
+                .map(String::as_str)
+                .collect(),
+            _ => Vec::new(),
+        };
+        for name in names {
+            let is_alias = options.operator_aliases.values().any(|alias| alias == name);
+            if is_alias && !shadowed.iter().any(|s| s == name) {
+                shadowed.push(name.to_string());
+            }
+        }
+    });
+    if shadowed.is_empty() {
+        return Cow::Borrowed(options);
+    }
+    let mut options = options.clone();
+    options
+        .operator_aliases
+        .retain(|_, alias| !shadowed.contains(alias));
+    Cow::Owned(options)
+}
+
+/// Replace operator aliases in formatted output with the operators they stand
+/// for, so that the output parses as plain CEL
+///
+/// Strings and `//` comments are copied unchanged.
+fn unalias_operators(output: &str, options: &FormatOptions) -> String {
+    if options.operator_aliases.is_empty() {
+        return output.to_string();
+    }
+
+    let chars: Vec<char> = output.chars().collect();
+    let mut result = String::with_capacity(output.len());
+    let mut i = 0;
+    while i < chars.len() {
+        let start = i;
+        let c = chars[i];
+        if c == '/' && chars.get(i + 1) == Some(&'/') {
+            while i < chars.len() && chars[i] != '\n' {
+                i += 1;
+            }
+        } else if c == '"' || c == '\'' {
+            i = skip_string(&chars, i, false);
+        } else if c.is_ascii_digit() {
+            i = scan_number(&chars, i);
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            let word: String = chars[start..i].iter().collect();
+            let prefix = word.to_lowercase();
+            if matches!(prefix.as_str(), "r" | "b" | "rb" | "br")
+                && matches!(chars.get(i), Some('"') | Some('\''))
+            {
+                i = skip_string(&chars, i, prefix.contains('r'));
+            } else if let Some((op, _)) = options
+                .operator_aliases
+                .iter()
+                .find(|(op, alias)| **alias == word && operator_alias(op, options).is_some())
+            {
+                result.push_str(operator_symbol(op));
+                continue;
+            }
+        } else {
+            i += 1;
+        }
+        result.extend(&chars[start..i]);
+    }
+    result
+}
+
+/// Format a CEL expression as a single-line preview of at most `max_chars`
+/// characters
+///
+/// Longer output is truncated at the last space before the limit (or mid-token
+/// if there is none) and ends with `…`, which counts towards the limit.
+pub fn format_preview(source: &str, max_chars: usize) -> anyhow::Result<String> {
+    let doc = to_doc(source, &FormatOptions::default())?;
+    Ok(truncate_preview(&doc.render_flat(), max_chars))
+}
+
+/// Format as much of a possibly incomplete CEL expression as possible
+///
+/// Meant for editors, where the buffer is often mid-edit. A source that
+/// parses is formatted as by `format_cel`. Otherwise the longest valid prefix
+/// is formatted and the rest of the source is appended untouched. The valid
+/// prefix is the longest one that ends on a token boundary (never inside an
+/// identifier or number, never on whitespace) and parses as a complete
+/// expression, so `a+b+` becomes `a + b+`. If no prefix parses, or formatting
+/// fails for another reason, the source is returned unchanged.
+pub fn format_cel_lenient(source: &str, options: &FormatOptions) -> String {
+    if let Ok(formatted) = format_cel(source, options) {
+        return formatted;
+    }
+    if check_depth(source, options).is_err() {
+        return source.to_string();
+    }
+
+    let mut ends = prefix_ends(source);
+    ends.reverse();
+    for end in ends {
+        if new_parser(options).parse(&source[..end]).is_err() {
+            continue;
+        }
+        return match format_cel(&source[..end], options) {
+            Ok(formatted) => formatted + &source[end..],
+            Err(_) => source.to_string(),
+        };
+    }
+    source.to_string()
+}
+
+/// Byte offsets where a prefix of `source` could end: after a non-whitespace
+/// character, but not between two characters of the same word or number
+fn prefix_ends(source: &str) -> Vec<usize> {
+    let is_word = |c: char| c.is_alphanumeric() || c == '_';
+    let mut ends = Vec::new();
+    let mut chars = source.char_indices().peekable();
+
+    while let Some((i, c)) = chars.next() {
+        let next = chars.peek().map(|&(_, next)| next);
+        let inside_word = is_word(c) && next.is_some_and(is_word);
+        if !(c.is_whitespace() || inside_word) {
+            ends.push(i + c.len_utf8());
+        }
+    }
+    ends
+}
+
+/// Truncate a single-line string to `max_chars`, preferring a space boundary
+fn truncate_preview(text: &str, max_chars: usize) -> String {
+    if text.chars().count() <= max_chars {
+        return text.to_string();
+    }
+    if max_chars == 0 {
+        return String::new();
+    }
+
+    // Leave room for the ellipsis
+    let budget = max_chars - 1;
+    let cut: String = text.chars().take(budget).collect();
+    let at_boundary = text.chars().nth(budget) == Some(' ');
+
+    let kept = match cut.rfind(' ') {
+        Some(pos) if !at_boundary && pos > 0 => cut[..pos].trim_end(),
+        _ => cut.trim_end(),
+    };
+    format!("{}…", kept)
+}
+
+/// Parse a CEL expression and build its document without rendering it
+///
+/// This exposes the formatter's layout decisions so callers can walk the
+/// `Doc` tree and render it themselves, e.g. with syntax highlighting.
+pub fn to_doc(source: &str, options: &FormatOptions) -> anyhow::Result<Doc> {
+    if let Some(comments) = comment_lines(source) {
+        let comments: Vec<String> = comments
+            .iter()
+            .map(|comment| format_comment(comment, options))
+            .collect();
+        return Ok(Doc::text(comments.join("\n")));
+    }
+    let source = strip_semicolon(source, options);
+    check_depth(source, options)?;
+
     // Parse the CEL expression
-    let parser = Parser::new();
-    let ast = parser
+    let parser = new_parser(options);
+    let mut ast = parser
         .parse(source)
-        .map_err(|e| anyhow::anyhow!("Parse error: {:?}", e))?;
+        .map_err(|e| FormatError::from_parse_errors(source, &e))?;
+    if options.preserve_arithmetic_parens {
+        // Marking is textual, so if the marked source doesn't parse the
+        // parentheses are left to the usual rules
+        let marked = mark_arithmetic_parens(source)
+            .and_then(|marked| new_parser(options).parse(&marked).ok());
+        if let Some(marked) = marked {
+            ast = marked;
+            unwrap_parens(&mut ast);
+        }
+    }
+    if options.simplify {
+        simplify(&mut ast);
+    }
+    if options.normalize_comparisons {
+        normalize_comparisons(&mut ast);
+    }
+    rewrite_key_presence(&mut ast, options.key_presence_style);
+    if options.sort_list_elements {
+        sort_lists(&mut ast);
+    }
+    lower_to_target(&mut ast, options.target_version)
+        .map_err(|syntax| FormatError::UnsupportedSyntax { syntax })?;
+    check_writable(&mut ast, options)?;
+    let options = &*without_shadowed_aliases(&mut ast, options);
 
     // Format the AST
-    let doc = format_expr(&ast);
+    let doc = format_expr(&ast, options);
+    let doc = if options.top_level_boolean_expand {
+        expand_boolean_root(doc, &ast.expr)
+    } else {
+        doc
+    };
 
-    // Render to string
-    let indent_str = if options.use_spaces {
-        " ".repeat(options.indent_width)
+    let doc = if options.preserve_literal_spellings && !options.normalize_literals {
+        let mut doc = preserve_spellings(doc, source, options.double_exponent_style);
+        if options.align_values {
+            // Keys such as `0x10` are longer than the canonical `16` they
+            // were aligned by
+            realign_entries(&mut doc);
+        }
+        doc
     } else {
-        "\t".to_string()
+        doc
     };
+    Ok(attach_open_comments(doc, source, options))
+}
+
+/// Force the group of a root `&&` or `||` chain to break, one operand per
+/// line, even if the expression fits in `options.max_width`
+fn expand_boolean_root(doc: Doc, expr: &Expr) -> Doc {
+    let is_boolean_chain =
+        matches!(expr, Expr::Call(call) if matches!(call.func_name.as_str(), "_&&_" | "_||_"));
+    match doc {
+        Doc::Group(inner) if is_boolean_chain => {
+            Doc::group(Doc::concat(vec![Doc::break_parent(), *inner]))
+        }
+        doc => doc,
+    }
+}
 
-    Ok(doc.render(options.max_width, &indent_str))
+/// The trimmed `//` comment lines of a source with no code, or `None` if it
+/// has code
+///
+/// Empty and whitespace-only sources have no lines to keep and format to an
+/// empty string.
+fn comment_lines(source: &str) -> Option<Vec<&str>> {
+    source
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(|line| line.starts_with("//").then_some(line))
+        .collect()
+}
+
+/// With `options.strip_trailing_semicolon`, drop a `;` that is the last
+/// non-whitespace character of the source
+///
+/// Only that one `;` goes: `a;;` and `a; b` still fail to parse.
+pub(crate) fn strip_semicolon<'a>(source: &'a str, options: &FormatOptions) -> &'a str {
+    if !options.strip_trailing_semicolon {
+        return source;
+    }
+    source.trim_end().strip_suffix(';').unwrap_or(source)
+}
+
+/// Create a parser configured by `options`
+pub(crate) fn new_parser(options: &FormatOptions) -> Parser {
+    match options.max_recursion_depth {
+        Some(max) => Parser::new().max_recursion_depth(max),
+        None => Parser::new(),
+    }
 }
 
 /// Format an IdedExpr
-fn format_expr(expr: &IdedExpr) -> Doc {
-    format_expr_inner(&expr.expr)
+pub(crate) fn format_expr(expr: &IdedExpr, options: &FormatOptions) -> Doc {
+    format_expr_inner(&expr.expr, options)
 }
 
 /// Format the inner Expr
-fn format_expr_inner(expr: &Expr) -> Doc {
+fn format_expr_inner(expr: &Expr, options: &FormatOptions) -> Doc {
     match expr {
         Expr::Unspecified => Doc::text(""),
 
-        Expr::Ident(name) => Doc::text(name.clone()),
+        Expr::Ident(name) => Doc::token(TokenKind::Identifier, escape_ident(name)),
 
-        Expr::Literal(val) => format_literal(val),
+        Expr::Literal(val) => format_literal(val, options),
 
-        Expr::Select(select) => format_select(select),
+        Expr::Select(select) => format_select(select, options),
 
-        Expr::Call(call) => format_call(call),
+        Expr::Call(call) => format_call(call, options),
 
-        Expr::List(list) => format_list(list),
+        Expr::List(list) => format_list(list, options),
 
-        Expr::Map(map) => format_map(map),
+        Expr::Map(map) => format_map(map, options),
 
-        Expr::Struct(s) => format_struct(s),
+        Expr::Struct(s) => format_struct(s, options),
 
-        Expr::Comprehension(comp) => format_comprehension(comp),
+        Expr::Comprehension(comp) => format_comprehension(comp, options),
     }
 }
 
 /// Format a literal value
-fn format_literal(val: &CelVal) -> Doc {
+fn format_literal(val: &CelVal, options: &FormatOptions) -> Doc {
     match val {
-        CelVal::Boolean(b) => Doc::text(b.to_string()),
-        CelVal::Int(i) => Doc::text(i.to_string()),
-        CelVal::UInt(u) => Doc::text(format!("{}u", u)),
-        CelVal::Double(d) => {
-            let s = d.to_string();
-            // Ensure doubles always have a decimal point
-            if !s.contains('.') && !s.contains('e') && !s.contains('E') {
-                Doc::text(format!("{}.0", s))
-            } else {
-                Doc::text(s)
-            }
+        CelVal::Boolean(b) => {
+            let text = match (options.bool_case, b) {
+                (BoolCase::Lower, true) => "true",
+                (BoolCase::Lower, false) => "false",
+                (BoolCase::Upper, true) => "True",
+                (BoolCase::Upper, false) => "False",
+            };
+            Doc::token(TokenKind::Literal, text)
+        }
+        CelVal::Int(i) => Doc::token(TokenKind::Literal, i.to_string()),
+        CelVal::UInt(u) => Doc::token(TokenKind::Literal, format!("{}u", u)),
+        CelVal::Double(d) => Doc::token(TokenKind::Literal, format_double(*d)),
+        CelVal::String(s) => match string_chunks(s, options) {
+            Some(chunks) => format_string_chunks(chunks, options),
+            None => Doc::token(
+                TokenKind::Literal,
+                format!("\"{}\"", escape_string(s, options)),
+            ),
+        },
+        CelVal::Bytes(b) => Doc::token(TokenKind::Literal, format_bytes(b, options)),
+        CelVal::Null => Doc::token(TokenKind::Literal, "null"),
+        CelVal::Duration(d) => Doc::token(
+            TokenKind::Literal,
+            format!(
+                "duration(\"{}\")",
+                duration_string(*d, options.duration_style)
+            ),
+        ),
+        CelVal::Timestamp(ts) => Doc::token(
+            TokenKind::Literal,
+            format!(
+                "timestamp({})",
+                timestamp_argument(*ts, options.timestamp_style)
+            ),
+        ),
+        _ => Doc::token(TokenKind::Literal, format!("{:?}", val)),
+    }
+}
+
+/// With `options.wrap_long_strings`, split a string whose literal is longer
+/// than the limit into pieces whose literals fit, breaking after spaces where
+/// possible
+///
+/// `None` means the string is written as one literal. So is a string that
+/// would need more pieces than `options.max_depth`, since a `+` chain that
+/// long could be refused as too deep when the output is formatted again.
+fn string_chunks(s: &str, options: &FormatOptions) -> Option<Vec<String>> {
+    let limit = options.wrap_long_strings?;
+    if escape_string(s, options).len() + 2 <= limit {
+        return None;
+    }
+
+    let mut chunks = Vec::new();
+    // The current piece, and the length of its literal without quotes
+    let mut current = (String::new(), 0);
+    let mut push = |current: &mut (String, usize), piece: &str| {
+        let width = escape_string(piece, options).len();
+        if !current.0.is_empty() && current.1 + width + 2 > limit {
+            chunks.push(std::mem::take(&mut current.0));
+            current.1 = 0;
         }
-        CelVal::String(s) => Doc::text(format!("\"{}\"", escape_string(s))),
-        CelVal::Bytes(b) => Doc::text(format!("b\"{}\"", escape_bytes(b))),
-        CelVal::Null => Doc::text("null"),
-        CelVal::Duration(d) => Doc::text(format!("duration(\"{}s\")", d.as_secs())),
-        CelVal::Timestamp(ts) => {
-            // Format timestamp as RFC3339
-            Doc::text(format!("timestamp({:?})", ts))
+        current.0.push_str(piece);
+        current.1 += width;
+    };
+    for word in s.split_inclusive(' ') {
+        if escape_string(word, options).len() + 2 <= limit {
+            push(&mut current, word);
+        } else {
+            // A word longer than the limit is split between characters
+            for c in word.chars() {
+                push(&mut current, c.encode_utf8(&mut [0; 4]));
+            }
         }
-        _ => Doc::text(format!("{:?}", val)),
     }
+    if !current.0.is_empty() {
+        chunks.push(current.0);
+    }
+    (chunks.len() > 1 && chunks.len() <= options.max_depth).then_some(chunks)
 }
 
-/// Format a select expression (field access)
-fn format_select(select: &SelectExpr) -> Doc {
-    let operand = format_expr(&select.operand);
+/// Write the pieces of a wrapped string literal joined by `+`
+///
+/// The pieces are laid out as the `+` chain the output parses back to, so
+/// formatting the output again gives the same text; like any chain, it only
+/// breaks when it doesn't fit.
+fn format_string_chunks(chunks: Vec<String>, options: &FormatOptions) -> Doc {
+    let mut pieces = chunks.into_iter().map(|chunk| IdedExpr {
+        id: 0,
+        // Each piece is within the limit, so it isn't split again
+        expr: Expr::Literal(CelVal::String(chunk)),
+    });
+    let Some(first) = pieces.next() else {
+        return Doc::nil();
+    };
+    let sum = pieces.fold(first, |sum, piece| IdedExpr {
+        id: 0,
+        expr: Expr::Call(CallExpr {
+            func_name: "_+_".to_string(),
+            target: None,
+            args: vec![sum, piece],
+        }),
+    });
+    format_expr(&sum, options)
+}
+
+/// Check if a string literal is written as a `+` of pieces, which binds less
+/// tightly than a literal
+fn is_wrapped_string(expr: &Expr, options: &FormatOptions) -> bool {
+    matches!(expr, Expr::Literal(CelVal::String(s)) if string_chunks(s, options).is_some())
+}
 
+/// Format a double in its canonical form
+///
+/// Values from `1e21` up, and below `1e-7`, are written with an exponent
+/// (`1e300`) rather than as hundreds of digits.
+pub(crate) fn format_double(d: f64) -> String {
+    let magnitude = d.abs();
+    if magnitude >= 1e21 || (magnitude != 0.0 && magnitude < 1e-7) {
+        return format!("{:e}", d);
+    }
+    let s = d.to_string();
+    // Ensure doubles always have a decimal point
+    if !s.contains('.') && !s.contains('e') && !s.contains('E') {
+        format!("{}.0", s)
+    } else {
+        s
+    }
+}
+
+/// Format a select expression (field access)
+fn format_select(select: &SelectExpr, options: &FormatOptions) -> Doc {
     if select.test {
         // This is a has() macro
         Doc::concat(vec![
-            Doc::text("has("),
-            operand,
-            Doc::text("."),
-            Doc::text(select.field.clone()),
+            Doc::token(TokenKind::Keyword, "has"),
+            Doc::text("("),
+            format_member_operand(&select.operand, options),
+            select_link(select),
             Doc::text(")"),
         ])
+    } else if options.break_long_selects {
+        // Field access that may break before each `.`, like a method chain
+        format_member_call(&select.operand, select_link(select), options)
     } else {
         // Regular field access
         Doc::concat(vec![
-            operand,
-            Doc::text("."),
-            Doc::text(select.field.clone()),
+            format_member_operand(&select.operand, options),
+            select_link(select),
         ])
     }
 }
 
+/// Format the operand of a field selection, method call, or index
+///
+/// Member access binds tighter than any operator, so an operator operand
+/// needs parens to keep `(a + b).size()` from becoming `a + b.size()`.
+fn format_member_operand(expr: &IdedExpr, options: &FormatOptions) -> Doc {
+    let doc = format_expr(expr, options);
+    match &expr.expr {
+        Expr::Call(call)
+            if is_binary_op(&call.func_name)
+                || is_unary_op(&call.func_name)
+                || call.func_name == "_?_:_" =>
+        {
+            Doc::parens(doc)
+        }
+        expr if is_wrapped_string(expr, options) => Doc::parens(doc),
+        _ => doc,
+    }
+}
+
 /// Format a function call
-fn format_call(call: &CallExpr) -> Doc {
+fn format_call(call: &CallExpr, options: &FormatOptions) -> Doc {
     let func_name = &call.func_name;
 
+    if let Some(formatter) = options.custom_formatters.get(func_name) {
+        if let Some(doc) = formatter(call) {
+            return doc;
+        }
+    }
+
     // Check if this is a binary operator
     if is_binary_op(func_name) {
-        return format_binary_op(func_name, &call.args);
+        return format_binary_op(func_name, &call.args, options);
     }
 
     // Check if this is a unary operator
     if is_unary_op(func_name) {
-        return format_unary_op(func_name, &call.args);
+        return format_unary_op(func_name, &call.args, options);
+    }
+
+    // Parentheses kept by `preserve_arithmetic_parens`
+    if let (PARENS, None, [arg]) = (func_name.as_str(), &call.target, call.args.as_slice()) {
+        return Doc::parens(format_expr(arg, options));
     }
 
     // Check if this is a ternary conditional
     if func_name == "_?_:_" {
-        return format_ternary(&call.args);
+        return format_ternary(&call.args, options);
     }
 
     // Check if this is an index operation
     if func_name == "_[_]" {
-        return format_index(&call.args);
+        return format_index(&call.args, options);
+    }
+
+    // Check if this is an optional field selection (a.?b)
+    if func_name == "_?._" {
+        if let Some(doc) = format_optional_select(&call.args, options) {
+            return doc;
+        }
+    }
+
+    // Check if this is an optional index (a[?b])
+    if func_name == "_[?_]" {
+        if let [operand, index] = call.args.as_slice() {
+            let mut docs = vec![format_member_operand(operand, options)];
+            docs.extend(optional_index_link(index, options));
+            return Doc::concat(docs);
+        }
+    }
+
+    if let Some(doc) = format_unexpanded_macro(call, options) {
+        return doc;
     }
 
     // Regular function call or method call
     if let Some(target) = &call.target {
         // Method call: target.func(args)
-        let target_doc = format_expr(target);
-        let args_doc = format_args(&call.args);
-
-        Doc::concat(vec![
-            target_doc,
-            Doc::text("."),
-            Doc::text(func_name.clone()),
-            Doc::wrap_parens(args_doc),
-        ])
+        format_method_call(target, method_link(call, options), options)
     } else {
         // Regular function call: func(args)
-        let args_doc = format_args(&call.args);
-
         Doc::concat(vec![
-            Doc::text(func_name.clone()),
-            Doc::wrap_parens(args_doc),
+            Doc::token(TokenKind::Function, func_name.clone()),
+            format_call_args(&call.args, options),
         ])
     }
 }
 
+/// Format an optional field selection (`a.?b`)
+///
+/// The optional-types AST encodes it as `_?._(a, "b")`, and an optional index
+/// `a[?b]` as `_[?_](a, b)`. cel 0.11 doesn't parse this syntax yet, but ASTs
+/// built elsewhere can contain it.
+fn format_optional_select(args: &[IdedExpr], options: &FormatOptions) -> Option<Doc> {
+    let [operand, field] = args else {
+        return None;
+    };
+    let Expr::Literal(CelVal::String(field)) = &field.expr else {
+        return None;
+    };
+
+    let link = optional_select_link(field);
+    if options.break_long_selects {
+        Some(format_member_call(operand, link, options))
+    } else {
+        Some(Doc::concat(vec![
+            format_member_operand(operand, options),
+            link,
+        ]))
+    }
+}
+
+/// The `.?field` part of an optional field selection
+fn optional_select_link(field: &str) -> Doc {
+    Doc::concat(vec![
+        Doc::text(".?"),
+        Doc::token(TokenKind::Identifier, field.to_string()),
+    ])
+}
+
+/// The `[?index]` part of an optional index, spliced like `index_link`
+fn optional_index_link(index: &IdedExpr, options: &FormatOptions) -> [Doc; 3] {
+    [Doc::text("[?"), format_expr(index, options), Doc::text("]")]
+}
+
 /// Check if a function name is a binary operator
 fn is_binary_op(name: &str) -> bool {
     matches!(
@@ -175,20 +864,9 @@ fn is_binary_op(name: &str) -> bool {
     )
 }
 
-/// Check if a function name is a unary operator
-fn is_unary_op(name: &str) -> bool {
-    matches!(name, "!_" | "-_")
-}
-
-/// Format a binary operator
-fn format_binary_op(op: &str, args: &[IdedExpr]) -> Doc {
-    if args.len() != 2 {
-        return Doc::text(format!("<invalid binary op: {}>", op));
-    }
-
-    let left = format_expr(&args[0]);
-    let right = format_expr(&args[1]);
-    let op_str = match op {
+/// The source spelling of a binary operator
+fn operator_symbol(op: &str) -> &str {
+    match op {
         "_+_" => "+",
         "_-_" => "-",
         "_*_" => "*",
@@ -203,175 +881,744 @@ fn format_binary_op(op: &str, args: &[IdedExpr]) -> Doc {
         "_&&_" => "&&",
         "_||_" => "||",
         "@in" => "in",
+        "@not_in" => "not in",
         _ => op,
-    };
-
-    // Add parentheses for complex expressions when needed
-    let left_doc = if needs_parens(&args[0].expr, op) {
-        Doc::parens(left)
-    } else {
-        left
-    };
+    }
+}
 
-    let right_doc = if needs_parens(&args[1].expr, op) {
-        Doc::parens(right)
-    } else {
-        right
-    };
+/// The alias configured for a binary operator in `options.operator_aliases`
+///
+/// Only words that aren't already CEL keywords are used, so that `verify`
+/// can map them back.
+fn operator_alias<'a>(op: &str, options: &'a FormatOptions) -> Option<&'a str> {
+    let alias = options.operator_aliases.get(op)?;
+    let is_word = alias.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && alias.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    let is_keyword = matches!(alias.as_str(), "in" | "true" | "false" | "null");
+    (is_binary_op(op) && is_word && !is_keyword).then_some(alias.as_str())
+}
 
-    // rustfmt-style: one operator per line
-    // Use soft_line to break only when needed
-    // Indent the operator, but not the right operand so chains stay at the same level
-    Doc::group(Doc::concat(vec![
-        left_doc,
-        Doc::indent(Doc::concat(vec![
-            Doc::if_break(Doc::nil(), Doc::text(" ")), // space when flat, nothing when breaking
-            Doc::soft_line(),                          // newline when breaking, nothing when flat
-            Doc::text(op_str),
-            Doc::text(" "),
-        ])),
-        right_doc,
-    ]))
+/// Check if a function name is a unary operator
+fn is_unary_op(name: &str) -> bool {
+    matches!(name, "!_" | "-_")
 }
 
-/// Format a unary operator
-fn format_unary_op(op: &str, args: &[IdedExpr]) -> Doc {
-    if args.len() != 1 {
-        return Doc::text(format!("<invalid unary op: {}>", op));
+/// Format a binary operator
+fn format_binary_op(op: &str, args: &[IdedExpr], options: &FormatOptions) -> Doc {
+    if args.len() != 2 {
+        return Doc::text(format!("<invalid binary op: {}>", op));
     }
 
-    let operand = format_expr(&args[0]);
-    let op_str = match op {
-        "!_" => "!",
-        "-_" => "-",
-        _ => op,
-    };
-
-    Doc::concat(vec![Doc::text(op_str), operand])
-}
+    let op_str = |op| operator_alias(op, options).unwrap_or_else(|| operator_symbol(op));
 
-/// Format a ternary conditional (a ? b : c)
-fn format_ternary(args: &[IdedExpr]) -> Doc {
-    if args.len() != 3 {
-        return Doc::text("<invalid ternary>");
+    // Membership in a collection literal hugs the collection, letting the
+    // collection break instead of the operator: `key in {\n  ...\n}`
+    if matches!(op, "@in" | "@not_in") && is_collection_literal(&args[1].expr) {
+        return Doc::group(Doc::concat(vec![
+            format_operand(&args[0], op, false, options),
+            Doc::text(" "),
+            Doc::token(TokenKind::Operator, op_str(op)),
+            Doc::text(" "),
+            format_operand(&args[1], op, true, options),
+        ]));
     }
 
-    let cond = format_expr(&args[0]);
-    let then_expr = format_expr(&args[1]);
-    let else_expr = format_expr(&args[2]);
-
+    // rustfmt-style: one operator per line. A chain of operators of one
+    // precedence breaks as a whole, with the operands after the first
+    // indented under it
+    let mut chain = Vec::new();
+    binary_chain(op, args, options, &mut chain);
+    let (first_op, first) = chain[0];
+    let rest = chain[1..].iter().map(|&(op, operand)| {
+        Doc::concat(vec![
+            Doc::line(),
+            Doc::token(TokenKind::Operator, op_str(op)),
+            Doc::text(" "),
+            format_operand(operand, op, true, options),
+        ])
+    });
     Doc::group(Doc::concat(vec![
-        cond,
-        Doc::line(),
-        Doc::text("? "),
-        then_expr,
-        Doc::line(),
-        Doc::text(": "),
-        else_expr,
+        format_operand(first, first_op, false, options),
+        Doc::indent(Doc::concat(rest)),
     ]))
 }
 
-/// Format an index operation (a[b])
-fn format_index(args: &[IdedExpr]) -> Doc {
-    if args.len() != 2 {
-        return Doc::text("<invalid index>");
+/// Format an operand of the binary operator `op`, parenthesized if needed
+///
+/// Operators group to the left, so a `right` operand also needs parens when
+/// it is an operator of the same precedence: `a - (b - c)` isn't `a - b - c`.
+fn format_operand(operand: &IdedExpr, op: &str, right: bool, options: &FormatOptions) -> Doc {
+    let doc = format_expr(operand, options);
+    let same_level = right
+        && matches!(&operand.expr, Expr::Call(call)
+            if call.target.is_none()
+                && is_binary_op(&call.func_name)
+                && op_precedence(&call.func_name) == op_precedence(op));
+    if same_level || needs_parens(&operand.expr, op, options) {
+        Doc::parens(doc)
+    } else {
+        doc
     }
-
-    let target = format_expr(&args[0]);
-    let index = format_expr(&args[1]);
-
-    Doc::concat(vec![target, Doc::text("["), index, Doc::text("]")])
 }
 
-/// Format function arguments
-fn format_args(args: &[IdedExpr]) -> Doc {
-    if args.is_empty() {
-        return Doc::nil();
+/// Flatten a chain of binary operators of one precedence, such as
+/// `a + b - c` or `a && b && c`, into its operands
+///
+/// Each operand comes with the operator it is an argument of, which is the
+/// one written before it (the first operand's is never written). Only left
+/// operands are flattened, since `a - (b - c)` isn't `a - b - c`, except for
+/// `&&` and `||`, which the parser balances.
+fn binary_chain<'a>(
+    op: &'a str,
+    args: &'a [IdedExpr],
+    options: &FormatOptions,
+    chain: &mut Vec<(&'a str, &'a IdedExpr)>,
+) {
+    for (i, arg) in args.iter().enumerate() {
+        let nested = match &arg.expr {
+            Expr::Call(call)
+                if call.target.is_none()
+                    && call.args.len() == 2
+                    && !is_comparison_op(op)
+                    && options.custom_formatters.get(&call.func_name).is_none() =>
+            {
+                let same_level = if i == 0 {
+                    is_binary_op(&call.func_name)
+                        && op_precedence(&call.func_name) == op_precedence(op)
+                } else {
+                    matches!(op, "_&&_" | "_||_") && call.func_name == op
+                };
+                same_level.then_some(call)
+            }
+            _ => None,
+        };
+        match nested {
+            Some(call) => binary_chain(&call.func_name, &call.args, options, chain),
+            None => chain.push((op, arg)),
+        }
     }
+}
 
-    let arg_docs: Vec<Doc> = args.iter().map(format_expr).collect();
-    Doc::join_comma(arg_docs, false)
+/// Check if an expression is a list, map, or struct literal
+fn is_collection_literal(expr: &Expr) -> bool {
+    matches!(expr, Expr::List(_) | Expr::Map(_) | Expr::Struct(_))
 }
 
-/// Format a list literal
-fn format_list(list: &ListExpr) -> Doc {
-    if list.elements.is_empty() {
-        return Doc::text("[]");
+/// Check if an expression could evaluate to a map
+///
+/// Literals, comprehensions, and operator results never do, so presence tests
+/// on them keep their written form.
+pub(crate) fn may_be_map(expr: &Expr) -> bool {
+    match expr {
+        Expr::Ident(_) | Expr::Map(_) => true,
+        Expr::Select(select) => !select.test,
+        Expr::Call(call) => {
+            call.func_name == "_[_]"
+                || !(is_binary_op(&call.func_name)
+                    || is_unary_op(&call.func_name)
+                    || call.func_name == "_?_:_")
+        }
+        _ => false,
     }
+}
 
-    let elem_docs: Vec<Doc> = list.elements.iter().map(format_expr).collect();
+/// Words the parser reads as literals or operators, never as identifiers
+const KEYWORDS: [&str; 4] = ["false", "in", "null", "true"];
 
-    // For simple short lists, always keep them inline for consistency
-    // A list is "simple" if all elements are literals or identifiers
-    let is_simple = list
-        .elements
-        .iter()
-        .all(|elem| matches!(elem.expr, Expr::Literal(_) | Expr::Ident(_)));
+/// Check if a string is spelled like an identifier and isn't a keyword
+fn is_plain_ident(s: &str) -> bool {
+    let mut chars = s.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && !KEYWORDS.contains(&s)
+}
 
-    if is_simple && list.elements.len() <= 5 {
-        // Format inline without grouping
-        Doc::concat(vec![
-            Doc::text("["),
-            Doc::join(elem_docs, Doc::text(", ")),
-            Doc::text("]"),
-        ])
+/// Write an identifier or field name so that it parses back to itself
+///
+/// The parser accepts reserved words such as `if` and `as` as identifiers, so
+/// those are written as they are, and so are fields the parser read in
+/// backticks, which keep them in their name. Other names, such as `true` or
+/// `foo-bar` from a constructed AST, have no spelling the parser reads back;
+/// `check_writable` refuses them, so the backticks they get here only show up
+/// in documents built straight from an AST. Macro variables such as `@result`
+/// are left alone.
+fn escape_ident(name: &str) -> String {
+    if is_quoted_field(name) || name.starts_with('@') || is_plain_ident(name) {
+        name.to_string()
     } else {
-        // Use wrapping for complex or long lists
-        Doc::wrap_brackets(Doc::join_comma(elem_docs, true))
+        format!("`{}`", name)
     }
 }
 
-/// Format a map literal
-fn format_map(map: &MapExpr) -> Doc {
-    if map.entries.is_empty() {
-        return Doc::text("{}");
+/// Check if a field name is one the parser read in backticks
+fn is_quoted_field(name: &str) -> bool {
+    name.len() > 2 && name.starts_with('`') && name.ends_with('`')
+}
+
+/// Check if a string can be written as a field selection (`m.k`)
+pub(crate) fn is_field_name(s: &str) -> bool {
+    const RESERVED: [&str; 21] = [
+        "as",
+        "break",
+        "const",
+        "continue",
+        "else",
+        "false",
+        "for",
+        "function",
+        "if",
+        "import",
+        "in",
+        "let",
+        "loop",
+        "package",
+        "namespace",
+        "null",
+        "return",
+        "true",
+        "var",
+        "void",
+        "while",
+    ];
+
+    is_plain_ident(s) && !RESERVED.contains(&s)
+}
+
+/// Format a unary operator
+fn format_unary_op(op: &str, args: &[IdedExpr], options: &FormatOptions) -> Doc {
+    if args.len() != 1 {
+        return Doc::text(format!("<invalid unary op: {}>", op));
+    }
+
+    if let Expr::Call(inner) = &args[0].expr {
+        if options.not_in_operator && op == "!_" && inner.func_name == "@in" {
+            // !(x in y) -> x not in y
+            return format_binary_op("@not_in", &inner.args, options);
+        }
+    }
+
+    let operand = format_expr(&args[0], options);
+    let op_str = match op {
+        "!_" => "!",
+        "-_" => "-",
+        _ => op,
+    };
+
+    let operand = if needs_parens_unary(op, &args[0].expr) {
+        Doc::parens(operand)
+    } else {
+        operand
+    };
+
+    Doc::concat(vec![Doc::token(TokenKind::Operator, op_str), operand])
+}
+
+/// Check if the operand of a prefix operator needs parens
+///
+/// A prefix operator applies to a member expression, so calls, selects, and
+/// indexes (`!f(x)`, `-a.b`, `!a[0]`) go bare while operators and ternaries
+/// need parens to keep `-(a + b)` from becoming `-a + b`. The parser also
+/// reads a run of prefix operators (`--b`, even as `- -b`) as a single one,
+/// so a unary operand needs parens too. Under `-`, an operand starting with an
+/// int or double literal needs them because `-1` (or `-1.0.f()`) would parse
+/// with the literal `-1`, not a negation.
+fn needs_parens_unary(op: &str, operand: &Expr) -> bool {
+    match operand {
+        Expr::Call(call)
+            if call.target.is_none()
+                && (is_binary_op(&call.func_name)
+                    || is_unary_op(&call.func_name)
+                    || call.func_name == "_?_:_") =>
+        {
+            true
+        }
+        _ => {
+            op == "-_"
+                && matches!(
+                    chain_root(operand),
+                    Expr::Literal(CelVal::Int(_) | CelVal::Double(_))
+                )
+        }
+    }
+}
+
+/// The expression a member chain starts from, like `a` in `a.b[0].c()`
+fn chain_root(expr: &Expr) -> &Expr {
+    match expr {
+        Expr::Select(select) => chain_root(&select.operand.expr),
+        Expr::Call(call) => match (&call.target, call.func_name.as_str(), call.args.as_slice()) {
+            (Some(target), _, _) => chain_root(&target.expr),
+            (None, "_[_]" | "_[?_]" | "_?._", [operand, _]) => chain_root(&operand.expr),
+            _ => expr,
+        },
+        Expr::Comprehension(comp) => chain_root(&comp.iter_range.expr),
+        _ => expr,
+    }
+}
+
+/// Format a ternary conditional (a ? b : c)
+fn format_ternary(args: &[IdedExpr], options: &FormatOptions) -> Doc {
+    if args.len() != 3 {
+        return Doc::text("<invalid ternary>");
+    }
+
+    // The grammar only allows a bare ternary in the else branch
+    // (`a ? b : c ? d : e`); in the other two it needs parens
+    let cond = format_ternary_operand(&args[0], options);
+    let then_expr = format_ternary_operand(&args[1], options);
+    let else_expr = format_expr(&args[2], options);
+
+    match options.ternary_operator_break {
+        OperatorBreak::Before => Doc::group(Doc::concat(vec![
+            cond,
+            Doc::line(),
+            Doc::text("? "),
+            indent_collection_branch(then_expr, &args[1]),
+            Doc::line(),
+            Doc::text(": "),
+            indent_collection_branch(else_expr, &args[2]),
+        ])),
+        OperatorBreak::After => Doc::group(Doc::concat(vec![
+            cond,
+            Doc::text(" ?"),
+            Doc::line(),
+            then_expr,
+            Doc::text(" :"),
+            Doc::line(),
+            else_expr,
+        ])),
+    }
+}
+
+/// Indent a collection after `? ` or `: ` so that when it breaks, its
+/// closing bracket lines up with the opening one instead of with the `?`
+fn indent_collection_branch(doc: Doc, expr: &IdedExpr) -> Doc {
+    if is_collection_literal(&expr.expr) {
+        Doc::indent(doc)
+    } else {
+        doc
+    }
+}
+
+/// Format the condition or then branch of a ternary
+fn format_ternary_operand(expr: &IdedExpr, options: &FormatOptions) -> Doc {
+    let doc = format_expr(expr, options);
+    match &expr.expr {
+        Expr::Call(call) if call.func_name == "_?_:_" => Doc::parens(doc),
+        _ => doc,
+    }
+}
+
+/// Format an index operation (a[b])
+///
+/// The parser always produces one index. Index calls built with any other
+/// number can't be written, since `a[b, c]` doesn't parse; `check_writable`
+/// refuses them, so the `a[b, c]` and placeholder forms here only show up in
+/// documents built straight from an AST.
+fn format_index(args: &[IdedExpr], options: &FormatOptions) -> Doc {
+    let Some((target, indices)) = args.split_first() else {
+        return Doc::concat(vec![
+            Doc::token(TokenKind::Function, "_[_]"),
+            Doc::text("()"),
+        ]);
+    };
+
+    if let Some(key) = dot_access_key(target, indices, options) {
+        // m["k"] -> m.k
+        let select = SelectExpr {
+            operand: Box::new(target.clone()),
+            field: key.clone(),
+            test: false,
+        };
+        return format_select(&select, options);
+    }
+
+    let mut docs = vec![format_member_operand(target, options)];
+    docs.extend(index_link(indices, options));
+    Doc::concat(docs)
+}
+
+/// The key of an index that `prefer_dot_access` writes as a field selection
+fn dot_access_key<'a>(
+    target: &IdedExpr,
+    indices: &'a [IdedExpr],
+    options: &FormatOptions,
+) -> Option<&'a String> {
+    match indices {
+        [IdedExpr {
+            expr: Expr::Literal(CelVal::String(key)),
+            ..
+        }] if options.prefer_dot_access && is_field_name(key) && may_be_map(&target.expr) => {
+            Some(key)
+        }
+        _ => None,
+    }
+}
+
+/// The `[index]` part of an index operation
+///
+/// The parts are spliced into the operation's own concatenation, since
+/// `[`, one document, and `]` alone would read as a list literal to
+/// `attach_open_comments`.
+fn index_link(indices: &[IdedExpr], options: &FormatOptions) -> [Doc; 3] {
+    let index_docs: Vec<Doc> = indices.iter().map(|e| format_expr(e, options)).collect();
+    [
+        Doc::text("["),
+        Doc::join(index_docs, Doc::text(", ")),
+        Doc::text("]"),
+    ]
+}
+
+/// Format the parenthesized arguments of a call
+///
+/// A lone atom (an identifier, literal, or field path) hugs the parens even
+/// when it overflows, since breaking around it gains no width. So does a lone
+/// list, map, or struct, which breaks inside its own brackets instead: CEL
+/// has no named arguments, and functions that take options take them as a
+/// map, as in `f({"timeout": 5})`.
+fn format_call_args(args: &[IdedExpr], options: &FormatOptions) -> Doc {
+    match args {
+        [] => Doc::text("()"),
+        [arg]
+            if (is_atom(&arg.expr) || is_collection_literal(&arg.expr))
+                && options.max_inline_args != Some(0) =>
+        {
+            Doc::parens(format_expr(arg, options))
+        }
+        // Past `options.max_inline_args`, only the argument list breaks:
+        // the expression around the call can still stay on one line
+        _ => {
+            let doc = Doc::wrap_parens(format_args(args, options));
+            match options.max_inline_args {
+                Some(max) if args.len() > max => doc.force_break(),
+                _ => doc,
+            }
+        }
+    }
+}
+
+/// Check if an expression is an identifier, a literal, or a field path
+fn is_atom(expr: &Expr) -> bool {
+    match expr {
+        Expr::Ident(_) | Expr::Literal(_) => true,
+        Expr::Select(select) => !select.test && is_atom(&select.operand.expr),
+        _ => false,
+    }
+}
+
+/// Format function arguments
+fn format_args(args: &[IdedExpr], options: &FormatOptions) -> Doc {
+    if args.is_empty() {
+        return Doc::nil();
+    }
+
+    // Never a trailing comma, whatever `options.trailing_comma` says: CEL's
+    // grammar allows one in list and map literals but not in argument lists,
+    // so `f(a, b,)` doesn't parse
+    let arg_docs: Vec<Doc> = args.iter().map(|e| format_expr(e, options)).collect();
+    Doc::join_comma(arg_docs, false)
+}
+
+/// Format a list literal
+fn format_list(list: &ListExpr, options: &FormatOptions) -> Doc {
+    if list.elements.is_empty() {
+        return Doc::text("[]");
+    }
+
+    let elem_docs: Vec<Doc> = list
+        .elements
+        .iter()
+        .map(|e| format_expr(e, options))
+        .collect();
+
+    // For simple short lists, always keep them inline for consistency
+    // A list is "simple" if all elements are literals or identifiers
+    let is_simple = list
+        .elements
+        .iter()
+        .all(|elem| matches!(elem.expr, Expr::Literal(_) | Expr::Ident(_)));
+
+    if is_simple && list.elements.len() <= 5 {
+        // Format inline without grouping
+        Doc::concat(vec![
+            Doc::text("["),
+            Doc::join(elem_docs, Doc::text(", ")),
+            Doc::text("]"),
+        ])
+    } else {
+        // Use wrapping for complex or long lists
+        wrap_collection("[", elem_docs, "]", options)
+    }
+}
+
+/// Format a map literal
+fn format_map(map: &MapExpr, options: &FormatOptions) -> Doc {
+    if map.entries.is_empty() {
+        return Doc::text("{}");
     }
 
-    let entry_docs: Vec<Doc> = map
+    let entries: Vec<(Doc, Doc)> = map
         .entries
         .iter()
         .filter_map(|ided_entry| match &ided_entry.expr {
             EntryExpr::MapEntry(entry) => {
-                let key = format_expr(&entry.key);
-                let value = format_expr(&entry.value);
-                Some(Doc::concat(vec![key, Doc::text(": "), value]))
+                let key = Doc::concat(vec![
+                    optional_marker(entry.optional),
+                    format_expr(&entry.key, options),
+                ]);
+                Some((key, format_expr(&entry.value, options)))
             }
             _ => None,
         })
         .collect();
 
-    Doc::wrap_braces(Doc::join_comma(entry_docs, true))
+    let entry_docs = format_entries(entries, options);
+    wrap_collection("{", break_past_threshold(entry_docs, options), "}", options)
+}
+
+/// Wrap the elements of a list, map, or struct in brackets that break
+/// according to `options.closing_bracket_style`
+pub(crate) fn wrap_collection(
+    open: &str,
+    docs: Vec<Doc>,
+    close: &str,
+    options: &FormatOptions,
+) -> Doc {
+    match options.closing_bracket_style {
+        ClosingBracketStyle::SameColumnAsOpen => Doc::group(Doc::concat(vec![
+            Doc::text(open),
+            Doc::indent(Doc::concat(vec![
+                Doc::soft_line(),
+                Doc::join_comma(docs, options.trailing_comma),
+            ])),
+            Doc::soft_line(),
+            Doc::text(close),
+        ])),
+        ClosingBracketStyle::Hugging => {
+            Doc::wrap_hugging(open, Doc::join_comma(docs, false), close)
+        }
+    }
+}
+
+/// The `?` before the key of an optional map or struct entry (`{?k: v}`)
+fn optional_marker(optional: bool) -> Doc {
+    if optional {
+        Doc::text("?")
+    } else {
+        Doc::nil()
+    }
+}
+
+/// Format the colon between a map or struct key and its value
+fn format_colon(options: &FormatOptions) -> Doc {
+    match options.colon_spacing {
+        ColonSpacing::After => Doc::text(": "),
+        ColonSpacing::None => Doc::text(":"),
+        ColonSpacing::Around => Doc::text(" : "),
+    }
 }
 
 /// Format a struct literal
-fn format_struct(s: &StructExpr) -> Doc {
-    let name = Doc::text(s.type_name.clone());
+fn format_struct(s: &StructExpr, options: &FormatOptions) -> Doc {
+    let name = Doc::token(TokenKind::Identifier, s.type_name.clone());
 
     if s.entries.is_empty() {
-        return Doc::concat(vec![name, Doc::text("{}")]);
+        let before = if options.empty_struct_space_before {
+            " "
+        } else {
+            ""
+        };
+        let inside = if options.empty_struct_space_inside {
+            " "
+        } else {
+            ""
+        };
+        return Doc::concat(vec![name, Doc::text(format!("{}{{{}}}", before, inside))]);
     }
 
-    let field_docs: Vec<Doc> = s
+    let fields: Vec<(Doc, Doc)> = s
         .entries
         .iter()
         .filter_map(|ided_entry| match &ided_entry.expr {
             EntryExpr::StructField(field) => {
-                let key = Doc::text(field.field.clone());
-                let value = format_expr(&field.value);
-                Some(Doc::concat(vec![key, Doc::text(": "), value]))
+                let key = Doc::concat(vec![
+                    optional_marker(field.optional),
+                    Doc::token(TokenKind::Identifier, field.field.clone()),
+                ]);
+                Some((key, format_expr(&field.value, options)))
             }
             _ => None,
         })
         .collect();
 
-    Doc::concat(vec![
-        name,
-        Doc::wrap_braces(Doc::join_comma(field_docs, true)),
-    ])
+    let field_docs = break_past_threshold(format_entries(fields, options), options);
+    Doc::concat(vec![name, wrap_collection("{", field_docs, "}", options)])
+}
+
+/// Join the keys and values of map or struct entries with colons
+///
+/// With `options.align_values`, each colon is followed by enough padding to
+/// bring its value to the column of the value after the widest key, but only
+/// when the entries are on separate lines. Nested maps and structs are aligned
+/// on their own.
+fn format_entries(entries: Vec<(Doc, Doc)>, options: &FormatOptions) -> Vec<Doc> {
+    let widths: Vec<Option<usize>> = entries.iter().map(|(key, _)| key_width(key)).collect();
+    let widest = widths.iter().flatten().copied().max().unwrap_or(0);
+
+    entries
+        .into_iter()
+        .zip(widths)
+        .map(|((key, value), width)| {
+            let padding = match width {
+                Some(width) if options.align_values => {
+                    Doc::if_break(Doc::text(" ".repeat(widest - width)), Doc::nil())
+                }
+                _ => Doc::nil(),
+            };
+            Doc::concat(vec![key, format_colon(options), padding, value])
+        })
+        .collect()
+}
+
+/// The width of a key in characters, or `None` if it spans lines
+fn key_width(key: &Doc) -> Option<usize> {
+    key.measure_flat()?;
+    Some(key.render_flat().chars().count())
+}
+
+/// Recompute the padding `format_entries` put after each key, for keys whose
+/// text has changed since
+///
+/// The entries of one map or struct are the children of one concatenation,
+/// the first behind a `BreakParent` when `break_map_threshold` forces a break.
+fn realign_entries(doc: &mut Doc) {
+    match doc {
+        Doc::Concat(docs) => {
+            let widest = docs
+                .iter_mut()
+                .filter_map(aligned_entry)
+                .filter_map(|entry| key_width(&entry[0]))
+                .max()
+                .unwrap_or(0);
+            for child in docs.iter_mut() {
+                if let Some(entry) = aligned_entry(child) {
+                    if let Some(width) = key_width(&entry[0]) {
+                        entry[2] = Doc::if_break(Doc::text(" ".repeat(widest - width)), Doc::nil());
+                    }
+                }
+                realign_entries(child);
+            }
+        }
+        Doc::Indent(doc) | Doc::Align(_, doc) | Doc::Group(doc) | Doc::BrokenGroup(doc) => {
+            realign_entries(doc)
+        }
+        Doc::IfBreak {
+            break_doc,
+            flat_doc,
+        } => {
+            realign_entries(break_doc);
+            realign_entries(flat_doc);
+        }
+        _ => {}
+    }
+}
+
+/// The key, colon, padding, and value of an entry `format_entries` aligned
+fn aligned_entry(doc: &mut Doc) -> Option<&mut [Doc]> {
+    let Doc::Concat(parts) = doc else {
+        return None;
+    };
+    match parts.as_mut_slice() {
+        [Doc::BreakParent, entry] => aligned_entry(entry),
+        entry @ [_, _, _, _] if is_padding(&entry[2]) => Some(entry),
+        _ => None,
+    }
+}
+
+/// Check if a document is the padding `format_entries` puts after a key
+fn is_padding(doc: &Doc) -> bool {
+    match doc {
+        Doc::IfBreak {
+            break_doc,
+            flat_doc,
+        } => {
+            matches!(&**break_doc, Doc::Text(s) if s.chars().all(|c| c == ' '))
+                && matches!(**flat_doc, Doc::Nil)
+        }
+        _ => false,
+    }
+}
+
+/// Force map or struct entries onto separate lines when there are more than
+/// `options.break_map_threshold` of them
+fn break_past_threshold(mut entries: Vec<Doc>, options: &FormatOptions) -> Vec<Doc> {
+    if options
+        .break_map_threshold
+        .is_some_and(|max| entries.len() > max)
+    {
+        let first = entries.remove(0);
+        entries.insert(0, Doc::concat(vec![Doc::break_parent(), first]));
+    }
+    entries
 }
 
 /// Format a comprehension expression
-fn format_comprehension(comp: &ComprehensionExpr) -> Doc {
+fn format_comprehension(comp: &ComprehensionExpr, options: &FormatOptions) -> Doc {
+    if let Some((name, args)) = comprehension_macro(comp, options) {
+        return format_macro(&comp.iter_range, name, &comp.iter_var, args, options);
+    }
+
+    // No macro produces this comprehension (e.g. a fold built by another
+    // frontend). `check_writable` keeps these out of formatted source,
+    // so this only shows up in documents built straight from an AST
+    format_debug_comprehension(comp, options)
+}
+
+/// Check that an expression can be written in CEL: every comprehension is
+/// the expansion of a macro, every index has a target and an index, and every
+/// name is one the parser reads back
+///
+/// The parser never produces anything else, but the rewrites before
+/// formatting, or another frontend's AST, might.
+fn check_writable(ast: &mut IdedExpr, options: &FormatOptions) -> Result<(), FormatError> {
+    let mut unsupported = None;
+    rewrite(ast, &mut |expr| {
+        if unsupported.is_some() {
+            return;
+        }
+        unsupported = match &expr.expr {
+            Expr::Comprehension(comp) if comprehension_macro(comp, options).is_none() => {
+                Some("a comprehension that no macro expands to")
+            }
+            Expr::Call(call)
+                if call.func_name == "_[_]" && call.target.is_none() && call.args.len() != 2 =>
+            {
+                Some("an index operator without exactly one index")
+            }
+            Expr::Ident(name) if !name.starts_with('@') && !is_plain_ident(name) => {
+                Some("an identifier that isn't a valid CEL name")
+            }
+            Expr::Select(select)
+                if !is_quoted_field(&select.field) && !is_plain_ident(&select.field) =>
+            {
+                Some("a field name that CEL has no spelling for")
+            }
+            _ => None,
+        };
+    });
+    unsupported.map_or(Ok(()), |syntax| {
+        Err(FormatError::UnsupportedSyntax { syntax })
+    })
+}
+
+/// The macro that expands to a comprehension, with its formatted arguments
+/// after the iteration variable
+fn comprehension_macro(
+    comp: &ComprehensionExpr,
+    options: &FormatOptions,
+) -> Option<(&'static str, Vec<Doc>)> {
     // Comprehensions are the result of macro expansion
     // Try to detect common patterns and format them back to macro form
 
@@ -384,33 +1631,21 @@ fn format_comprehension(comp: &ComprehensionExpr) -> Doc {
                 // Check if loop_cond is true
                 if is_literal_true(&comp.loop_cond.expr) {
                     // Check if loop_step is @result + [expr]
-                    if let Some(map_expr) = extract_map_pattern(comp) {
-                        let range = format_expr(&comp.iter_range);
-                        let var = Doc::text(comp.iter_var.clone());
-                        return Doc::concat(vec![
-                            range,
-                            Doc::text(".map("),
-                            var,
-                            Doc::text(", "),
-                            map_expr,
-                            Doc::text(")"),
-                        ]);
+                    if let Some(map_expr) = extract_map_pattern(comp, options) {
+                        return Some(("map", vec![map_expr]));
                     }
                 }
 
                 // Check for filter() pattern:
                 // loop_cond = predicate, loop_step = @result + [iter_var]
-                if let Some(filter_expr) = extract_filter_pattern(comp) {
-                    let range = format_expr(&comp.iter_range);
-                    let var = Doc::text(comp.iter_var.clone());
-                    return Doc::concat(vec![
-                        range,
-                        Doc::text(".filter("),
-                        var,
-                        Doc::text(", "),
-                        filter_expr,
-                        Doc::text(")"),
-                    ]);
+                if let Some(filter_expr) = extract_filter_pattern(comp, options) {
+                    return Some(("filter", vec![filter_expr]));
+                }
+
+                // Check for the three-argument map() pattern:
+                // loop_step = predicate ? (@result + [expr]) : @result
+                if let Some((filter_expr, map_expr)) = extract_map_filter_pattern(comp, options) {
+                    return Some(("map", vec![filter_expr, map_expr]));
                 }
             }
         }
@@ -418,75 +1653,275 @@ fn format_comprehension(comp: &ComprehensionExpr) -> Doc {
         // Check for all() pattern:
         // accu_init = true, loop_step = @result && predicate
         if is_literal_true(&comp.accu_init.expr) {
-            if let Some(all_expr) = extract_all_pattern(comp) {
-                let range = format_expr(&comp.iter_range);
-                let var = Doc::text(comp.iter_var.clone());
-                return Doc::concat(vec![
-                    range,
-                    Doc::text(".all("),
-                    var,
-                    Doc::text(", "),
-                    all_expr,
-                    Doc::text(")"),
-                ]);
+            if let Some(all_expr) = extract_all_pattern(comp, options) {
+                return Some(("all", vec![all_expr]));
             }
         }
 
         // Check for exists() pattern:
         // accu_init = false, loop_step = @result || predicate
         if is_literal_false(&comp.accu_init.expr) {
-            if let Some(exists_expr) = extract_exists_pattern(comp) {
-                let range = format_expr(&comp.iter_range);
-                let var = Doc::text(comp.iter_var.clone());
-                return Doc::concat(vec![
-                    range,
-                    Doc::text(".exists("),
-                    var,
-                    Doc::text(", "),
-                    exists_expr,
-                    Doc::text(")"),
-                ]);
+            if let Some(exists_expr) = extract_exists_pattern(comp, options) {
+                return Some(("exists", vec![exists_expr]));
             }
         }
 
         // Check for exists_one() pattern:
         // accu_init = 0, loop_step = predicate ? (@result + 1) : @result, result = @result == 1
         if is_literal_int(&comp.accu_init.expr, 0) {
-            if let Some(exists_one_expr) = extract_exists_one_pattern(comp) {
-                let range = format_expr(&comp.iter_range);
-                let var = Doc::text(comp.iter_var.clone());
-                return Doc::concat(vec![
-                    range,
-                    Doc::text(".exists_one("),
-                    var,
-                    Doc::text(", "),
-                    exists_one_expr,
-                    Doc::text(")"),
-                ]);
+            if let Some(exists_one_expr) = extract_exists_one_pattern(comp, options) {
+                return Some(("exists_one", vec![exists_one_expr]));
             }
         }
     }
 
-    // Fallback: couldn't detect a macro pattern
-    // This should not happen in practice since CEL only creates comprehensions through
-    // known macros (map, filter, all, exists, exists_one). If we reach here, it indicates
-    // either:
-    // 1. A new macro was added to CEL that we don't support yet
-    // 2. There's a bug in our pattern detection
-    // 3. The CEL parser created an unexpected comprehension
-    //
-    // Rather than silently corrupting the source with a placeholder, we panic with a
-    // descriptive error message.
-    panic!(
-        "Unsupported comprehension pattern. Please report this as a bug.\n\
-         Comprehension details:\n\
-         - iter_var: {}\n\
-         - accu_var: {}\n\
-         - accu_init: {:?}\n\
-         - loop_cond: {:?}\n\
-         - loop_step: {:?}",
-        comp.iter_var, comp.accu_var, comp.accu_init.expr, comp.loop_cond.expr, comp.loop_step.expr
-    )
+    None
+}
+
+/// The `.name(iter_var, args...)` part of a method-style macro call such as
+/// `range.all(var, predicate)`
+fn macro_link(name: &str, iter_var: &str, args: Vec<Doc>) -> Doc {
+    let mut parts = vec![Doc::token(TokenKind::Identifier, iter_var)];
+    parts.extend(args);
+
+    Doc::concat(vec![
+        Doc::text("."),
+        Doc::token(TokenKind::Keyword, name),
+        Doc::text("("),
+        Doc::join(parts, Doc::text(", ")),
+        Doc::text(")"),
+    ])
+}
+
+/// The `.name(args)` part of a method call
+fn method_link(call: &CallExpr, options: &FormatOptions) -> Doc {
+    if let Some(link) = unexpanded_macro_link(call, options) {
+        return link;
+    }
+    Doc::concat(vec![
+        Doc::text("."),
+        Doc::token(TokenKind::Function, call.func_name.clone()),
+        format_call_args(&call.args, options),
+    ])
+}
+
+/// The `.field` part of a field selection
+fn select_link(select: &SelectExpr) -> Doc {
+    Doc::concat(vec![
+        Doc::text("."),
+        Doc::token(TokenKind::Identifier, escape_ident(&select.field)),
+    ])
+}
+
+/// One link of a member chain
+enum ChainLink {
+    /// A `.name(...)` or `.field` link, which the chain may break before
+    Line(Doc),
+
+    /// The parts of an `[index]` link, which stays on the line of the link
+    /// before it
+    Index([Doc; 3]),
+}
+
+/// With `break_member_chains`, the target and `.name(...)` part of a method
+/// call or method-style macro, or with `break_long_selects` the `.field` or
+/// `.?field` part of a field selection
+///
+/// An index or optional index is a link too, so a chain carries on through
+/// `a.b()[0].c()` and `a.?b[?0].c()`.
+fn chain_link<'a>(
+    expr: &'a IdedExpr,
+    options: &FormatOptions,
+) -> Option<(&'a IdedExpr, ChainLink)> {
+    match &expr.expr {
+        Expr::Select(select) if options.break_long_selects && !select.test => {
+            Some((&select.operand, ChainLink::Line(select_link(select))))
+        }
+        Expr::Call(call) if call.func_name == "_[_]" && call.target.is_none() => {
+            let (target, indices) = call.args.split_first()?;
+            if indices.is_empty()
+                || dot_access_key(target, indices, options).is_some()
+                || options.custom_formatters.get(&call.func_name).is_some()
+            {
+                return None;
+            }
+            Some((target, ChainLink::Index(index_link(indices, options))))
+        }
+        Expr::Call(call) if call.func_name == "_?._" && options.break_long_selects => {
+            match call.args.as_slice() {
+                [operand, IdedExpr {
+                    expr: Expr::Literal(CelVal::String(field)),
+                    ..
+                }] if call.target.is_none() => {
+                    Some((operand, ChainLink::Line(optional_select_link(field))))
+                }
+                _ => None,
+            }
+        }
+        Expr::Call(call) if call.func_name == "_[?_]" && call.target.is_none() => {
+            let [operand, index] = call.args.as_slice() else {
+                return None;
+            };
+            let link = optional_index_link(index, options);
+            Some((operand, ChainLink::Index(link)))
+        }
+        Expr::Call(call) if options.break_member_chains => {
+            let target = call.target.as_deref()?;
+            if options.custom_formatters.get(&call.func_name).is_some() {
+                return None;
+            }
+            Some((target, ChainLink::Line(method_link(call, options))))
+        }
+        Expr::Comprehension(comp) if options.break_member_chains => {
+            let (name, args) = comprehension_macro(comp, options)?;
+            let link = macro_link(name, &comp.iter_var, args);
+            Some((&comp.iter_range, ChainLink::Line(link)))
+        }
+        _ => None,
+    }
+}
+
+/// Format a method call or method-style macro on `target`
+///
+/// Only with `break_member_chains` is it laid out as part of a member chain;
+/// otherwise the call follows its target like any other member access.
+fn format_method_call(target: &IdedExpr, link: Doc, options: &FormatOptions) -> Doc {
+    if options.break_member_chains {
+        format_member_call(target, link, options)
+    } else {
+        Doc::concat(vec![format_member_operand(target, options), link])
+    }
+}
+
+/// Format a link of a member chain on `target`
+///
+/// A chain of two or more calls, such as `xs.filter(x, x > 0).map(x, x * 2)`,
+/// is laid out as a group that breaks before each `.call()`, one per line.
+/// With `chain_first_call_inline` the first call stays on the receiver's line.
+fn format_member_call(target: &IdedExpr, link: Doc, options: &FormatOptions) -> Doc {
+    let mut links = vec![ChainLink::Line(link)];
+    let mut receiver = target;
+    while let Some((next, link)) = chain_link(receiver, options) {
+        links.push(link);
+        receiver = next;
+    }
+    links.reverse();
+
+    // Indexes right after the receiver stay with it, and each other index
+    // stays with the link before it
+    let mut head = vec![format_member_operand(receiver, options)];
+    let mut lines: Vec<Vec<Doc>> = Vec::new();
+    for link in links {
+        match (link, lines.last_mut()) {
+            (ChainLink::Index(parts), Some(line)) => line.extend(parts),
+            (ChainLink::Index(parts), None) => head.extend(parts),
+            (ChainLink::Line(link), _) => lines.push(vec![link]),
+        }
+    }
+    if lines.len() == 1 || (options.chain_first_call_inline && !lines.is_empty()) {
+        head.extend(lines.remove(0));
+    }
+    if lines.is_empty() {
+        return Doc::concat(head);
+    }
+
+    let rest = lines
+        .into_iter()
+        .map(|line| Doc::concat(std::iter::once(Doc::soft_line()).chain(line)));
+    Doc::group(Doc::concat(vec![
+        Doc::concat(head),
+        Doc::indent(Doc::concat(rest)),
+    ]))
+}
+
+/// Format a method-style macro call such as `range.all(var, predicate)`
+fn format_macro(
+    range: &IdedExpr,
+    name: &str,
+    iter_var: &str,
+    args: Vec<Doc>,
+    options: &FormatOptions,
+) -> Doc {
+    format_method_call(range, macro_link(name, iter_var, args), options)
+}
+
+/// Format a macro call the parser left unexpanded
+///
+/// The `cel` parser expands macros into selects and comprehensions, but ASTs
+/// from a parser with expansion turned off keep them as plain calls. Those
+/// get the same layout as the expanded forms.
+fn format_unexpanded_macro(call: &CallExpr, options: &FormatOptions) -> Option<Doc> {
+    let Some(target) = &call.target else {
+        // has(a.b)
+        let [arg] = call.args.as_slice() else {
+            return None;
+        };
+        return match &arg.expr {
+            Expr::Select(select) if call.func_name == "has" && !select.test => Some(format_select(
+                &SelectExpr {
+                    test: true,
+                    ..select.clone()
+                },
+                options,
+            )),
+            _ => None,
+        };
+    };
+
+    let link = unexpanded_macro_link(call, options)?;
+    Some(format_method_call(target, link, options))
+}
+
+/// The `.name(var, ...)` part of an unexpanded method-style macro call
+fn unexpanded_macro_link(call: &CallExpr, options: &FormatOptions) -> Option<Doc> {
+    let [first, rest @ ..] = call.args.as_slice() else {
+        return None;
+    };
+    let Expr::Ident(iter_var) = &first.expr else {
+        return None;
+    };
+    let arity_ok = match call.func_name.as_str() {
+        "all" | "exists" | "exists_one" | "filter" => rest.len() == 1,
+        "map" => rest.len() == 1 || rest.len() == 2,
+        _ => false,
+    };
+    if !arity_ok {
+        return None;
+    }
+
+    let args = rest.iter().map(|e| format_expr(e, options)).collect();
+    Some(macro_link(&call.func_name, iter_var, args))
+}
+
+/// Format a comprehension that doesn't match any macro
+///
+/// CEL has no surface syntax for arbitrary comprehensions, so this uses the
+/// `__comprehension__(iter_var, [iter_var2,] iter_range, accu_var, accu_init,
+/// loop_cond, loop_step, result)` form from CEL's debug printers. It keeps
+/// every part of the comprehension visible, but it isn't CEL and doesn't
+/// parse.
+fn format_debug_comprehension(comp: &ComprehensionExpr, options: &FormatOptions) -> Doc {
+    let mut parts = vec![Doc::token(TokenKind::Identifier, comp.iter_var.clone())];
+    if let Some(iter_var2) = &comp.iter_var2 {
+        parts.push(Doc::token(TokenKind::Identifier, iter_var2.clone()));
+    }
+    parts.push(format_expr(&comp.iter_range, options));
+    parts.push(Doc::token(TokenKind::Identifier, comp.accu_var.clone()));
+    parts.extend(
+        [
+            &comp.accu_init,
+            &comp.loop_cond,
+            &comp.loop_step,
+            &comp.result,
+        ]
+        .into_iter()
+        .map(|e| format_expr(e, options)),
+    );
+
+    Doc::concat(vec![
+        Doc::token(TokenKind::Keyword, "__comprehension__"),
+        Doc::wrap_parens(Doc::join_comma(parts, false)),
+    ])
 }
 
 /// Check if an expression is the literal true
@@ -505,7 +1940,7 @@ fn is_literal_int(expr: &Expr, value: i64) -> bool {
 }
 
 /// Extract map() pattern: @result + [expr]
-fn extract_map_pattern(comp: &ComprehensionExpr) -> Option<Doc> {
+fn extract_map_pattern(comp: &ComprehensionExpr, options: &FormatOptions) -> Option<Doc> {
     if let Expr::Call(call) = &comp.loop_step.expr {
         if call.func_name == "_+_" && call.args.len() == 2 {
             // Check if first arg is @result
@@ -514,7 +1949,7 @@ fn extract_map_pattern(comp: &ComprehensionExpr) -> Option<Doc> {
                     // Check if second arg is [expr]
                     if let Expr::List(list) = &call.args[1].expr {
                         if list.elements.len() == 1 {
-                            return Some(format_expr(&list.elements[0]));
+                            return Some(format_expr(&list.elements[0], options));
                         }
                     }
                 }
@@ -526,7 +1961,7 @@ fn extract_map_pattern(comp: &ComprehensionExpr) -> Option<Doc> {
 
 /// Extract filter() pattern
 /// Pattern: loop_step = predicate ? (@result + [var]) : @result
-fn extract_filter_pattern(comp: &ComprehensionExpr) -> Option<Doc> {
+fn extract_filter_pattern(comp: &ComprehensionExpr, options: &FormatOptions) -> Option<Doc> {
     // loop_step should be a ternary: predicate ? (@result + [var]) : @result
     if let Expr::Call(call) = &comp.loop_step.expr {
         if call.func_name == "_?_:_" && call.args.len() == 3 {
@@ -547,7 +1982,7 @@ fn extract_filter_pattern(comp: &ComprehensionExpr) -> Option<Doc> {
                                             if let Expr::Ident(else_name) = &else_branch.expr {
                                                 if else_name == "@result" {
                                                     // This is a filter!
-                                                    return Some(format_expr(predicate));
+                                                    return Some(format_expr(predicate, options));
                                                 }
                                             }
                                         }
@@ -563,13 +1998,29 @@ fn extract_filter_pattern(comp: &ComprehensionExpr) -> Option<Doc> {
     None
 }
 
-/// Extract all() pattern: @result && predicate
-fn extract_all_pattern(comp: &ComprehensionExpr) -> Option<Doc> {
+/// Extract the three-argument map() pattern
+/// Pattern: loop_step = predicate ? (@result + [expr]) : @result
+fn extract_map_filter_pattern(
+    comp: &ComprehensionExpr,
+    options: &FormatOptions,
+) -> Option<(Doc, Doc)> {
     if let Expr::Call(call) = &comp.loop_step.expr {
-        if call.func_name == "_&&_" && call.args.len() == 2 {
-            if let Expr::Ident(name) = &call.args[0].expr {
-                if name == "@result" {
-                    return Some(format_expr(&call.args[1]));
+        if call.func_name == "_?_:_" && call.args.len() == 3 {
+            if let (Expr::Call(add_call), Expr::Ident(else_name)) =
+                (&call.args[1].expr, &call.args[2].expr)
+            {
+                if add_call.func_name == "_+_" && add_call.args.len() == 2 && else_name == "@result"
+                {
+                    if let (Expr::Ident(name), Expr::List(list)) =
+                        (&add_call.args[0].expr, &add_call.args[1].expr)
+                    {
+                        if name == "@result" && list.elements.len() == 1 {
+                            return Some((
+                                format_expr(&call.args[0], options),
+                                format_expr(&list.elements[0], options),
+                            ));
+                        }
+                    }
                 }
             }
         }
@@ -577,13 +2028,52 @@ fn extract_all_pattern(comp: &ComprehensionExpr) -> Option<Doc> {
     None
 }
 
-/// Extract exists() pattern: @result || predicate
-fn extract_exists_pattern(comp: &ComprehensionExpr) -> Option<Doc> {
+/// Extract all() pattern: @result && predicate
+fn extract_all_pattern(comp: &ComprehensionExpr, options: &FormatOptions) -> Option<Doc> {
+    if !is_short_circuit(&comp.loop_cond.expr, false) {
+        return None;
+    }
     if let Expr::Call(call) = &comp.loop_step.expr {
-        if call.func_name == "_||_" && call.args.len() == 2 {
+        if call.func_name == "_&&_" && call.args.len() == 2 {
+            if let Expr::Ident(name) = &call.args[0].expr {
+                if name == "@result" {
+                    return Some(format_expr(&call.args[1], options));
+                }
+            }
+        }
+    }
+    None
+}
+
+/// Check if a loop condition stops the loop once `@result` is settled:
+/// `@result` for all() and `!@result` for exists() (`negated`), possibly
+/// wrapped in `@not_strictly_false` as the parser expands them
+fn is_short_circuit(loop_cond: &Expr, negated: bool) -> bool {
+    let cond = match loop_cond {
+        Expr::Call(call) if call.func_name == "@not_strictly_false" && call.args.len() == 1 => {
+            &call.args[0].expr
+        }
+        other => other,
+    };
+    let is_result = |expr: &Expr| matches!(expr, Expr::Ident(name) if name == "@result");
+    match cond {
+        Expr::Call(call) if negated && call.func_name == "!_" && call.args.len() == 1 => {
+            is_result(&call.args[0].expr)
+        }
+        expr => !negated && is_result(expr),
+    }
+}
+
+/// Extract exists() pattern: @result || predicate
+fn extract_exists_pattern(comp: &ComprehensionExpr, options: &FormatOptions) -> Option<Doc> {
+    if !is_short_circuit(&comp.loop_cond.expr, true) {
+        return None;
+    }
+    if let Expr::Call(call) = &comp.loop_step.expr {
+        if call.func_name == "_||_" && call.args.len() == 2 {
             if let Expr::Ident(name) = &call.args[0].expr {
                 if name == "@result" {
-                    return Some(format_expr(&call.args[1]));
+                    return Some(format_expr(&call.args[1], options));
                 }
             }
         }
@@ -593,7 +2083,12 @@ fn extract_exists_pattern(comp: &ComprehensionExpr) -> Option<Doc> {
 
 /// Extract exists_one() pattern
 /// Pattern: loop_step = predicate ? (@result + 1) : @result
-fn extract_exists_one_pattern(comp: &ComprehensionExpr) -> Option<Doc> {
+fn extract_exists_one_pattern(comp: &ComprehensionExpr, options: &FormatOptions) -> Option<Doc> {
+    // exists_one() never stops early
+    if !is_literal_true(&comp.loop_cond.expr) {
+        return None;
+    }
+
     // loop_step should be a ternary: predicate ? (@result + 1) : @result
     if let Expr::Call(call) = &comp.loop_step.expr {
         if call.func_name == "_?_:_" && call.args.len() == 3 {
@@ -621,7 +2116,7 @@ fn extract_exists_one_pattern(comp: &ComprehensionExpr) -> Option<Doc> {
                                                     && is_literal_int(&result_call.args[1].expr, 1)
                                                 {
                                                     // This is exists_one!
-                                                    return Some(format_expr(predicate));
+                                                    return Some(format_expr(predicate, options));
                                                 }
                                             }
                                         }
@@ -638,24 +2133,57 @@ fn extract_exists_one_pattern(comp: &ComprehensionExpr) -> Option<Doc> {
 }
 
 /// Check if an expression needs parentheses based on operator precedence
-fn needs_parens(expr: &Expr, parent_op: &str) -> bool {
-    match expr {
-        Expr::Call(call) if is_binary_op(&call.func_name) => {
-            let child_prec = op_precedence(&call.func_name);
-            let parent_prec = op_precedence(parent_op);
-            child_prec < parent_prec
-        }
+fn needs_parens(expr: &Expr, parent_op: &str, options: &FormatOptions) -> bool {
+    if is_wrapped_string(expr, options) {
+        return op_precedence("_+_") <= op_precedence(parent_op);
+    }
+    let Expr::Call(call) = expr else {
+        return false;
+    };
+    // With `not_in_operator`, `!(x in y)` is written as the operator `not in`
+    let op = if options.not_in_operator && is_negated_in(call) {
+        "@not_in"
+    } else {
+        call.func_name.as_str()
+    };
+
+    // Comparisons don't chain: `a < b < c` is `(a < b) < c`, which reads
+    // like something else, so a comparison inside another is always wrapped
+    if is_comparison_op(op) && is_comparison_op(parent_op) {
+        return true;
+    }
+    (is_binary_op(op) || op == "@not_in" || op == "_?_:_")
+        && op_precedence(op) < op_precedence(parent_op)
+}
+
+/// Check if a call is `!(x in y)`
+fn is_negated_in(call: &CallExpr) -> bool {
+    match call.args.as_slice() {
+        [arg] if call.func_name == "!_" && call.target.is_none() => matches!(
+            &arg.expr,
+            Expr::Call(inner)
+                if inner.func_name == "@in" && inner.target.is_none() && inner.args.len() == 2
+        ),
         _ => false,
     }
 }
 
+/// Check if an operator is one of CEL's relations, which share a precedence
+fn is_comparison_op(op: &str) -> bool {
+    matches!(
+        op,
+        "_==_" | "_!=_" | "_<_" | "_<=_" | "_>_" | "_>=_" | "@in" | "@not_in"
+    )
+}
+
 /// Get operator precedence (higher = tighter binding)
 fn op_precedence(op: &str) -> i32 {
     match op {
+        "_?_:_" => 0,
         "_||_" => 1,
         "_&&_" => 2,
         "_==_" | "_!=_" => 3,
-        "_<_" | "_<=_" | "_>_" | "_>=_" | "@in" => 4,
+        "_<_" | "_<=_" | "_>_" | "_>=_" | "@in" | "@not_in" => 4,
         "_+_" | "_-_" => 5,
         "_*_" | "_/_" | "_%_" => 6,
         "!_" | "-_" => 7,
@@ -663,29 +2191,56 @@ fn op_precedence(op: &str) -> i32 {
     }
 }
 
-/// Escape a string for CEL string literals
-fn escape_string(s: &str) -> String {
+/// Escape a string literal's contents for a double-quoted literal
+///
+/// Control characters and the Unicode line and paragraph separators are
+/// escaped too, so the output never contains invisible or line-breaking
+/// characters inside a string. CEL has no `\/` escape, so
+/// `escape_forward_slash` writes `/` as `\x2f`.
+fn escape_string(s: &str, options: &FormatOptions) -> String {
     s.chars()
         .flat_map(|c| match c {
             '"' => vec!['\\', '"'],
+            '/' if options.escape_forward_slash => "\\x2f".chars().collect(),
             '\\' => vec!['\\', '\\'],
             '\n' => vec!['\\', 'n'],
             '\r' => vec!['\\', 'r'],
             '\t' => vec!['\\', 't'],
+            '\x07' => vec!['\\', 'a'],
+            '\x08' => vec!['\\', 'b'],
+            '\x0b' => vec!['\\', 'v'],
+            '\x0c' => vec!['\\', 'f'],
+            c if c.is_control() && options.minimize_escapes && (c as u32) < 0x100 => {
+                format!("\\x{:02x}", c as u32).chars().collect()
+            }
+            c if c.is_control() || c == '\u{2028}' || c == '\u{2029}' => {
+                format!("\\u{:04x}", c as u32).chars().collect()
+            }
             c => vec![c],
         })
         .collect()
 }
 
-/// Escape bytes for CEL byte literals
+/// Write a bytes literal in the configured style
+fn format_bytes(b: &[u8], options: &FormatOptions) -> String {
+    let escaped = format!("b\"{}\"", escape_bytes(b));
+    if options.bytes_literal_style == BytesLiteralStyle::Base64 {
+        let decoded = format!("base64.decode(\"{}\")", encode_base64(b));
+        if decoded.len() < escaped.len() {
+            return decoded;
+        }
+    }
+    escaped
+}
+
+/// Escape bytes for a double-quoted bytes literal
+///
+/// The `cel` parser only takes `\x` and octal escapes in bytes literals, so
+/// everything but printable ASCII, including `"` and `\`, is written as `\xNN`.
 fn escape_bytes(b: &[u8]) -> String {
     b.iter()
         .flat_map(|&byte| match byte {
-            b'"' => vec![b'\\', b'"'],
-            b'\\' => vec![b'\\', b'\\'],
-            b'\n' => vec![b'\\', b'n'],
-            b'\r' => vec![b'\\', b'r'],
-            b'\t' => vec![b'\\', b't'],
+            b'"' | b'\\' => format!("\\x{:02x}", byte).into_bytes(),
             32..=126 => vec![byte],
             _ => format!("\\x{:02x}", byte).into_bytes(),
         })
@@ -696,6 +2251,12 @@ fn escape_bytes(b: &[u8]) -> String {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::options::{
+        CommentStyle, DoubleExponentStyle, DurationStyle, KeyPresenceStyle, TargetVersion,
+        TimestampStyle,
+    };
+    use cel::common::ast::{IdedEntryExpr, MapEntryExpr, StructFieldExpr};
+    use std::time::{Duration, UNIX_EPOCH};
 
     fn format_expr_str(input: &str) -> String {
         let options = FormatOptions::default();
@@ -754,6 +2315,29 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_colon_spacing() {
+        let source = r#"{"a": 1, "b": Point{x: 2}}"#;
+        let cases = [
+            (ColonSpacing::After, r#"{"a": 1, "b": Point{x: 2}}"#),
+            (ColonSpacing::None, r#"{"a":1, "b":Point{x:2}}"#),
+            (ColonSpacing::Around, r#"{"a" : 1, "b" : Point{x : 2}}"#),
+        ];
+        for (spacing, expected) in cases {
+            let options = FormatOptions::default().with_colon_spacing(spacing);
+            assert_eq!(format_cel(source, &options).unwrap(), expected);
+        }
+
+        // Broken entries keep the colon next to the key
+        let options = FormatOptions::default()
+            .with_max_width(10)
+            .with_colon_spacing(ColonSpacing::Around);
+        assert_eq!(
+            format_cel(r#"{"a": 1, "b": 2}"#, &options).unwrap(),
+            "{\n  \"a\" : 1,\n  \"b\" : 2,\n}"
+        );
+    }
+
     #[test]
     fn test_field_access() {
         assert_eq!(format_expr_str("user.name"), "user.name");
@@ -783,6 +2367,136 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_ternary_operator_break() {
+        let source = r#"x > 0 ? "positive" : "negative""#;
+
+        let options = FormatOptions::default().with_max_width(20);
+        assert_eq!(
+            format_cel(source, &options).unwrap(),
+            "x > 0\n? \"positive\"\n: \"negative\""
+        );
+
+        let options = options.with_ternary_operator_break(OperatorBreak::After);
+        assert_eq!(
+            format_cel(source, &options).unwrap(),
+            "x > 0 ?\n\"positive\" :\n\"negative\""
+        );
+
+        // Flat output is the same regardless of placement
+        let options = FormatOptions::default().with_ternary_operator_break(OperatorBreak::After);
+        assert_eq!(
+            format_cel(source, &options).unwrap(),
+            r#"x > 0 ? "positive" : "negative""#
+        );
+    }
+
+    #[test]
+    fn test_to_doc() {
+        fn tokens(doc: &Doc, out: &mut Vec<(TokenKind, String)>) {
+            match doc {
+                Doc::Token(kind, text) => out.push((*kind, text.clone())),
+                Doc::Concat(docs) => docs.iter().for_each(|d| tokens(d, out)),
+                Doc::Indent(d) | Doc::Align(_, d) | Doc::Group(d) | Doc::BrokenGroup(d) => {
+                    tokens(d, out)
+                }
+                Doc::IfBreak { break_doc, .. } => tokens(break_doc, out),
+                _ => {}
+            }
+        }
+
+        let options = FormatOptions::default();
+        let source = r#"has(a.b) && size(a.c) > 1"#;
+        let doc = to_doc(source, &options).unwrap();
+        assert_eq!(
+            doc.render(options.max_width, "  "),
+            format_cel(source, &options).unwrap()
+        );
+
+        let mut found = Vec::new();
+        tokens(&doc, &mut found);
+        assert_eq!(
+            found,
+            vec![
+                (TokenKind::Keyword, "has".to_string()),
+                (TokenKind::Identifier, "a".to_string()),
+                (TokenKind::Identifier, "b".to_string()),
+                (TokenKind::Operator, "&&".to_string()),
+                (TokenKind::Function, "size".to_string()),
+                (TokenKind::Identifier, "a".to_string()),
+                (TokenKind::Identifier, "c".to_string()),
+                (TokenKind::Operator, ">".to_string()),
+                (TokenKind::Literal, "1".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_format_preview() {
+        assert_eq!(format_preview("x>5&&y<10", 80).unwrap(), "x > 5 && y < 10");
+        assert_eq!(
+            format_preview("user.age >= 18 && user.active == true", 20).unwrap(),
+            "user.age >= 18 &&…"
+        );
+        assert_eq!(format_preview("abcdefghijkl", 5).unwrap(), "abcd…");
+
+        // Never wraps, even past the default width
+        let long = format!("[{}]", vec!["\"element\""; 20].join(", "));
+        let preview = format_preview(&long, 500).unwrap();
+        assert!(!preview.contains('\n'));
+        assert_eq!(preview.chars().count(), long.chars().count());
+    }
+
+    #[test]
+    fn test_in_operator() {
+        assert_eq!(format_expr_str("x in list"), "x in list");
+        assert_eq!(format_expr_str("x in [1, 2, 3]"), "x in [1, 2, 3]");
+        assert_eq!(
+            format_expr_str(r#"k in {"a": 1, "b": 2}"#),
+            r#"k in {"a": 1, "b": 2}"#
+        );
+        assert_eq!(
+            format_expr_str(r#"m.exists(k, k in {"a": 1}) && "b" in m"#),
+            r#"m.exists(k, k in {"a": 1}) && "b" in m"#
+        );
+    }
+
+    #[test]
+    fn test_in_operator_long_collection() {
+        let options = FormatOptions::default().with_max_width(40);
+
+        let source = r#"key in {"alpha": 1, "beta": 2, "gamma": 3, "delta": 4}"#;
+        assert_eq!(
+            format_cel(source, &options).unwrap(),
+            "key in {\n  \"alpha\": 1,\n  \"beta\": 2,\n  \"gamma\": 3,\n  \"delta\": 4,\n}"
+        );
+
+        let source = r#"key in ["alpha", "beta", "gamma", "delta", "epsilon", "zeta"]"#;
+        assert_eq!(
+            format_cel(source, &options).unwrap(),
+            "key in [\n  \"alpha\",\n  \"beta\",\n  \"gamma\",\n  \"delta\",\n  \"epsilon\",\n  \"zeta\",\n]"
+        );
+    }
+
+    #[test]
+    fn test_literal_spellings() {
+        let source = r#"0x10 + 1.50 + 1e3 + 0x1Fu + -5 + "0x10".size()"#;
+        let normalized = r#"16 + 1.5 + 1000.0 + 31u + -5 + "0x10".size()"#;
+
+        assert_eq!(format_expr_str(source), normalized);
+        assert_eq!(
+            format_expr_str("1.0 == 1.00 && 1e0 == 1.0"),
+            "1.0 == 1.0 && 1.0 == 1.0"
+        );
+
+        let options = FormatOptions::default().with_preserve_literal_spellings(true);
+        assert_eq!(format_cel(source, &options).unwrap(), source);
+
+        // `normalize_literals` wins when both are set
+        let options = options.with_normalize_literals(true);
+        assert_eq!(format_cel(source, &options).unwrap(), normalized);
+    }
+
     #[test]
     fn test_operator_precedence() {
         assert_eq!(format_expr_str("1 + 2 * 3"), "1 + 2 * 3");
@@ -873,6 +2587,71 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_map_filter_macro() {
+        assert_eq!(
+            format_expr_str("[1, 2, 3].map(x, x > 1, x * 2)"),
+            "[1, 2, 3].map(x, x > 1, x * 2)"
+        );
+    }
+
+    fn ided(expr: Expr) -> IdedExpr {
+        IdedExpr { id: 0, expr }
+    }
+
+    fn ident(name: &str) -> IdedExpr {
+        ided(Expr::Ident(name.to_string()))
+    }
+
+    fn call(func_name: &str, args: Vec<IdedExpr>) -> IdedExpr {
+        ided(Expr::Call(CallExpr {
+            func_name: func_name.to_string(),
+            target: None,
+            args,
+        }))
+    }
+
+    fn comprehension(accu_init: IdedExpr, loop_step: IdedExpr) -> IdedExpr {
+        ided(Expr::Comprehension(Box::new(ComprehensionExpr {
+            iter_range: ident("nums"),
+            iter_var: "x".to_string(),
+            iter_var2: None,
+            accu_var: "@result".to_string(),
+            accu_init,
+            loop_cond: ided(Expr::Literal(CelVal::Boolean(true))),
+            loop_step,
+            result: ident("@result"),
+        })))
+    }
+
+    fn render(expr: &IdedExpr) -> String {
+        let options = FormatOptions::default();
+        format_expr(expr, &options).render(options.max_width, "  ")
+    }
+
+    #[test]
+    fn test_debug_comprehension() {
+        // A sum fold: nums.reduce(@result, 0, @result + x)
+        let sum = comprehension(
+            ided(Expr::Literal(CelVal::Int(0))),
+            call("_+_", vec![ident("@result"), ident("x")]),
+        );
+        assert_eq!(
+            render(&sum),
+            "__comprehension__(x, nums, @result, 0, true, @result + x, @result)"
+        );
+
+        // That isn't CEL, so formatting refuses it
+        let options = FormatOptions::default();
+        let mut sum = sum;
+        assert!(matches!(
+            check_writable(&mut sum, &options),
+            Err(FormatError::UnsupportedSyntax { .. })
+        ));
+        let mut all = new_parser(&options).parse("nums.all(x, x > 0)").unwrap();
+        assert!(check_writable(&mut all, &options).is_ok());
+    }
+
     #[test]
     fn test_nested_macros() {
         assert_eq!(
@@ -880,4 +2659,2223 @@ mod tests {
             "[[1, 2], [3, 4]].map(x, x.map(y, y * 2))"
         );
     }
+
+    #[test]
+    fn test_too_deep() {
+        let options = FormatOptions::default();
+        let deep = format!("{}1{}", "(".repeat(5000), ")".repeat(5000));
+        let err = format_cel(&deep, &options).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<FormatError>(),
+            Some(&FormatError::TooDeep {
+                depth: 5000,
+                limit: 256
+            })
+        );
+
+        // Long flat chains are fine; only a very long one is refused
+        let clauses: Vec<String> = (0..300)
+            .map(|i| format!("user.profile.field{} == request.auth.claims.c{}", i, i))
+            .collect();
+        assert!(format_cel(&clauses.join(" && "), &options).is_ok());
+        assert!(format_cel(&clauses.join(" || "), &options).is_ok());
+        let sum = vec!["a"; 300].join(" + ");
+        assert!(format_cel(&sum, &options).is_ok());
+        let products: Vec<String> = (0..300).map(|i| format!("a{} * b.c", i)).collect();
+        assert!(format_cel(&products.join(" + "), &options).is_ok());
+        let sum = vec!["a"; 5000].join(" + ");
+        assert!(matches!(
+            format_cel(&sum, &options)
+                .unwrap_err()
+                .downcast_ref::<FormatError>(),
+            Some(FormatError::TooDeep { .. })
+        ));
+
+        let options = options.with_max_depth(3);
+        assert!(format_cel("f([{1: [2]}])", &options).is_err());
+        assert_eq!(format_cel("f([1], 2)", &options).unwrap(), "f([1], 2)");
+    }
+
+    #[test]
+    fn test_long_operator_chain() {
+        let chain = vec!["a"; 100].join(" + ");
+        let formatted = format_expr_str(&chain);
+        assert!(formatted.starts_with("a\n  + a\n  + a"));
+        assert_eq!(formatted.matches('a').count(), 100);
+    }
+
+    #[test]
+    fn test_key_presence_style() {
+        let preserve = FormatOptions::default();
+        assert_eq!(format_cel("has(m.k)", &preserve).unwrap(), "has(m.k)");
+        assert_eq!(format_cel("'k' in m", &preserve).unwrap(), "\"k\" in m");
+
+        let to_in = FormatOptions::default().with_key_presence_style(KeyPresenceStyle::In);
+        assert_eq!(format_cel("has(m.k)", &to_in).unwrap(), "\"k\" in m");
+        assert_eq!(
+            format_cel("has(a.b.c) && x", &to_in).unwrap(),
+            "\"c\" in a.b && x"
+        );
+        assert_eq!(
+            format_cel("has({'k': 1}.k)", &to_in).unwrap(),
+            "\"k\" in {\"k\": 1}"
+        );
+
+        let to_has = FormatOptions::default().with_key_presence_style(KeyPresenceStyle::Has);
+        assert_eq!(format_cel("'k' in m", &to_has).unwrap(), "has(m.k)");
+        assert_eq!(
+            format_cel("'k' in a['b']", &to_has).unwrap(),
+            "has(a[\"b\"].k)"
+        );
+        // Keys that aren't field names, and operands that can't be maps, stay
+        assert_eq!(format_cel("'a-b' in m", &to_has).unwrap(), "\"a-b\" in m");
+        assert_eq!(format_cel("'in' in m", &to_has).unwrap(), "\"in\" in m");
+        assert_eq!(format_cel("x in m", &to_has).unwrap(), "x in m");
+        assert_eq!(
+            format_cel("'k' in ['k']", &to_has).unwrap(),
+            "\"k\" in [\"k\"]"
+        );
+
+        // The rewritten test is parenthesized like the operator it becomes,
+        // and verifies as the same expression in both directions
+        let to_in = to_in.with_verify(true);
+        let to_has = to_has.with_verify(true);
+        let cases = [
+            ("!has(m.k)", "!(\"k\" in m)"),
+            ("x == has(m.k)", "x == (\"k\" in m)"),
+            ("-has(m.k)", "-(\"k\" in m)"),
+            ("has(m.k).f()", "(\"k\" in m).f()"),
+        ];
+        for (has, in_form) in cases {
+            assert_eq!(format_cel(has, &to_in).unwrap(), in_form);
+            assert_eq!(format_cel(in_form, &to_has).unwrap(), has);
+        }
+    }
+
+    #[test]
+    fn test_parse_error_position() {
+        let options = FormatOptions::default();
+        let err = format_cel("\n\n  x ==\n  )", &options).unwrap_err();
+        match err.downcast_ref::<FormatError>() {
+            Some(FormatError::Parse { line, column, .. }) => assert_eq!((*line, *column), (4, 3)),
+            other => panic!("expected a parse error, got {:?}", other),
+        }
+
+        let err = format_cel("  a + )", &options).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("Parse error at line 1, column 7: "));
+    }
+
+    #[test]
+    fn test_chained_comparisons() {
+        assert_eq!(format_expr_str("(a < b) == c"), "(a < b) == c");
+        assert_eq!(format_expr_str("(a == b) == c"), "(a == b) == c");
+        assert_eq!(format_expr_str("a == (b < c)"), "a == (b < c)");
+        assert_eq!(format_expr_str("a < b < c"), "(a < b) < c");
+        assert_eq!(format_expr_str("(x in list) != y"), "(x in list) != y");
+        assert_eq!(format_expr_str("a < b && b < c"), "a < b && b < c");
+    }
+
+    #[test]
+    fn test_member_access_on_operators() {
+        assert_eq!(format_expr_str("(a + b).size()"), "(a + b).size()");
+        assert_eq!(format_expr_str("(a ? b : c).x"), "(a ? b : c).x");
+        assert_eq!(format_expr_str("(!a).b"), "(!a).b");
+        assert_eq!(format_expr_str("(a || b)[0]"), "(a || b)[0]");
+        assert_eq!(format_expr_str("has((a + b).c)"), "has((a + b).c)");
+        assert_eq!(format_expr_str("f(x).bar"), "f(x).bar");
+        assert_eq!(
+            format_expr_str("(a + b).map(x, x * 2)"),
+            "(a + b).map(x, x * 2)"
+        );
+        assert_eq!(format_expr_str("a.b[0].c()"), "a.b[0].c()");
+    }
+
+    #[test]
+    fn test_method_call_target_parens() {
+        let cases = [
+            ("(a + b).size()", "(a + b).size()"),
+            ("(cond ? x : y).method(z)", "(cond ? x : y).method(z)"),
+            ("(-a).abs()", "(-a).abs()"),
+            ("(a in b).string()", "(a in b).string()"),
+            ("(a || b).f().g()", "(a || b).f().g()"),
+        ];
+        for (source, expected) in cases {
+            assert_eq!(format_expr_str(source), expected);
+        }
+
+        let options = FormatOptions::default()
+            .with_max_width(20)
+            .with_break_member_chains(true);
+        assert_eq!(
+            format_cel("(alpha + beta).filter(x, x > 0).map(x, x * 2)", &options).unwrap(),
+            "(alpha + beta)\n  .filter(x, x > 0)\n  .map(x, x * 2)"
+        );
+    }
+
+    fn pair(index: IdedExpr, last: IdedExpr) -> IdedExpr {
+        let field = |name: &str, value| IdedEntryExpr {
+            id: 0,
+            expr: EntryExpr::StructField(StructFieldExpr {
+                field: name.to_string(),
+                value,
+                optional: false,
+            }),
+        };
+        ided(Expr::Struct(StructExpr {
+            type_name: "Pair".to_string(),
+            entries: vec![field("index", index), field("last", last)],
+        }))
+    }
+
+    #[test]
+    fn test_struct_accumulator_comprehension() {
+        // Enumerate-style fold tracking the index alongside the last value
+        let zero = || ided(Expr::Literal(CelVal::Int(0)));
+        let select = ided(Expr::Select(SelectExpr {
+            operand: Box::new(ident("@result")),
+            field: "index".to_string(),
+            test: false,
+        }));
+        let next_index = call("_+_", vec![select, ided(Expr::Literal(CelVal::Int(1)))]);
+        let enumerate = comprehension(pair(zero(), zero()), pair(next_index, ident("x")));
+        assert_eq!(
+            render(&enumerate),
+            "__comprehension__(\n  x,\n  nums,\n  @result,\n  Pair{index: 0, last: 0},\n  true,\n  Pair{index: @result.index + 1, last: x},\n  @result\n)"
+        );
+    }
+
+    #[test]
+    fn test_max_inline_args() {
+        let options = FormatOptions::default().with_max_inline_args(Some(3));
+        assert_eq!(
+            format_cel("myPolicy(a, b, c, d, e, f)", &options).unwrap(),
+            "myPolicy(\n  a,\n  b,\n  c,\n  d,\n  e,\n  f\n)"
+        );
+        assert_eq!(
+            format_cel("x.check(a, b, c, d) && y", &options).unwrap(),
+            "x.check(\n  a,\n  b,\n  c,\n  d\n) && y"
+        );
+        assert_eq!(
+            format_cel("[f(a, b, c, d), 1]", &options).unwrap(),
+            "[f(\n  a,\n  b,\n  c,\n  d\n), 1]"
+        );
+        assert_eq!(format_cel("f(a, b, c)", &options).unwrap(), "f(a, b, c)");
+        assert_eq!(
+            format_expr_str("myPolicy(a, b, c, d, e, f)"),
+            "myPolicy(a, b, c, d, e, f)"
+        );
+        // The line the arguments close on still has to fit
+        let options = options.with_max_width(9);
+        assert_eq!(
+            format_cel("x.check(a, b, c, d) && yyyyy", &options).unwrap(),
+            "x.check(\n  a,\n  b,\n  c,\n  d\n)\n  && yyyyy"
+        );
+    }
+
+    #[test]
+    fn test_nested_ternary_parens() {
+        assert_eq!(
+            format_expr_str("(a ? b : c) ? d : e"),
+            "(a ? b : c) ? d : e"
+        );
+        assert_eq!(
+            format_expr_str("a ? (b ? c : d) : e"),
+            "a ? (b ? c : d) : e"
+        );
+        assert_eq!(format_expr_str("a ? b : c ? d : e"), "a ? b : c ? d : e");
+        assert_eq!(format_expr_str("a || b ? c : d"), "a || b ? c : d");
+    }
+
+    #[test]
+    fn test_verify() {
+        let options = FormatOptions::default().with_verify(true);
+        assert_eq!(
+            format_cel("[1,2].map(x, x*2) == y ? {'a': 1} : null", &options).unwrap(),
+            "[1, 2].map(x, x * 2) == y ? {\"a\": 1} : null"
+        );
+
+        assert_eq!(format_cel("a - (b - c)", &options).unwrap(), "a - (b - c)");
+
+        // A custom formatter that writes something else is caught
+        let options = options.with_custom_formatter("f", |_| Some(Doc::text("g()")));
+        let err = format_cel("f(x)", &options).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<FormatError>(),
+            Some(&FormatError::ChangedMeaning)
+        );
+    }
+
+    #[test]
+    fn test_index_arities() {
+        assert_eq!(format_expr_str("list[-1]"), "list[-1]");
+        assert_eq!(format_expr_str("list[-i]"), "list[-i]");
+        assert_eq!(format_expr_str("slice(list, 1, -1)"), "slice(list, 1, -1)");
+
+        let index = |args| render(&call("_[_]", args));
+        let one = || ided(Expr::Literal(CelVal::Int(1)));
+        assert_eq!(index(vec![ident("list"), one(), one()]), "list[1, 1]");
+        assert_eq!(index(vec![ident("list")]), "list[]");
+        assert_eq!(index(vec![]), "_[_]()");
+
+        // Without exactly one index there is no CEL to write
+        let options = FormatOptions::default();
+        for args in [
+            vec![ident("list"), one(), one()],
+            vec![ident("list")],
+            vec![],
+        ] {
+            assert_eq!(
+                check_writable(&mut call("_[_]", args), &options),
+                Err(FormatError::UnsupportedSyntax {
+                    syntax: "an index operator without exactly one index"
+                })
+            );
+        }
+        let error = check_writable(&mut call("_[_]", vec![]), &options).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Can't write an index operator without exactly one index"
+        );
+        let mut indexed = call("_[_]", vec![ident("list"), one()]);
+        assert!(check_writable(&mut indexed, &options).is_ok());
+    }
+
+    #[test]
+    fn test_line_ending() {
+        let source = "{\r\n  aaaaaaaaaa: 1,\r\n  bbbbbbbbbb: 2,\r\n}";
+        let options = FormatOptions::default().with_max_width(20);
+        let lf = "{\n  aaaaaaaaaa: 1,\n  bbbbbbbbbb: 2,\n}";
+        let crlf = "{\r\n  aaaaaaaaaa: 1,\r\n  bbbbbbbbbb: 2,\r\n}";
+
+        assert_eq!(format_cel(source, &options).unwrap(), lf);
+        let options = options.with_line_ending(LineEnding::Crlf);
+        assert_eq!(format_cel(source, &options).unwrap(), crlf);
+        let options = options.with_line_ending(LineEnding::Auto);
+        assert_eq!(format_cel(source, &options).unwrap(), crlf);
+        assert_eq!(format_cel(lf, &options).unwrap(), lf);
+    }
+
+    #[test]
+    fn test_unexpanded_macros() {
+        let method = |target: &str, name: &str, args: Vec<IdedExpr>| {
+            ided(Expr::Call(CallExpr {
+                func_name: name.to_string(),
+                target: Some(Box::new(ident(target))),
+                args,
+            }))
+        };
+        let select = ided(Expr::Select(SelectExpr {
+            operand: Box::new(ident("a")),
+            field: "b".to_string(),
+            test: false,
+        }));
+        let gt = call("_>_", vec![ident("x"), ident("y")]);
+
+        let cases = [
+            (call("has", vec![select]), "has(a.b)"),
+            (
+                method("xs", "all", vec![ident("x"), gt.clone()]),
+                "xs.all(x, x > y)",
+            ),
+            (
+                method("xs", "map", vec![ident("x"), gt.clone(), ident("x")]),
+                "xs.map(x, x > y, x)",
+            ),
+            (method("xs", "size", vec![]), "xs.size()"),
+        ];
+        for (expr, expected) in cases {
+            assert_eq!(render(&expr), expected);
+        }
+
+        // Expanded and unexpanded forms break the same way
+        let source = "items.exists(item, item.name == 'a-very-long-name-that-goes-past-the-limit')";
+        let options = FormatOptions::default().with_max_width(40);
+        let expanded = to_doc(source, &options).unwrap();
+        let unexpanded = format_expr(
+            &method(
+                "items",
+                "exists",
+                vec![
+                    ident("item"),
+                    call(
+                        "_==_",
+                        vec![
+                            ided(Expr::Select(SelectExpr {
+                                operand: Box::new(ident("item")),
+                                field: "name".to_string(),
+                                test: false,
+                            })),
+                            ided(Expr::Literal(CelVal::String(
+                                "a-very-long-name-that-goes-past-the-limit".to_string(),
+                            ))),
+                        ],
+                    ),
+                ],
+            ),
+            &options,
+        );
+        assert_eq!(unexpanded.render(40, "  "), expanded.render(40, "  "));
+    }
+
+    #[test]
+    fn test_string_escapes_round_trip() {
+        let strings = [
+            "",
+            "plain",
+            "quote \" and 'apostrophe'",
+            "back\\slash \\n not a newline",
+            "line\nbreak\r\ttab",
+            "bell \x07 backspace \x08 form feed \x0c vertical tab \x0b",
+            "nul \0 escape \x1b delete \x7f",
+            "next line \u{85} separators \u{2028}\u{2029}",
+            "unicode é 日本 🎉",
+            "\\\"\\'",
+        ];
+        for value in strings {
+            let source = format!("\"{}\"", escape_string(value, &FormatOptions::default()));
+            let formatted = format_expr_str(&source);
+            let reparsed = Parser::new().parse(&formatted).unwrap();
+            assert_eq!(
+                reparsed.expr,
+                Expr::Literal(CelVal::String(value.to_string())),
+                "round-tripping {:?} through {}",
+                value,
+                formatted
+            );
+            assert_eq!(format_expr_str(&formatted), formatted);
+        }
+
+        // Escapes in the source come out in one canonical spelling
+        assert_eq!(
+            format_expr_str(r#"'\a\b\f\v\x41\u00e9\101\?\`\x1b'"#),
+            r#""\a\b\f\vAéA?`\u001b""#
+        );
+        assert_eq!(
+            format_expr_str("'next line \u{85} separators \u{2028}\u{2029}'"),
+            r#""next line \u0085 separators \u2028\u2029""#
+        );
+    }
+
+    #[test]
+    fn test_open_bracket_comments() {
+        let cases = [
+            (
+                "[ // allowed values\n 1, 2 ]",
+                "[ // allowed values\n  1,\n  2,\n]",
+            ),
+            ("{ // limits\n'cpu': 1}", "{ // limits\n  \"cpu\": 1\n}"),
+            ("Msg{ // empty\n}", "Msg{ // empty\n}"),
+            (
+                "f(a[0], [ // one\n b])",
+                "f(\n  a[0],\n  [ // one\n    b\n  ]\n)",
+            ),
+            ("[1, 2] // after", "[1, 2]"),
+        ];
+        let options = FormatOptions::default();
+        for (source, expected) in cases {
+            let formatted = format_cel(source, &options).unwrap();
+            assert_eq!(formatted, expected, "formatting {}", source);
+            assert_eq!(format_cel(&formatted, &options).unwrap(), formatted);
+        }
+    }
+
+    #[test]
+    fn test_max_recursion_depth() {
+        let source = "[[[1]]]";
+        let options = FormatOptions::default();
+        assert_eq!(format_cel(source, &options).unwrap(), source);
+
+        let options = options.with_max_recursion_depth(Some(2));
+        let err = format_cel(source, &options).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<FormatError>(),
+            Some(FormatError::Parse { .. })
+        ));
+    }
+
+    #[test]
+    fn test_single_atom_argument() {
+        let options = FormatOptions::default().with_max_width(10);
+        let cases = [
+            ("f(oneVeryLongArg)", "f(oneVeryLongArg)"),
+            (
+                "obj.method(a.very.long.path)",
+                "obj.method(a.very.long.path)",
+            ),
+            ("size('a long string')", "size(\"a long string\")"),
+            ("f(aaaaa, bbbbb)", "f(\n  aaaaa,\n  bbbbb\n)"),
+            ("f(aaaaa + bbbbb)", "f(\n  aaaaa\n    + bbbbb\n)"),
+        ];
+        for (source, expected) in cases {
+            assert_eq!(format_cel(source, &options).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_no_constant_folding() {
+        for source in [
+            "1 + 2",
+            "true && false",
+            "2 * 3 - 1",
+            "\"a\" + \"b\"",
+            "!true",
+            "[1, 2][0]",
+            "size(\"abc\")",
+            "true ? 1 : 2",
+        ] {
+            assert_eq!(format_expr_str(source), source);
+        }
+    }
+
+    #[test]
+    fn test_normalize_comparisons() {
+        let source = "5 < x && 0 >= f(y) && a <= 1 && 1 > 2 && 3 == z";
+        let options = FormatOptions::default();
+        assert_eq!(format_cel(source, &options).unwrap(), source);
+
+        let options = options.with_normalize_comparisons(true).with_verify(true);
+        let expected = "x > 5 && f(y) <= 0 && a <= 1 && 1 > 2 && 3 == z";
+        assert_eq!(format_cel(source, &options).unwrap(), expected);
+        assert_eq!(format_cel(expected, &options).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_format_cel_lenient() {
+        let options = FormatOptions::default();
+        let cases = [
+            ("a+b", "a + b"),
+            ("a + ", "a + "),
+            ("a+b+", "a + b+"),
+            ("x>5&&y<", "x > 5 && y<"),
+            ("[1,2,3].map(x, x*2", "[1, 2, 3].map(x, x*2"),
+            ("foo(bar", "foo(bar"),
+            (")", ")"),
+            ("", ""),
+        ];
+        for (source, expected) in cases {
+            assert_eq!(format_cel_lenient(source, &options), expected);
+        }
+    }
+
+    #[test]
+    fn test_repeated_unary_operators() {
+        let options = FormatOptions::default().with_verify(true);
+        for source in [
+            "a - -b", "-(-b)", "-(-1)", "-(-1.5)", "!(!x)", "a - -1", "-(!x)",
+        ] {
+            assert_eq!(format_cel(source, &options).unwrap(), source);
+        }
+    }
+
+    #[test]
+    fn test_reflow() {
+        // Line breaks between tokens are reflowed away when the result fits
+        assert_eq!(format_expr_str("a &&\n  b\n  || c"), "a && b || c");
+        assert_eq!(
+            format_expr_str("[\n  1,\n  2\n].map(\n  x,\n  x * 2\n)"),
+            "[1, 2].map(x, x * 2)"
+        );
+
+        // Line breaks inside strings are part of the value
+        let source = "\"\"\"first\nsecond\"\"\" + '\\n'";
+        let formatted = format_expr_str(source);
+        assert_eq!(formatted, "\"first\\nsecond\" + \"\\n\"");
+        assert!(same_expr(
+            &Parser::new().parse(&formatted).unwrap(),
+            &Parser::new().parse(source).unwrap(),
+            &FormatOptions::default()
+        ));
+    }
+
+    #[test]
+    fn test_max_width_zero() {
+        let source = "items.all(item, item.enabled && item.name.startsWith('a-very-long-prefix')) && [aaaaaaaaaa, bbbbbbbbbb, f(cccccccccc)].size() > 2";
+        let options = FormatOptions::default().with_max_width(0);
+        assert_eq!(
+            format_cel(source, &options).unwrap(),
+            source.replace('\'', "\"")
+        );
+    }
+
+    #[test]
+    fn test_trailing_comma_only_on_broken_groups() {
+        // The inner lists fit, so their trailing commas stay out even though
+        // the outer list breaks
+        let source = "[[aaaa, f(b)], [cccc, f(d)], [eeee, f(g)]]";
+        let options = FormatOptions::default().with_max_width(20);
+        assert_eq!(
+            format_cel(source, &options).unwrap(),
+            "[\n  [aaaa, f(b)],\n  [cccc, f(d)],\n  [eeee, f(g)],\n]"
+        );
+
+        let options = options.with_trailing_comma(false);
+        assert_eq!(
+            format_cel(source, &options).unwrap(),
+            "[\n  [aaaa, f(b)],\n  [cccc, f(d)],\n  [eeee, f(g)]\n]"
+        );
+    }
+
+    #[test]
+    fn test_closing_bracket_style() {
+        let source = "[first_element, second_element, f(third_element)]";
+        let options = FormatOptions::default().with_max_width(20);
+        assert_eq!(
+            format_cel(source, &options).unwrap(),
+            "[\n  first_element,\n  second_element,\n  f(third_element),\n]"
+        );
+
+        let options = options.with_closing_bracket_style(ClosingBracketStyle::Hugging);
+        assert_eq!(
+            format_cel(source, &options).unwrap(),
+            "[\n  first_element,\n  second_element,\n  f(third_element)]"
+        );
+        assert_eq!(format_cel("{a: 1}", &options).unwrap(), "{a: 1}");
+    }
+
+    #[test]
+    fn test_simplify() {
+        let options = FormatOptions::default();
+        assert!(!options.simplify);
+        assert_eq!(format_cel("!(!x)", &options).unwrap(), "!(!x)");
+        assert_eq!(format_cel("!(a == b)", &options).unwrap(), "!(a == b)");
+
+        let options = options.with_simplify(true);
+        let cases = [
+            ("!(!(a < b))", "a < b"),
+            ("!(!has(a.b))", "has(a.b)"),
+            ("-(-(5))", "5"),
+            ("-(-(2.5))", "2.5"),
+            ("!(a == b)", "a != b"),
+            ("!(a != b)", "a == b"),
+            ("!(!(!(a == b))) && c", "a != b && c"),
+            ("!(!(a || b)) && c", "(a || b) && c"),
+            // `!(!"s")` is an error, not `"s"`
+            ("!(!x)", "!(!x)"),
+            ("!(!\"s\")", "!(!\"s\")"),
+            // `-(-x)` is an error when `x` is a uint or the smallest int
+            ("-(-x)", "-(-x)"),
+            ("-(-(5u))", "-(-5u)"),
+        ];
+        for (source, expected) in cases {
+            let formatted = format_cel(source, &options).unwrap();
+            assert_eq!(formatted, expected, "simplifying {}", source);
+            assert_eq!(format_cel(&formatted, &options).unwrap(), formatted);
+        }
+        assert!(format_cel("!(a == b)", &options.with_verify(true)).is_ok());
+    }
+
+    #[test]
+    fn test_bool_case() {
+        let source = "a == true || b != false";
+        let options = FormatOptions::default();
+        assert_eq!(format_cel(source, &options).unwrap(), source);
+
+        let options = options.with_bool_case(BoolCase::Upper);
+        assert_eq!(
+            format_cel(source, &options).unwrap(),
+            "a == True || b != False"
+        );
+    }
+
+    #[test]
+    fn test_optional_select() {
+        let opt_select = |operand, field: &str| {
+            call(
+                "_?._",
+                vec![
+                    operand,
+                    ided(Expr::Literal(CelVal::String(field.to_string()))),
+                ],
+            )
+        };
+        let chain = opt_select(opt_select(ident("m"), "a"), "b");
+        let or_value = ided(Expr::Call(CallExpr {
+            func_name: "orValue".to_string(),
+            target: Some(Box::new(chain)),
+            args: vec![ident("def")],
+        }));
+        assert_eq!(render(&or_value), "m.?a.?b.orValue(def)");
+
+        let sum = call("_+_", vec![ident("a"), ident("b")]);
+        assert_eq!(render(&opt_select(sum, "c")), "(a + b).?c");
+    }
+
+    #[test]
+    fn test_custom_formatter() {
+        let options = FormatOptions::default()
+            .with_custom_formatter("inRange", |call| {
+                let target = call.target.as_ref()?;
+                let Expr::Ident(name) = &target.expr else {
+                    return None;
+                };
+                Some(Doc::text(format!("{} within range", name)))
+            })
+            .with_custom_formatter("_+_", |_| None);
+
+        assert_eq!(
+            format_cel("ip.inRange('10.0.0.0/8')", &options).unwrap(),
+            "ip within range"
+        );
+        assert_eq!(
+            format_cel("f(x).inRange(y)", &options).unwrap(),
+            "f(x).inRange(y)"
+        );
+        assert_eq!(format_cel("1+2", &options).unwrap(), "1 + 2");
+        assert_eq!(
+            format!("{:?}", options.custom_formatters),
+            "{\"_+_\", \"inRange\"}"
+        );
+    }
+
+    #[test]
+    fn test_nested_calls_at_width_30() {
+        let options = FormatOptions::default().with_max_width(30);
+        assert_eq!(
+            format_cel("outer(inner1(a, b), inner2(c, d), inner3(e, f))", &options).unwrap(),
+            "outer(\n  inner1(a, b),\n  inner2(c, d),\n  inner3(e, f)\n)"
+        );
+
+        // A group that starts a line is measured from that line's start
+        let options = options.with_ternary_operator_break(OperatorBreak::After);
+        assert_eq!(
+            format_cel(
+                "some_long_condition_value_here ? [aaaa, f(b)] : c",
+                &options
+            )
+            .unwrap(),
+            "some_long_condition_value_here ?\n[aaaa, f(b)] :\nc"
+        );
+    }
+
+    #[test]
+    fn test_format_cel_to() {
+        let source = "{\r\n  aaaaaaaaaa: 1,\r\n  bbbbbbbbbb: [x, y].map(v, v * 2),\r\n}";
+        for options in [
+            FormatOptions::default(),
+            FormatOptions::default()
+                .with_max_width(20)
+                .with_line_ending(LineEnding::Auto),
+        ] {
+            let mut out = Vec::new();
+            format_cel_to(source, &options, &mut out).unwrap();
+            assert_eq!(
+                String::from_utf8(out).unwrap(),
+                format_cel(source, &options).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn test_method_chain() {
+        let source = "users.filter(u, u.active).map(u, u.name).size()";
+        let options = FormatOptions::default().with_max_width(40);
+        // Chains only break with `break_member_chains`
+        assert_eq!(format_cel(source, &options).unwrap(), source);
+
+        let options = options.with_break_member_chains(true);
+        assert_eq!(
+            format_cel(source, &options).unwrap(),
+            "users\n  .filter(u, u.active)\n  .map(u, u.name)\n  .size()"
+        );
+
+        let options = options.with_chain_first_call_inline(true);
+        assert_eq!(
+            format_cel(source, &options).unwrap(),
+            "users.filter(u, u.active)\n  .map(u, u.name)\n  .size()"
+        );
+
+        // A chain that fits stays on one line
+        assert_eq!(format_expr_str(source), source);
+    }
+
+    #[test]
+    fn test_input_without_code() {
+        let options = FormatOptions::default().with_verify(true);
+        assert_eq!(format_cel("", &options).unwrap(), "");
+        assert_eq!(format_cel("   \n", &options).unwrap(), "");
+        assert_eq!(
+            format_cel("// just a comment", &options).unwrap(),
+            "// just a comment"
+        );
+        assert_eq!(
+            format_cel("\n  // one\n\n// two  \n", &options).unwrap(),
+            "// one\n// two"
+        );
+    }
+
+    #[test]
+    fn test_trailing_comma_element_count() {
+        let options = FormatOptions::default().with_max_width(20);
+        assert_eq!(
+            format_cel("[f(some_long_element)]", &options).unwrap(),
+            "[\n  f(some_long_element)\n]"
+        );
+        assert_eq!(
+            format_cel("[f(first), f(second_one)]", &options).unwrap(),
+            "[\n  f(first),\n  f(second_one),\n]"
+        );
+        assert_eq!(
+            format_cel("{\"some_long_key\": value}", &options).unwrap(),
+            "{\n  \"some_long_key\": value\n}"
+        );
+    }
+
+    #[test]
+    fn test_bytes_literal_style() {
+        let source = r#"b"\x00\x01\x02\x03\xfc\xfd\xfe\xff""#;
+        let options = FormatOptions::default()
+            .with_bytes_literal_style(BytesLiteralStyle::Base64)
+            .with_verify(true);
+        let formatted = format_cel(source, &options).unwrap();
+        assert_eq!(formatted, r#"base64.decode("AAECA/z9/v8=")"#);
+
+        // The base64 form parses as a call of the encoders extension
+        let reparsed = Parser::new().parse(&formatted).unwrap();
+        let Expr::Call(call) = &reparsed.expr else {
+            panic!("expected a call, got {:?}", reparsed.expr);
+        };
+        assert_eq!(call.func_name, "decode");
+        assert_eq!(
+            call.args[0].expr,
+            Expr::Literal(CelVal::String("AAECA/z9/v8=".to_string()))
+        );
+
+        // Short or printable bytes stay escaped
+        assert_eq!(format_cel(r#"b"\x00""#, &options).unwrap(), r#"b"\x00""#);
+        assert_eq!(
+            format_cel(r#"b"hello world""#, &options).unwrap(),
+            r#"b"hello world""#
+        );
+        assert_eq!(
+            format_expr_str(source),
+            r#"b"\x00\x01\x02\x03\xfc\xfd\xfe\xff""#
+        );
+
+        // Other styles don't write the call, so it isn't the same expression
+        assert!(same_expr(
+            &Parser::new().parse(source).unwrap(),
+            &reparsed,
+            &options
+        ));
+        let options = FormatOptions::default();
+        assert!(!same_expr(
+            &Parser::new().parse(source).unwrap(),
+            &reparsed,
+            &options
+        ));
+    }
+
+    #[test]
+    fn test_complexity_warning() {
+        let source = "f(g(h([x + 1])))";
+        let options = FormatOptions::default();
+        let (output, warnings) = format_cel_with_warnings(source, &options).unwrap();
+        assert_eq!(output, source);
+        assert!(warnings.is_empty());
+
+        let options = options.with_complexity_warn_depth(Some(4));
+        let (output, warnings) = format_cel_with_warnings(source, &options).unwrap();
+        assert_eq!(output, source);
+        assert_eq!(
+            warnings,
+            [FormatWarning::TooComplex {
+                line: 1,
+                depth: 6,
+                limit: 4,
+            }]
+        );
+
+        let options = options.with_complexity_warn_depth(Some(6));
+        assert!(format_cel_with_warnings(source, &options)
+            .unwrap()
+            .1
+            .is_empty());
+    }
+
+    #[test]
+    fn test_escape_forward_slash() {
+        let source = r#""</script>""#;
+        assert_eq!(format_expr_str(source), source);
+
+        let options = FormatOptions::default()
+            .with_escape_forward_slash(true)
+            .with_verify(true);
+        let formatted = format_cel(source, &options).unwrap();
+        assert_eq!(formatted, r#""<\x2fscript>""#);
+        assert_eq!(
+            Parser::new().parse(&formatted).unwrap().expr,
+            Expr::Literal(CelVal::String("</script>".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_ternary_collection_branches() {
+        let source = "cond ? [f(alpha), f(beta), f(gamma), f(delta)] : [g(epsilon), g(zeta)]";
+        let options = FormatOptions::default().with_max_width(40);
+        assert_eq!(
+            format_cel(source, &options).unwrap(),
+            "cond\n? [\n    f(alpha),\n    f(beta),\n    f(gamma),\n    f(delta),\n  ]\n: [g(epsilon), g(zeta)]"
+        );
+
+        let source = r#"ok ? {"name": user.name, "email": user.email} : {}"#;
+        assert_eq!(
+            format_cel(source, &options).unwrap(),
+            "ok\n? {\n    \"name\": user.name,\n    \"email\": user.email,\n  }\n: {}"
+        );
+    }
+
+    #[test]
+    fn test_indent_string() {
+        let options = FormatOptions::default()
+            .with_max_width(20)
+            .with_indent_string("| ");
+        assert_eq!(
+            format_cel("[f(first), f(second), [f(third), f(fourth)]]", &options).unwrap(),
+            "[\n| f(first),\n| f(second),\n| [\n| | f(third),\n| | f(fourth),\n| ],\n]"
+        );
+
+        let options = options.with_indent_string("\n");
+        assert_eq!(
+            format_cel("x", &options)
+                .unwrap_err()
+                .downcast::<FormatError>()
+                .unwrap(),
+            FormatError::InvalidIndentString
+        );
+    }
+
+    #[test]
+    fn test_unary_operands() {
+        let options = FormatOptions::default().with_verify(true);
+        let cases = [
+            // Member expressions bind tighter and stay bare
+            ("!f(x)", "!f(x)"),
+            ("-a.b", "-a.b"),
+            ("!a[0]", "!a[0]"),
+            ("-x.size()", "-x.size()"),
+            ("!has(a.b)", "!has(a.b)"),
+            ("-[1][0]", "-[1][0]"),
+            // Operators and ternaries need parens
+            ("-(a + b)", "-(a + b)"),
+            ("!(a == b)", "!(a == b)"),
+            ("!(a && b)", "!(a && b)"),
+            ("!(x in y)", "!(x in y)"),
+            ("-(x ? 1 : 2)", "-(x ? 1 : 2)"),
+            ("-a + b", "-a + b"),
+            ("!a == b", "!a == b"),
+            // Nested prefix operators and negated numeric literals
+            ("!(!x)", "!(!x)"),
+            ("-(-x)", "-(-x)"),
+            ("-(1)", "-(1)"),
+            ("-(1.5)", "-(1.5)"),
+            ("-(-1)", "-(-1)"),
+            ("-1u", "-1u"),
+            ("-1", "-1"),
+        ];
+        for (source, expected) in cases {
+            let formatted = format_cel(source, &options).unwrap();
+            assert_eq!(formatted, expected, "formatting {}", source);
+        }
+    }
+
+    #[test]
+    fn test_strip_trailing_semicolon() {
+        let options = FormatOptions::default();
+        assert!(format_cel("a + b;", &options).is_err());
+
+        let options = options
+            .with_strip_trailing_semicolon(true)
+            .with_verify(true);
+        assert_eq!(format_cel("a+b;", &options).unwrap(), "a + b");
+        assert_eq!(format_cel("a + b ;\n", &options).unwrap(), "a + b");
+        assert_eq!(format_cel("a + b", &options).unwrap(), "a + b");
+        for source in ["a + b;;", "a; b", "a +;", ";"] {
+            assert!(format_cel(source, &options).is_err(), "{}", source);
+        }
+    }
+
+    #[test]
+    fn test_macro_loop_conditions() {
+        let result = || ident("@result");
+        let gt = call("_>_", vec![ident("x"), ided(Expr::Literal(CelVal::Int(0)))]);
+        let not_strictly_false = |cond: IdedExpr| call("@not_strictly_false", vec![cond]);
+        let with_cond = |accu_init: bool, op: &str, loop_cond: IdedExpr| {
+            let mut comp = comprehension(
+                ided(Expr::Literal(CelVal::Boolean(accu_init))),
+                call(op, vec![result(), gt.clone()]),
+            );
+            if let Expr::Comprehension(c) = &mut comp.expr {
+                c.loop_cond = loop_cond;
+            }
+            comp
+        };
+
+        let cases = [
+            (
+                with_cond(true, "_&&_", not_strictly_false(result())),
+                "nums.all(x, x > 0)",
+            ),
+            (with_cond(true, "_&&_", result()), "nums.all(x, x > 0)"),
+            (
+                with_cond(
+                    false,
+                    "_||_",
+                    not_strictly_false(call("!_", vec![result()])),
+                ),
+                "nums.exists(x, x > 0)",
+            ),
+            // The right step with a loop condition that doesn't short-circuit
+            // as the macro would isn't that macro
+            (
+                with_cond(true, "_&&_", call("!_", vec![result()])),
+                "__comprehension__(x, nums, @result, true, !@result, @result && x > 0, @result)",
+            ),
+            (
+                with_cond(false, "_||_", ided(Expr::Literal(CelVal::Boolean(true)))),
+                "__comprehension__(x, nums, @result, false, true, @result || x > 0, @result)",
+            ),
+        ];
+        for (expr, expected) in cases {
+            assert_eq!(render(&expr), expected);
+        }
+    }
+
+    #[test]
+    fn test_break_map_threshold() {
+        let small = r#"{"a": 1, "b": 2}"#;
+        let large = r#"{"a": 1, "b": 2, "c": 3, "d": 4, "e": 5, "f": 6}"#;
+        let options = FormatOptions::default();
+        assert_eq!(format_cel(large, &options).unwrap(), large);
+
+        let options = options.with_break_map_threshold(Some(4));
+        assert_eq!(format_cel(small, &options).unwrap(), small);
+        assert_eq!(
+            format_cel(large, &options).unwrap(),
+            "{\n  \"a\": 1,\n  \"b\": 2,\n  \"c\": 3,\n  \"d\": 4,\n  \"e\": 5,\n  \"f\": 6,\n}"
+        );
+        assert_eq!(
+            format_cel("Msg{a: 1, b: 2, c: 3, d: 4, e: 5}", &options).unwrap(),
+            "Msg{\n  a: 1,\n  b: 2,\n  c: 3,\n  d: 4,\n  e: 5,\n}"
+        );
+    }
+
+    #[test]
+    fn test_not_in() {
+        let options = FormatOptions::default().with_verify(true);
+        let cases = [
+            ("!(x in [1,2,3])", "!(x in [1, 2, 3])"),
+            ("!(x in y) && z", "!(x in y) && z"),
+            ("a == !(x in y)", "a == !(x in y)"),
+        ];
+        for (source, expected) in cases {
+            assert_eq!(format_cel(source, &options).unwrap(), expected);
+        }
+
+        let options = FormatOptions::default().with_not_in_operator(true);
+        let cases = [
+            ("!(x in [1,2,3])", "x not in [1, 2, 3]"),
+            ("!(x in y) && z", "x not in y && z"),
+            ("a == !(x in y)", "a == (x not in y)"),
+            ("(!(x in y)).size()", "(x not in y).size()"),
+        ];
+        for (source, expected) in cases {
+            assert_eq!(format_cel(source, &options).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_flat_fast_path() {
+        let sources = [
+            "x",
+            "a && b || !c",
+            "size(items) > 0",
+            "request.auth.claims.email.endsWith('@example.com')",
+            "{'a': 1, 'b': [1, 2, 3]}",
+            "items.all(i, i.price < 100 && i.quantity > 0 && i.name != '' && i.active)",
+            "cond ? [x] : []",
+        ];
+        for max_width in [0, 20, 80] {
+            let options = FormatOptions::default().with_max_width(max_width);
+            for source in sources {
+                let slow = to_doc(source, &options).unwrap().render(max_width, "  ");
+                assert_eq!(format_cel(source, &options).unwrap(), slow);
+            }
+        }
+    }
+
+    #[test]
+    fn test_operator_aliases() {
+        let options = FormatOptions::default()
+            .with_operator_alias("_&&_", "and")
+            .with_operator_alias("_||_", "or")
+            .with_verify(true);
+        let cases = [
+            ("a && b", "a and b"),
+            ("a && (b || c)", "a and (b or c)"),
+            ("x == 'a && b' || y", "x == \"a && b\" or y"),
+            ("a + b", "a + b"),
+        ];
+        for (source, expected) in cases {
+            assert_eq!(format_cel(source, &options).unwrap(), expected);
+        }
+
+        // Aliases that aren't words, or are keywords, are ignored
+        let options = FormatOptions::default()
+            .with_operator_alias("_&&_", "&")
+            .with_operator_alias("_||_", "in");
+        assert_eq!(format_cel("a && b || c", &options).unwrap(), "a && b || c");
+
+        // An operator whose alias is also a name in the expression keeps its
+        // symbol, so the output maps back to the same expression
+        let options = FormatOptions::default()
+            .with_operator_alias("_&&_", "and")
+            .with_operator_alias("_||_", "or")
+            .with_verify(true);
+        let cases = [
+            ("and && b || c", "and && b or c"),
+            ("a.or || b && c", "a.or || b and c"),
+            ("a && b || or(c)", "a and b || or(c)"),
+            ("xs.all(and, and) || c", "xs.all(and, and) or c"),
+            ("Msg{or: a || b}", "Msg{or: a || b}"),
+        ];
+        for (source, expected) in cases {
+            assert_eq!(format_cel(source, &options).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_has_complex_operand() {
+        let options = FormatOptions::default().with_verify(true);
+        let cases = [
+            ("has((a ? b : c).field)", "has((a ? b : c).field)"),
+            ("has(f(x).field)", "has(f(x).field)"),
+            ("has(x.y().z)", "has(x.y().z)"),
+            ("has((a + b).f)", "has((a + b).f)"),
+            ("has((-x).f)", "has((-x).f)"),
+        ];
+        for (source, expected) in cases {
+            assert_eq!(format_cel(source, &options).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_double_literals() {
+        // (source, as written, normalized): both keep the literal a double
+        let cases = [
+            ("2.0", "2.0", "2.0"),
+            ("2e3", "2e3", "2000.0"),
+            ("2.5e-3", "2.5e-3", "0.0025"),
+            ("20.0", "20.0", "20.0"),
+            ("2.0e3", "2.0e3", "2000.0"),
+            ("-2E3", "-2e3", "-2000.0"),
+            ("1e300", "1e300", "1e300"),
+            ("1.5E+21", "1.5e+21", "1.5e21"),
+            ("1e20", "1e20", "100000000000000000000.0"),
+            ("0.00000000025", "0.00000000025", "2.5e-10"),
+        ];
+        let normalized = FormatOptions::default().with_verify(true);
+        let options = normalized.clone().with_preserve_literal_spellings(true);
+        for (source, expected, expected_normalized) in cases {
+            assert_eq!(format_cel(source, &options).unwrap(), expected);
+            assert_eq!(
+                format_cel(source, &normalized).unwrap(),
+                expected_normalized
+            );
+        }
+    }
+
+    #[test]
+    fn test_comment_style() {
+        let options = FormatOptions::default().with_comment_style(CommentStyle::Normalize);
+        assert_eq!(
+            format_cel("//first  \n//    second\n////\n/// doc", &options).unwrap(),
+            "// first\n// second\n////\n/// doc"
+        );
+        assert_eq!(
+            format_cel("[ //values   \n1, 2]", &options).unwrap(),
+            "[ // values\n  1,\n  2,\n]"
+        );
+
+        let options = FormatOptions::default();
+        assert_eq!(
+            format_cel("//first  \n//    second", &options).unwrap(),
+            "//first\n//    second"
+        );
+    }
+
+    #[test]
+    fn test_prefer_dot_access() {
+        let options = FormatOptions::default()
+            .with_prefer_dot_access(true)
+            .with_verify(true);
+        let cases = [
+            ("m['foo']", "m.foo"),
+            ("a.b['c'].d", "a.b.c.d"),
+            ("f(x)['key_1']", "f(x).key_1"),
+            // Not field names
+            ("m['foo-bar']", "m[\"foo-bar\"]"),
+            ("m['1st']", "m[\"1st\"]"),
+            ("m['in']", "m[\"in\"]"),
+            ("m[k]", "m[k]"),
+            // Can't be maps
+            ("[1, 2]['a']", "[1, 2][\"a\"]"),
+            ("(a + b)['c']", "(a + b)[\"c\"]"),
+        ];
+        for (source, expected) in cases {
+            assert_eq!(format_cel(source, &options).unwrap(), expected);
+        }
+
+        let parse = |source| Parser::new().parse(source).unwrap();
+        assert!(same_expr(&parse("m['foo']"), &parse("m.foo"), &options));
+
+        // Without the option, an index and a select aren't the same expression
+        let options = FormatOptions::default();
+        assert_eq!(format_cel("m['foo']", &options).unwrap(), "m[\"foo\"]");
+        assert!(!same_expr(&parse("m['foo']"), &parse("m.foo"), &options));
+    }
+
+    #[test]
+    fn test_break_long_selects() {
+        let source = "request.resource.metadata.annotations.owner";
+        let options = FormatOptions::default().with_max_width(20);
+        assert_eq!(format_cel(source, &options).unwrap(), source);
+
+        let options = options.with_break_long_selects(true).with_verify(true);
+        assert_eq!(
+            format_cel(source, &options).unwrap(),
+            "request\n  .resource\n  .metadata\n  .annotations\n  .owner"
+        );
+        let options = options.with_break_member_chains(true);
+        assert_eq!(
+            format_cel("request.items.filter(i, i.ok).size", &options).unwrap(),
+            "request\n  .items\n  .filter(i, i.ok)\n  .size"
+        );
+        assert_eq!(format_cel("a.b.c", &options).unwrap(), "a.b.c");
+    }
+
+    #[test]
+    fn test_binary_chain_indent() {
+        let options = FormatOptions::default()
+            .with_max_width(30)
+            .with_verify(true);
+        let cases = [
+            (
+                "alpha_value && beta_value && gamma_value && delta_value",
+                "alpha_value\n  && beta_value\n  && gamma_value\n  && delta_value",
+            ),
+            (
+                "(alpha_value || beta_value) && (gamma_value || delta_value_long_name)",
+                "(alpha_value || beta_value)\n  && (gamma_value\n    || delta_value_long_name)",
+            ),
+            (
+                "first_value + second_value * third_value_long",
+                "first_value\n  + second_value\n    * third_value_long",
+            ),
+            (
+                "first_value - second_value + third_value",
+                "first_value\n  - second_value\n  + third_value",
+            ),
+        ];
+        for (source, expected) in cases {
+            assert_eq!(format_cel(source, &options).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_sort_list_elements() {
+        let options = FormatOptions::default()
+            .with_sort_list_elements(true)
+            .with_preserve_literal_spellings(true)
+            .with_verify(true);
+        let cases = [
+            ("x in [3, 1, 2]", "x in [1, 2, 3]"),
+            (
+                "role in ['write', 'admin', 'read']",
+                "role in [\"admin\", \"read\", \"write\"]",
+            ),
+            ("x in [0x10, 2]", "x in [2, 0x10]"),
+            ("x in [2.5, -1.0, 0x10 == y]", "x in [2.5, -1.0, 0x10 == y]"),
+            // Not all constants of one type
+            ("x in [3, a, 1]", "x in [3, a, 1]"),
+            ("x in [3, 1.0, 2u]", "x in [3, 1.0, 2u]"),
+            ("x in [[2, 1], [1]]", "x in [[2, 1], [1]]"),
+            // Lists that aren't used as sets keep their order
+            ("[3, 1, 2]", "[3, 1, 2]"),
+            ("[3, 1, 2][0] == 3", "[3, 1, 2][0] == 3"),
+            ("x == [2, 1]", "x == [2, 1]"),
+            ("[2, 1] in xs", "[2, 1] in xs"),
+        ];
+        for (source, expected) in cases {
+            let formatted = format_cel(source, &options).unwrap();
+            assert_eq!(formatted, expected);
+            assert_eq!(format_cel(&formatted, &options).unwrap(), expected);
+        }
+
+        let options = FormatOptions::default();
+        assert_eq!(
+            format_cel("x in [3, 1, 2]", &options).unwrap(),
+            "x in [3, 1, 2]"
+        );
+    }
+
+    #[test]
+    fn test_nested_struct() {
+        let options = FormatOptions::default()
+            .with_max_width(40)
+            .with_verify(true);
+        let cases = [
+            (
+                "Outer{a: 1, inner: Inner{b: 2, c: 3}}",
+                "Outer{a: 1, inner: Inner{b: 2, c: 3}}",
+            ),
+            (
+                "Outer{alpha: 1, inner: Inner{beta: 2, gamma: 3}}",
+                "Outer{\n  alpha: 1,\n  inner: Inner{beta: 2, gamma: 3},\n}",
+            ),
+            (
+                "Outer{alpha: 1, inner: Inner{beta_value: 2, gamma_value: 3, delta_value: 4}}",
+                "Outer{\n  alpha: 1,\n  inner: Inner{\n    beta_value: 2,\n    gamma_value: 3,\n    delta_value: 4,\n  },\n}",
+            ),
+        ];
+        for (source, expected) in cases {
+            assert_eq!(format_cel(source, &options).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_optional_chain() {
+        let string = |s: &str| ided(Expr::Literal(CelVal::String(s.to_string())));
+        let method = |target, name: &str, args| {
+            ided(Expr::Call(CallExpr {
+                func_name: name.to_string(),
+                target: Some(Box::new(target)),
+                args,
+            }))
+        };
+
+        // obj.?list[?0].orValue(default)
+        let list = call("_?._", vec![ident("obj"), string("list")]);
+        let first = call("_[?_]", vec![list, ided(Expr::Literal(CelVal::Int(0)))]);
+        let chain = method(first, "orValue", vec![ident("default")]);
+        assert_eq!(render(&chain), "obj.?list[?0].orValue(default)");
+
+        let cond = call("_?_:_", vec![ident("a"), ident("b"), ident("c")]);
+        let index = call("_[?_]", vec![cond, string("k")]);
+        assert_eq!(render(&index), "(a ? b : c)[?\"k\"]");
+        assert_eq!(
+            render(&method(index, "hasValue", vec![])),
+            "(a ? b : c)[?\"k\"].hasValue()"
+        );
+
+        let map = ided(Expr::Map(MapExpr {
+            entries: vec![IdedEntryExpr {
+                id: 0,
+                expr: EntryExpr::MapEntry(MapEntryExpr {
+                    key: string("k"),
+                    value: call("_?._", vec![ident("m"), string("k")]),
+                    optional: true,
+                }),
+            }],
+        }));
+        assert_eq!(render(&map), "{?\"k\": m.?k}");
+
+        let message = ided(Expr::Struct(StructExpr {
+            type_name: "Msg".to_string(),
+            entries: vec![IdedEntryExpr {
+                id: 0,
+                expr: EntryExpr::StructField(StructFieldExpr {
+                    field: "f".to_string(),
+                    value: ident("v"),
+                    optional: true,
+                }),
+            }],
+        }));
+        assert_eq!(render(&message), "Msg{?f: v}");
+
+        // Optional selections and indexes are links of a member chain
+        let options = FormatOptions::default()
+            .with_max_width(40)
+            .with_break_member_chains(true)
+            .with_break_long_selects(true);
+        let render = |expr: &IdedExpr| format_expr(expr, &options).render(options.max_width, "  ");
+        let settings = call("_?._", vec![ident("request"), string("settings")]);
+        let theme = call("_?._", vec![settings, string("preferredTheme")]);
+        let chain = method(theme, "orValue", vec![string("light")]);
+        assert_eq!(
+            render(&chain),
+            "request\n  .?settings\n  .?preferredTheme\n  .orValue(\"light\")"
+        );
+        let items = call("_?._", vec![ident("request"), string("items")]);
+        let first = call("_[?_]", vec![items, ided(Expr::Literal(CelVal::Int(0)))]);
+        let name = call("_?._", vec![first, string("displayName")]);
+        let chain = method(name, "orValue", vec![ident("fallbackName")]);
+        assert_eq!(
+            render(&chain),
+            "request\n  .?items[?0]\n  .?displayName\n  .orValue(fallbackName)"
+        );
+    }
+
+    #[test]
+    fn test_options_map_argument() {
+        let options = FormatOptions::default()
+            .with_max_width(30)
+            .with_verify(true);
+        let cases = [
+            (
+                "request({'timeout': 5, 'retries': 3, 'backoff': 2})",
+                "request({\n  \"timeout\": 5,\n  \"retries\": 3,\n  \"backoff\": 2,\n})",
+            ),
+            (
+                "request(url, {'timeout': 5, 'retries': 3})",
+                "request(\n  url,\n  {\"timeout\": 5, \"retries\": 3}\n)",
+            ),
+            (
+                "target.configure(Options{timeout: 5, retries: 3})",
+                "target.configure(Options{\n  timeout: 5,\n  retries: 3,\n})",
+            ),
+        ];
+        for (source, expected) in cases {
+            assert_eq!(format_cel(source, &options).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_wrap_long_strings() {
+        let options = FormatOptions::default()
+            .with_wrap_long_strings(Some(20))
+            .with_max_width(30)
+            .with_verify(true);
+        let cases = [
+            ("'short'", "\"short\""),
+            (
+                "'the quick brown fox jumps over the lazy dog'",
+                "\"the quick brown \"\n  + \"fox jumps over \"\n  + \"the lazy dog\"",
+            ),
+            (
+                "'abcdefghijklmnopqrstuvwxyzabcdefghij'",
+                "\"abcdefghijklmnopqr\"\n  + \"stuvwxyzabcdefghij\"",
+            ),
+            (
+                "x + 'the quick brown fox jumps'",
+                "x\n  + (\"the quick brown \"\n    + \"fox jumps\")",
+            ),
+            (
+                "'the quick brown fox jumps'.size()",
+                "(\"the quick brown \"\n  + \"fox jumps\").size()",
+            ),
+            // The pieces stay on one line when they fit
+            ("'the quick brown fox'", "\"the quick brown \" + \"fox\""),
+        ];
+        for (source, expected) in cases {
+            let formatted = format_cel(source, &options).unwrap();
+            assert_eq!(formatted, expected);
+            assert_eq!(format_cel(&formatted, &options).unwrap(), formatted);
+        }
+
+        // Only as much breaks as doesn't fit, and the output formats to itself
+        let long = "a long message that goes on and on and on";
+        let source = format!("msg == '{}'", long);
+        for max_width in [30, 60, 200] {
+            let options = options.clone().with_max_width(max_width);
+            let formatted = format_cel(&source, &options).unwrap();
+            assert_eq!(format_cel(&formatted, &options).unwrap(), formatted);
+        }
+        let options = options.with_max_width(200);
+        assert_eq!(
+            format_cel(&source, &options).unwrap(),
+            "msg == \"a long message \" + \"that goes on and \" + \"on and on\""
+        );
+
+        // A string that needs more pieces than `max_depth` stays whole, so
+        // the output can be formatted again
+        let source = format!("'{}'", "a".repeat(20000));
+        let formatted = format_cel(&source, &options).unwrap();
+        assert_eq!(formatted, format!("\"{}\"", "a".repeat(20000)));
+        assert_eq!(format_cel(&formatted, &options).unwrap(), formatted);
+
+        // Without the option, the pieces aren't the same expression
+        let parse = |source| Parser::new().parse(source).unwrap();
+        assert!(same_expr(&parse("'ab'"), &parse("'a' + 'b'"), &options));
+        let options = FormatOptions::default();
+        assert!(!same_expr(&parse("'ab'"), &parse("'a' + 'b'"), &options));
+    }
+
+    #[test]
+    fn test_minimize_escapes() {
+        let options = FormatOptions::default()
+            .with_minimize_escapes(true)
+            .with_verify(true);
+        let cases = [
+            (r#""\u0041\x42\103""#, r#""ABC""#),
+            (r#""caf\u00e9 \U0001F600""#, "\"caf\u{e9} \u{1f600}\""),
+            (r#"'\u0022quoted\u0022'"#, r#""\"quoted\"""#),
+            (r#""tab\u0009end""#, r#""tab\tend""#),
+            (r#""\u0007\u001f\u007f""#, r#""\a\x1f\x7f""#),
+            (r#""\u200b""#, "\"\u{200b}\""),
+        ];
+        for (source, expected) in cases {
+            assert_eq!(format_cel(source, &options).unwrap(), expected);
+        }
+
+        let options = options.with_minimize_escapes(false);
+        assert_eq!(format_cel(r#""\u001f""#, &options).unwrap(), r#""\u001f""#);
+    }
+
+    #[test]
+    fn test_top_level_boolean_expand() {
+        let options = FormatOptions::default()
+            .with_max_width(80)
+            .with_top_level_boolean_expand(true)
+            .with_verify(true);
+        let policy = "request.auth.claims.email_verified && \
+            request.auth.claims.email.endsWith('@example.com') && \
+            (request.method == 'GET' || request.method == 'HEAD') && \
+            !(request.path in ['/admin', '/internal'])";
+        assert_eq!(
+            format_cel(policy, &options).unwrap(),
+            "request.auth.claims.email_verified\n  \
+            && request.auth.claims.email.endsWith(\"@example.com\")\n  \
+            && (request.method == \"GET\" || request.method == \"HEAD\")\n  \
+            && !(request.path in [\"/admin\", \"/internal\"])"
+        );
+
+        // Short chains break too, but only the root chain; roots that aren't
+        // boolean chains are left to the usual layout
+        assert_eq!(format_cel("a && b", &options).unwrap(), "a\n  && b");
+        assert_eq!(
+            format_cel("a && b || c && f(x || y)", &options).unwrap(),
+            "a && b\n  || c && f(x || y)"
+        );
+        assert_eq!(
+            format_cel("size(request.headers) > 10", &options).unwrap(),
+            "size(request.headers) > 10"
+        );
+        let formatted = format_cel(policy, &options).unwrap();
+        assert_eq!(format_cel(&formatted, &options).unwrap(), formatted);
+    }
+
+    #[test]
+    fn test_target_version() {
+        let options = FormatOptions::default()
+            .with_target_version(TargetVersion::Core)
+            .with_verify(true);
+        let cases = [
+            ("xs.exists_one(x, x > 1)", "xs.filter(x, x > 1).size() == 1"),
+            (
+                "xs.map(x, x > 1, x * 2)",
+                "xs.filter(x, x > 1).map(x, x * 2)",
+            ),
+            (
+                "xs.all(x, ys.exists_one(y, y == x))",
+                "xs.all(x, ys.filter(y, y == x).size() == 1)",
+            ),
+            ("xs.map(x, x * 2)", "xs.map(x, x * 2)"),
+            ("xs.filter(x, x > 1)", "xs.filter(x, x > 1)"),
+        ];
+        for (source, expected) in cases {
+            assert_eq!(format_cel(source, &options).unwrap(), expected);
+        }
+
+        let options = options.with_target_version(TargetVersion::Standard);
+        assert_eq!(
+            format_cel("xs.exists_one(x, x > 1)", &options).unwrap(),
+            "xs.exists_one(x, x > 1)"
+        );
+
+        // Optional syntax has no older spelling
+        let mut select = call("_?._", vec![ident("obj"), ident("field")]);
+        assert_eq!(
+            lower_to_target(&mut select, TargetVersion::Standard),
+            Err("optional field selection in the target CEL version")
+        );
+        assert_eq!(lower_to_target(&mut select, TargetVersion::Latest), Ok(()));
+        let syntax = lower_to_target(&mut select, TargetVersion::Standard).unwrap_err();
+        assert_eq!(
+            FormatError::UnsupportedSyntax { syntax }.to_string(),
+            "Can't write optional field selection in the target CEL version"
+        );
+    }
+
+    #[test]
+    fn test_round_trip_regressions() {
+        // Cases found by the round-trip property test
+        let options = FormatOptions::default().with_verify(true);
+        let cases = [
+            ("a * (b * c)", "a * (b * c)"),
+            ("a + (b - c)", "a + (b - c)"),
+            ("(a ? b : c) || d", "(a ? b : c) || d"),
+            ("a + (b ? c : d)", "a + (b ? c : d)"),
+            ("-(0.5.f()[a])", "-(0.5.f()[a])"),
+            ("-(1.size())", "-(1.size())"),
+            ("-x.size()", "-x.size()"),
+        ];
+        for (source, expected) in cases {
+            assert_eq!(format_cel(source, &options).unwrap(), expected);
+        }
+
+        // The parser only takes `\x` and octal escapes in bytes literals
+        let options = options.with_normalize_literals(true);
+        assert_eq!(
+            format_cel(r#"b"\011\042\134""#, &options).unwrap(),
+            r#"b"\x09\x22\x5c""#
+        );
+    }
+
+    #[test]
+    fn test_duration_and_timestamp_styles() {
+        // The nanoseconds a formatted duration or timestamp evaluates to,
+        // timestamps counted from the epoch
+        let nanos = |output: &str| {
+            let source = if output.starts_with("timestamp") {
+                format!("{} - timestamp('1970-01-01T00:00:00Z')", output)
+            } else {
+                output.to_string()
+            };
+            match cel::Program::compile(&source)
+                .unwrap()
+                .execute(&cel::Context::default())
+            {
+                Ok(cel::Value::Duration(d)) => d.num_nanoseconds().unwrap(),
+                other => panic!("{} evaluated to {:?}", output, other),
+            }
+        };
+
+        let durations = [
+            ((5400, 0), "duration(\"1h30m\")", "duration(\"5400s\")"),
+            (
+                (90, 500_000_000),
+                "duration(\"1m30.5s\")",
+                "duration(\"90.5s\")",
+            ),
+            (
+                (0, 250_000),
+                "duration(\"0.00025s\")",
+                "duration(\"0.00025s\")",
+            ),
+            ((0, 0), "duration(\"0s\")", "duration(\"0s\")"),
+        ];
+        for ((secs, subsec), compact, seconds) in durations {
+            let duration = Duration::new(secs, subsec);
+            let expr = ided(Expr::Literal(CelVal::Duration(duration)));
+            for (style, expected) in [
+                (DurationStyle::Compact, compact),
+                (DurationStyle::Seconds, seconds),
+            ] {
+                let options = FormatOptions::default().with_duration_style(style);
+                let output = format_expr(&expr, &options).render(0, "  ");
+                assert_eq!(output, expected);
+                assert_eq!(nanos(&output), duration.as_nanos() as i64);
+            }
+        }
+
+        let timestamps = [
+            (
+                UNIX_EPOCH + Duration::from_secs(1_672_531_200),
+                "timestamp(\"2023-01-01T00:00:00Z\")",
+                "timestamp(1672531200)",
+            ),
+            (
+                UNIX_EPOCH + Duration::new(951_827_696, 500_000_000),
+                "timestamp(\"2000-02-29T12:34:56.5Z\")",
+                "timestamp(\"2000-02-29T12:34:56.5Z\")",
+            ),
+            (
+                UNIX_EPOCH - Duration::from_secs(86_400),
+                "timestamp(\"1969-12-31T00:00:00Z\")",
+                "timestamp(-86400)",
+            ),
+        ];
+        for (timestamp, rfc3339, epoch) in timestamps {
+            let expr = ided(Expr::Literal(CelVal::Timestamp(timestamp)));
+            let since_epoch = match timestamp.duration_since(UNIX_EPOCH) {
+                Ok(after) => after.as_nanos() as i64,
+                Err(before) => -(before.duration().as_nanos() as i64),
+            };
+
+            let options = FormatOptions::default();
+            let output = format_expr(&expr, &options).render(0, "  ");
+            assert_eq!(output, rfc3339);
+            assert_eq!(nanos(&output), since_epoch);
+
+            // The `cel` interpreter only takes strings, so the int form is
+            // checked by parsing
+            let options = options.with_timestamp_style(TimestampStyle::EpochSeconds);
+            let output = format_expr(&expr, &options).render(0, "  ");
+            assert_eq!(output, epoch);
+            let parsed = Parser::new().parse(&output).unwrap();
+            match &parsed.expr {
+                Expr::Call(call) => match &call.args[0].expr {
+                    Expr::Literal(CelVal::Int(secs)) => {
+                        assert_eq!(*secs * 1_000_000_000, since_epoch)
+                    }
+                    _ => assert_eq!(nanos(&output), since_epoch),
+                },
+                _ => panic!("{} isn't a call", output),
+            }
+        }
+    }
+
+    #[test]
+    fn test_flatten() {
+        fn nested(doc: &Doc) -> bool {
+            match doc {
+                Doc::Concat(docs) => docs
+                    .iter()
+                    .any(|doc| matches!(doc, Doc::Nil | Doc::Concat(_)) || nested(doc)),
+                Doc::Indent(doc) | Doc::Align(_, doc) | Doc::Group(doc) | Doc::BrokenGroup(doc) => {
+                    nested(doc)
+                }
+                Doc::IfBreak {
+                    break_doc,
+                    flat_doc,
+                } => nested(break_doc) || nested(flat_doc),
+                _ => false,
+            }
+        }
+
+        let sources = [
+            "a && b || !c",
+            "{'a': [1, 2, 3], // ids\n 'b': {'c': x.map(y, y * 2)}}",
+            "items.all(i, i.price < 100 && i.quantity > 0 && i.name != '' && i.active)",
+            "cond ? request.auth.claims['email'].endsWith('@example.com') : f(x, y, z)",
+        ];
+        let options = FormatOptions::default();
+        for source in sources {
+            let doc = to_doc(source, &options).unwrap();
+            let flat = doc.clone().flatten();
+            assert!(!nested(&flat), "{}", source);
+            for max_width in [0, 10, 30, 80] {
+                assert_eq!(
+                    flat.render(max_width, "  "),
+                    doc.render(max_width, "  "),
+                    "{} at {}",
+                    source,
+                    max_width
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_simplify_boolean_constants() {
+        let options = FormatOptions::default();
+        for source in ["x && true", "y || false", "(a < b) == true"] {
+            assert_eq!(format_cel(source, &options).unwrap(), source);
+        }
+
+        let options = options.with_simplify(true);
+        let cases = [
+            ("(a < b) && true", "a < b"),
+            ("true && has(a.b)", "has(a.b)"),
+            ("a.contains(b) || false", "a.contains(b)"),
+            ("false || !y", "!y"),
+            ("(a < b) == true", "a < b"),
+            ("true == a.startsWith('b')", "a.startsWith(\"b\")"),
+            ("has(a.b) != false", "has(a.b)"),
+            ("items.all(i, i > 0) == true", "items.all(i, i > 0)"),
+            ("(x > 1 || false) && (true && y > 2)", "x > 1 && y > 2"),
+            ("!(!(a < b)) && true", "a < b"),
+            // Only bools are kept, since `1 == true` is false and
+            // `"s" && true` is an error
+            ("z == true", "z == true"),
+            ("x && true", "x && true"),
+            ("(\"s\" && true)", "\"s\" && true"),
+            ("(1 || false)", "1 || false"),
+            ("f(z) != false", "f(z) != false"),
+            ("x && false", "x && false"),
+            ("y || true", "y || true"),
+        ];
+        for (source, expected) in cases {
+            let formatted = format_cel(source, &options).unwrap();
+            assert_eq!(formatted, expected, "simplifying {}", source);
+            assert_eq!(format_cel(&formatted, &options).unwrap(), formatted);
+        }
+        assert!(format_cel("(a < b) && true", &options.with_verify(true)).is_ok());
+    }
+
+    #[test]
+    fn test_mixed_index_select_chains() {
+        let options = FormatOptions::default().with_verify(true);
+        let unchanged = [
+            r#"data["users"][0].name.emails[1]"#,
+            "a.b[c][d].e.f[g.h[i]][0]",
+            "a.b()[0].c()[1].d",
+            "[1, 2][0].a",
+            r#"{"a": b}["a"].c[0]"#,
+            "has(a[0].b.c[1].d)",
+            "-a[0].b[1].c",
+            "!a.b[0].c[1]",
+            "(a ? b : c)[0].d[1]",
+            "(a[0] ? b[1] : c[2])[3].d",
+            "(a || b)[0].c.d[1]",
+            "(!a).b[1].c[2]",
+            "(x.y[0] + 1)[2].z",
+            "a[b ? c : d].e[0].f",
+        ];
+        for source in unchanged {
+            assert_eq!(format_cel(source, &options).unwrap(), source);
+        }
+
+        // An index stays on the line of the link before it
+        let options = options.with_max_width(24).with_break_member_chains(true);
+        assert_eq!(
+            format_cel(
+                r#"request.headers["x-forwarded-for"].split(",")[0].trim().lowerAscii()"#,
+                &options
+            )
+            .unwrap(),
+            "request.headers[\"x-forwarded-for\"]\n  .split(\",\")[0]\n  .trim()\n  .lowerAscii()"
+        );
+        assert_eq!(
+            format_cel("items[0].children()[1].grandchildren()[2].name", &options).unwrap(),
+            "items[0]\n  .children()[1]\n  .grandchildren()[2].name"
+        );
+
+        let options = options.with_break_long_selects(true);
+        assert_eq!(
+            format_cel(
+                "(cond ? left : right)[index].value.children[0].name",
+                &options
+            )
+            .unwrap(),
+            "(cond ? left : right)[index]\n  .value\n  .children[0]\n  .name"
+        );
+    }
+
+    #[test]
+    fn test_no_trailing_comma_in_args() {
+        let source = "check(request.principal, resource.owner, 'write')";
+        let expected = "check(\n  request.principal,\n  resource.owner,\n  \"write\"\n)";
+        for trailing_comma in [true, false] {
+            let options = FormatOptions::default()
+                .with_max_width(40)
+                .with_trailing_comma(trailing_comma)
+                .with_verify(true);
+            assert_eq!(format_cel(source, &options).unwrap(), expected);
+        }
+        assert!(Parser::new().parse("check(a, b,)").is_err());
+    }
+
+    #[test]
+    fn test_max_output_bytes() {
+        let items: Vec<String> = (0..1000).map(|i| format!("item_{}", i)).collect();
+        let source = format!("[{}]", items.join(", "));
+        let error = |options: &FormatOptions| {
+            format_cel(&source, options)
+                .unwrap_err()
+                .downcast::<FormatError>()
+                .unwrap()
+        };
+
+        // Broken across lines, and on one line with no width limit
+        for max_width in [80, 0] {
+            let options = FormatOptions::default().with_max_width(max_width);
+            let full = format_cel(&source, &options).unwrap();
+            assert!(full.len() > 8_000);
+
+            let capped = options.clone().with_max_output_bytes(Some(full.len()));
+            assert_eq!(format_cel(&source, &capped).unwrap(), full);
+            for limit in [full.len() - 1, 1000] {
+                let capped = options.clone().with_max_output_bytes(Some(limit));
+                assert_eq!(error(&capped), FormatError::OutputTooLarge { limit });
+            }
+        }
+
+        // Streaming stops at the cap
+        let options = FormatOptions::default().with_max_output_bytes(Some(1000));
+        let mut out = Vec::new();
+        let error = format_cel_to(&source, &options, &mut out).unwrap_err();
+        assert_eq!(
+            error.downcast::<FormatError>().unwrap(),
+            FormatError::OutputTooLarge { limit: 1000 }
+        );
+        assert!(out.len() <= 1000);
+    }
+
+    #[test]
+    fn test_comprehensions_in_collections() {
+        let options = FormatOptions::default()
+            .with_max_width(40)
+            .with_verify(true);
+        let cases = [
+            (
+                "[xs.map(x, x * 2), ys.map(y, y + 1)]",
+                "[xs.map(x, x * 2), ys.map(y, y + 1)]",
+            ),
+            // The list breaks, and so does the body of the first macro
+            (
+                "[orders.map(o, o.price * o.quantity + o.shipping), refunds.map(r, r.amount * r.rate)]",
+                "[\n  orders.map(o, o.price * o.quantity\n    + o.shipping),\n  refunds.map(r, r.amount * r.rate),\n]",
+            ),
+            (
+                "[xs.map(x, [x.first_value, x.second_value, x.third]), ys.filter(y, y > 0)]",
+                "[\n  xs.map(x, [\n    x.first_value,\n    x.second_value,\n    x.third,\n  ]),\n  ys.filter(y, y > 0),\n]",
+            ),
+            (
+                "{'emails': users.map(u, u.email), 'admins': users.filter(u, u.is_admin)}",
+                "{\n  \"emails\": users.map(u, u.email),\n  \"admins\": users.filter(u, u.is_admin),\n}",
+            ),
+        ];
+        for (source, expected) in cases {
+            let formatted = format_cel(source, &options).unwrap();
+            assert_eq!(formatted, expected);
+            assert_eq!(format_cel(&formatted, &options).unwrap(), formatted);
+        }
+
+        // A comprehension no macro produces is spelled out in full
+        let sum = comprehension(
+            ided(Expr::Literal(CelVal::Int(0))),
+            call("_+_", vec![ident("@result"), ident("x")]),
+        );
+        let list = ided(Expr::List(ListExpr {
+            elements: vec![sum, ident("y")],
+        }));
+        assert_eq!(
+            render(&list),
+            "[__comprehension__(x, nums, @result, 0, true, @result + x, @result), y]"
+        );
+    }
+
+    #[test]
+    fn test_preserve_arithmetic_parens() {
+        let options = FormatOptions::default().with_verify(true);
+        let cases = [
+            ("(a + b) + c", "a + b + c"),
+            ("(a * b) + (c / d)", "a * b + c / d"),
+            ("x - (-y)", "x - -y"),
+            ("(a + b) * c", "(a + b) * c"),
+        ];
+        // Without the option only the needed parentheses are kept
+        for (source, expected) in cases {
+            assert_eq!(format_cel(source, &options).unwrap(), expected);
+        }
+
+        let options = options.with_preserve_arithmetic_parens(true);
+        let cases = [
+            ("(a + b) + c", "(a + b) + c"),
+            ("(a * b) + (c / d)", "(a * b) + (c / d)"),
+            ("((a + b)) * c", "(a + b) * c"),
+            ("(x % 2) == 0 && (y)", "(x % 2) == 0 && y"),
+            ("(a < b) == (c || d)", "(a < b) == (c || d)"),
+            ("x - (-y)", "x - -y"),
+            ("-(a.b + 1.0).size()", "-(a.b + 1.0).size()"),
+            (
+                "f((a + b), '(c + d)') + xs.map(x, (x * 2) + 1)[0]",
+                "f((a + b), \"(c + d)\") + xs.map(x, (x * 2) + 1)[0]",
+            ),
+            (
+                "has(a.b) ? (c + d) : e // (f + g)",
+                "has(a.b) ? (c + d) : e",
+            ),
+        ];
+        for (source, expected) in cases {
+            let formatted = format_cel(source, &options).unwrap();
+            assert_eq!(formatted, expected, "formatting {}", source);
+            assert_eq!(format_cel(&formatted, &options).unwrap(), formatted);
+        }
+
+        // A source that already uses the marker's name is formatted as usual
+        assert_eq!(
+            format_cel("__parens__(1) + ((a + b) + c)", &options).unwrap(),
+            "__parens__(1) + (a + b + c)"
+        );
+    }
+
+    #[test]
+    fn test_reserved_word_identifiers() {
+        let options = FormatOptions::default();
+        let field = |source: &str| {
+            let ast = Parser::new().parse(source).unwrap();
+            match ast.expr {
+                Expr::Select(select) => select.field,
+                _ => unreachable!(),
+            }
+        };
+
+        // Reserved words the parser takes as identifiers are written as they
+        // are, and backtick-quoted fields keep their quotes
+        for source in ["as", "a.if", "a.while.namespace", "a.`foo-bar`", "a.`if`"] {
+            let formatted = format_cel(source, &options).unwrap();
+            assert_eq!(formatted, source);
+            if source.contains('.') {
+                assert_eq!(field(&formatted), field(source));
+            }
+        }
+
+        // Names that can't be spelled as identifiers have no CEL to write: in
+        // backticks, a field keeps them in its name
+        let select = |field: &str| {
+            ided(Expr::Select(SelectExpr {
+                operand: Box::new(ident("a")),
+                field: field.to_string(),
+                test: false,
+            }))
+        };
+        for name in ["true", "foo-bar", "in"] {
+            assert_eq!(
+                check_writable(&mut select(name), &options),
+                Err(FormatError::UnsupportedSyntax {
+                    syntax: "a field name that CEL has no spelling for"
+                })
+            );
+            assert_eq!(
+                check_writable(&mut ident(name), &options),
+                Err(FormatError::UnsupportedSyntax {
+                    syntax: "an identifier that isn't a valid CEL name"
+                })
+            );
+        }
+        assert_eq!(render(&select("_x1")), "a._x1");
+        assert!(check_writable(&mut select("_x1"), &options).is_ok());
+        assert!(check_writable(&mut select("`foo-bar`"), &options).is_ok());
+
+        // A quoted field used as a map key drops its quotes
+        let options = FormatOptions::default().with_key_presence_style(KeyPresenceStyle::In);
+        assert_eq!(
+            format_cel("has(m.`foo-bar`)", &options).unwrap(),
+            "\"foo-bar\" in m"
+        );
+    }
+
+    #[test]
+    fn test_double_exponent_style() {
+        // (source, lower, upper, lower padded, upper padded)
+        let cases = [
+            ("1e-3", "1e-3", "1E-3", "1e-03", "1E-03"),
+            ("1.5e10", "1.5e10", "1.5E10", "1.5e10", "1.5E10"),
+            ("2.5E+007", "2.5e+7", "2.5E+7", "2.5e+07", "2.5E+07"),
+            (
+                "1e-300 + 1e0",
+                "1e-300 + 1e0",
+                "1E-300 + 1E0",
+                "1e-300 + 1e00",
+                "1E-300 + 1E00",
+            ),
+            (
+                "0x1E + 1.5",
+                "0x1E + 1.5",
+                "0x1E + 1.5",
+                "0x1E + 1.5",
+                "0x1E + 1.5",
+            ),
+        ];
+        let styles = [
+            DoubleExponentStyle::Lower,
+            DoubleExponentStyle::Upper,
+            DoubleExponentStyle::LowerPadded,
+            DoubleExponentStyle::UpperPadded,
+        ];
+        for (source, lower, upper, lower_padded, upper_padded) in cases {
+            for (style, expected) in
+                styles
+                    .into_iter()
+                    .zip([lower, upper, lower_padded, upper_padded])
+            {
+                let options = FormatOptions::default()
+                    .with_preserve_literal_spellings(true)
+                    .with_double_exponent_style(style)
+                    .with_verify(true);
+                assert_eq!(
+                    format_cel(source, &options).unwrap(),
+                    expected,
+                    "{:?}",
+                    style
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_ternary_operands_of_binary_ops() {
+        // The ternary binds loosest, so as the operand of any binary operator
+        // it keeps its parens
+        let options = FormatOptions::default().with_verify(true);
+        for op in ["in", "||", "&&", "==", "<", "+", "*", "%"] {
+            for source in [
+                format!("x {} (a ? b : c)", op),
+                format!("(a ? b : c) {} x", op),
+            ] {
+                assert_eq!(format_cel(&source, &options).unwrap(), source);
+            }
+        }
+        assert_eq!(
+            format_cel("x in a ? b : c", &options).unwrap(),
+            "x in a ? b : c"
+        );
+
+        // Built without parens, as another tool might hand it over
+        let cond = || call("_?_:_", vec![ident("a"), ident("b"), ident("c")]);
+        let expr = call("@in", vec![ident("x"), cond()]);
+        assert_eq!(render(&expr), "x in (a ? b : c)");
+        let negated = call("!_", vec![expr]);
+        let options = FormatOptions::default().with_not_in_operator(true);
+        assert_eq!(
+            format_expr(&negated, &options).render(options.max_width, "  "),
+            "x not in (a ? b : c)"
+        );
+        assert_eq!(render(&call("-_", vec![cond()])), "-(a ? b : c)");
+    }
+
+    #[test]
+    fn test_align_values() {
+        let options = FormatOptions::default()
+            .with_align_values(true)
+            .with_max_width(40);
+
+        // Each struct lines up its own fields
+        assert_eq!(
+            format_cel(
+                "Config{id: 1, display_name: 'x', retry_policy_name: Policy{max: 3, backoff_seconds: 10}, tag: t}",
+                &options
+            )
+            .unwrap(),
+            "Config{\n  id:                1,\n  display_name:      \"x\",\n  retry_policy_name: Policy{\n    max:             3,\n    backoff_seconds: 10,\n  },\n  tag:               t,\n}"
+        );
+
+        // Map keys of any kind, and nothing is padded on one line
+        assert_eq!(
+            format_cel("{'a': 1, 'longer_key': 2, x.y: [3, 4, 5, 6, 7]}", &options).unwrap(),
+            "{\n  \"a\":          1,\n  \"longer_key\": 2,\n  x.y:          [3, 4, 5, 6, 7],\n}"
+        );
+        assert_eq!(
+            format_cel("Point{x: 1, longer: 2}", &options).unwrap(),
+            "Point{x: 1, longer: 2}"
+        );
+
+        let options = options.with_colon_spacing(ColonSpacing::Around);
+        assert_eq!(
+            format_cel("{'a': 1, 'longer_key': 2, 'third': 'three'}", &options).unwrap(),
+            "{\n  \"a\" :          1,\n  \"longer_key\" : 2,\n  \"third\" :      \"three\",\n}"
+        );
+
+        // Keys are measured in characters, as written
+        let options = FormatOptions::default()
+            .with_align_values(true)
+            .with_max_width(10);
+        assert_eq!(
+            format_cel("{'é': 1, 'ab': 2}", &options).unwrap(),
+            "{\n  \"é\":  1,\n  \"ab\": 2,\n}"
+        );
+        let options = options
+            .with_preserve_literal_spellings(true)
+            .with_max_width(20);
+        assert_eq!(
+            format_cel("{0x10: 'a', 2: 'b', 300: {0x1: 0}}", &options).unwrap(),
+            "{\n  0x10: \"a\",\n  2:    \"b\",\n  300:  {0x1: 0},\n}"
+        );
+    }
+
+    #[test]
+    fn test_empty_struct_spacing() {
+        // (space before, space inside, expected)
+        let cases = [
+            (false, false, "Foo{}"),
+            (true, false, "Foo {}"),
+            (false, true, "Foo{ }"),
+            (true, true, "Foo { }"),
+        ];
+        for (before, inside, expected) in cases {
+            let options = FormatOptions::default()
+                .with_empty_struct_space_before(before)
+                .with_empty_struct_space_inside(inside)
+                .with_verify(true);
+            assert_eq!(format_cel("Foo{}", &options).unwrap(), expected);
+            assert_eq!(format_cel(expected, &options).unwrap(), expected);
+            // Only empty structs change
+            assert_eq!(
+                format_cel("Foo{a: Bar{}}", &options).unwrap(),
+                format!("Foo{{a: {}}}", expected.replace("Foo", "Bar"))
+            );
+        }
+    }
+
+    #[test]
+    fn test_align() {
+        let lines = || Doc::concat(vec![Doc::text("a"), Doc::line(), Doc::text("b")]);
+        let broken = |doc| Doc::group(Doc::concat(vec![Doc::break_parent(), doc]));
+
+        // Align adds spaces, Indent adds levels, and they stack in any order
+        let doc = Doc::concat(vec![
+            Doc::text("x"),
+            Doc::align(3, broken(Doc::concat(vec![Doc::line(), lines()]))),
+        ]);
+        assert_eq!(doc.render(80, "\t"), "x\n   a\n   b");
+        let doc = Doc::indent(Doc::align(
+            2,
+            broken(Doc::concat(vec![
+                Doc::line(),
+                lines(),
+                Doc::indent(Doc::concat(vec![Doc::line(), lines()])),
+            ])),
+        ));
+        assert_eq!(doc.render(80, "\t"), "\n\t  a\n\t  b\n\t\t  a\n\t\t  b");
+
+        // The aligned column counts toward the width
+        let group = Doc::group(Doc::concat(vec![
+            Doc::text("12345"),
+            Doc::line(),
+            Doc::text("678"),
+        ]));
+        let doc = Doc::align(
+            4,
+            broken(Doc::concat(vec![Doc::text("f("), Doc::line(), group])),
+        );
+        assert_eq!(doc.render(9, "  "), "f(\n    12345\n    678");
+        assert_eq!(doc.render(13, "  "), "f(\n    12345 678");
+
+        // Flat, nothing is aligned, and flattening keeps the alignment
+        assert_eq!(Doc::align(4, lines()).render_flat(), "a b");
+        assert_eq!(doc.clone().flatten().render(9, "  "), doc.render(9, "  "));
+    }
+
+    #[test]
+    fn test_running_max_comprehension() {
+        // nums.reduce(@result, 0, @result > x ? @result : x), which no CEL
+        // macro expands to. The `cel` parser has no `reduce` macro and doesn't
+        // parse the debug form, so it can't round-trip: formatting refuses it
+        let zero = || ided(Expr::Literal(CelVal::Int(0)));
+        let step = |op: &str| {
+            call(
+                "_?_:_",
+                vec![
+                    call(op, vec![ident("@result"), ident("x")]),
+                    ident("@result"),
+                    ident("x"),
+                ],
+            )
+        };
+        let options = FormatOptions::default();
+        let mut running_max = comprehension(zero(), step("_>_"));
+        let debug = render(&running_max);
+        assert_eq!(
+            debug,
+            "__comprehension__(x, nums, @result, 0, true, @result > x ? @result : x, @result)"
+        );
+        assert!(new_parser(&options).parse(&debug).is_err());
+        assert!(matches!(
+            check_writable(&mut running_max, &options),
+            Err(FormatError::UnsupportedSyntax { .. })
+        ));
+
+        // Steps shaped like the exists_one() macro's aren't mistaken for it
+        let count_like = call(
+            "_?_:_",
+            vec![
+                call("_<_", vec![ident("@result"), ident("x")]),
+                call("_+_", vec![ident("@result"), zero()]),
+                ident("@result"),
+            ],
+        );
+        let formatted = render(&comprehension(zero(), count_like));
+        assert!(formatted.starts_with("__comprehension__("), "{}", formatted);
+
+        // Broken, each part is on its own line
+        let options = FormatOptions::default().with_max_width(40);
+        assert_eq!(
+            format_expr(&comprehension(zero(), step("_<_")), &options).render(40, "  "),
+            "__comprehension__(\n  x,\n  nums,\n  @result,\n  0,\n  true,\n  @result < x ? @result : x,\n  @result\n)"
+        );
+    }
 }
@@ -1,9 +1,64 @@
+//! A code formatter for the Common Expression Language (CEL)
+//!
+//! With the default `std` feature this crate parses and formats CEL source.
+//! Without it the crate is `no_std` (it only needs `alloc`) and provides the
+//! [`doc`] pretty-printing engine and [`FormatOptions`]; parsing needs the
+//! `cel` crate, which requires `std`.
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+#[cfg(feature = "std")]
+pub mod analyze;
+#[cfg(feature = "std")]
+mod comments;
+#[cfg(feature = "std")]
+pub mod decl;
+#[cfg(feature = "std")]
+mod depth;
+#[cfg(feature = "std")]
+pub mod diff;
 pub mod doc;
+#[cfg(feature = "std")]
+pub mod error;
+#[cfg(feature = "std")]
 pub mod formatter;
+#[cfg(feature = "std")]
+mod literals;
+#[cfg(feature = "std")]
+pub mod multi;
 pub mod options;
+#[cfg(feature = "std")]
+mod parens;
+#[cfg(all(test, feature = "std"))]
+mod roundtrip;
+#[cfg(feature = "std")]
+mod simplify;
+#[cfg(feature = "std")]
+mod verify;
 
 #[cfg(feature = "wasm")]
 pub mod wasm;
 
-pub use formatter::format_cel;
-pub use options::FormatOptions;
+#[cfg(feature = "std")]
+pub use analyze::{analyze, free_variables, FormatStats, NodeCounts};
+#[cfg(feature = "std")]
+pub use decl::format_decl;
+#[cfg(feature = "std")]
+pub use diff::{classify_change, line_changes, ChangeKind, LineChanges};
+#[cfg(feature = "std")]
+pub use error::{FormatError, FormatWarning};
+#[cfg(feature = "std")]
+pub use formatter::{
+    format_cel, format_cel_lenient, format_cel_to, format_cel_with_warnings, format_preview, to_doc,
+};
+#[cfg(feature = "std")]
+pub use multi::{
+    check_syntax, format_all, format_cel_many, format_cel_many_in_lines,
+    format_cel_many_with_warnings,
+};
+pub use options::{
+    BoolCase, BytesLiteralStyle, ClosingBracketStyle, ColonSpacing, CommentStyle,
+    DoubleExponentStyle, DurationStyle, FormatOptions, KeyPresenceStyle, LineEnding, OperatorBreak,
+    TargetVersion, TimestampStyle,
+};
@@ -0,0 +1,330 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::doc::{Doc, TokenKind};
+use crate::formatter::format_double;
+use crate::options::{DoubleExponentStyle, DurationStyle, TimestampStyle};
+
+/// Replace canonical numeric literals in a document with their source spelling
+///
+/// The parser only keeps literal values, so `0x10` and `16` are
+/// indistinguishable in the AST. This recovers the spellings by scanning the
+/// source and swaps them into `Doc::Token(TokenKind::Literal, _)` nodes whose
+/// canonical text matches, in source order. Exponents are respelled in
+/// `exponent_style`.
+pub(crate) fn preserve_spellings(
+    doc: Doc,
+    source: &str,
+    exponent_style: DoubleExponentStyle,
+) -> Doc {
+    let mut spellings: HashMap<String, VecDeque<String>> = HashMap::new();
+    for spelling in numeric_literals(source) {
+        if let Some(canonical) = canonical_form(&spelling) {
+            let spelling = style_exponent(&spelling, exponent_style);
+            spellings.entry(canonical).or_default().push_back(spelling);
+        }
+    }
+
+    if spellings.is_empty() {
+        return doc;
+    }
+    replace_literals(doc, &mut spellings)
+}
+
+fn replace_literals(doc: Doc, spellings: &mut HashMap<String, VecDeque<String>>) -> Doc {
+    match doc {
+        Doc::Token(TokenKind::Literal, text) => {
+            // Negative literals carry their sign in the value, not the spelling
+            let (sign, magnitude) = match text.strip_prefix('-') {
+                Some(rest) => ("-", rest),
+                None => ("", text.as_str()),
+            };
+            match spellings.get_mut(magnitude).and_then(|q| q.pop_front()) {
+                Some(spelling) => Doc::Token(TokenKind::Literal, format!("{}{}", sign, spelling)),
+                None => Doc::Token(TokenKind::Literal, text),
+            }
+        }
+        Doc::Concat(docs) => Doc::Concat(
+            docs.into_iter()
+                .map(|d| replace_literals(d, spellings))
+                .collect(),
+        ),
+        Doc::Indent(d) => Doc::Indent(Box::new(replace_literals(*d, spellings))),
+        Doc::Align(width, d) => Doc::Align(width, Box::new(replace_literals(*d, spellings))),
+        Doc::Group(d) => Doc::Group(Box::new(replace_literals(*d, spellings))),
+        Doc::BrokenGroup(d) => Doc::BrokenGroup(Box::new(replace_literals(*d, spellings))),
+        Doc::IfBreak {
+            break_doc,
+            flat_doc,
+        } => Doc::IfBreak {
+            break_doc: Box::new(replace_literals(*break_doc, spellings)),
+            flat_doc: Box::new(replace_literals(*flat_doc, spellings)),
+        },
+        other => other,
+    }
+}
+
+/// The text the formatter emits for a numeric literal spelling
+fn canonical_form(spelling: &str) -> Option<String> {
+    let (digits, unsigned) = match spelling.strip_suffix(['u', 'U']) {
+        Some(digits) => (digits, true),
+        None => (spelling, false),
+    };
+
+    if let Some(hex) = digits.strip_prefix("0x") {
+        let value = u64::from_str_radix(hex, 16).ok()?;
+        return Some(if unsigned {
+            format!("{}u", value)
+        } else {
+            value.to_string()
+        });
+    }
+
+    if unsigned {
+        return digits.parse::<u64>().ok().map(|v| format!("{}u", v));
+    }
+    if digits.contains(['.', 'e', 'E']) {
+        return digits.parse::<f64>().ok().map(format_double);
+    }
+    digits.parse::<u64>().ok().map(|v| v.to_string())
+}
+
+/// Respell the exponent of a decimal literal, leaving other literals alone
+fn style_exponent(spelling: &str, style: DoubleExponentStyle) -> String {
+    let is_hex = spelling.starts_with("0x") || spelling.starts_with("0X");
+    let Some((mantissa, exponent)) = spelling.split_once(['e', 'E']).filter(|_| !is_hex) else {
+        return spelling.to_string();
+    };
+
+    let (sign, digits) = match exponent.strip_prefix(['+', '-']) {
+        Some(digits) => (&exponent[..1], digits),
+        None => ("", exponent),
+    };
+    let digits = match digits.trim_start_matches('0') {
+        "" => "0",
+        digits => digits,
+    };
+    let (letter, width) = match style {
+        DoubleExponentStyle::Lower => ('e', 1),
+        DoubleExponentStyle::Upper => ('E', 1),
+        DoubleExponentStyle::LowerPadded => ('e', 2),
+        DoubleExponentStyle::UpperPadded => ('E', 2),
+    };
+    format!(
+        "{}{}{}{:0>width$}",
+        mantissa,
+        letter,
+        sign,
+        digits,
+        width = width
+    )
+}
+
+/// Collect the spellings of all numeric literals in a CEL source, in order
+///
+/// Strings, bytes, comments, and identifiers are skipped so digits inside them
+/// aren't mistaken for literals.
+fn numeric_literals(source: &str) -> Vec<String> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut literals = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+
+        if c == '/' && next == Some('/') {
+            while i < chars.len() && chars[i] != '\n' {
+                i += 1;
+            }
+        } else if c == '"' || c == '\'' {
+            i = skip_string(&chars, i, false);
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            // String prefixes: r"...", b"...", rb"...", and so on
+            let word: String = chars[start..i].iter().collect::<String>().to_lowercase();
+            let is_prefix = matches!(word.as_str(), "r" | "b" | "rb" | "br");
+            if is_prefix && matches!(chars.get(i), Some('"') | Some('\'')) {
+                i = skip_string(&chars, i, word.contains('r'));
+            }
+        } else if c.is_ascii_digit() || (c == '.' && next.is_some_and(|n| n.is_ascii_digit())) {
+            let start = i;
+            i = scan_number(&chars, i);
+            literals.push(chars[start..i].iter().collect());
+        } else {
+            i += 1;
+        }
+    }
+
+    literals
+}
+
+/// Skip a string literal starting at the opening quote, returning the index
+/// just past its closing quote
+pub(crate) fn skip_string(chars: &[char], start: usize, raw: bool) -> usize {
+    let quote = chars[start];
+    let triple = chars.get(start + 1) == Some(&quote) && chars.get(start + 2) == Some(&quote);
+    let mut i = start + if triple { 3 } else { 1 };
+
+    while i < chars.len() {
+        if chars[i] == '\\' && !raw {
+            i += 2;
+        } else if chars[i] == quote {
+            if !triple {
+                return i + 1;
+            }
+            if chars.get(i + 1) == Some(&quote) && chars.get(i + 2) == Some(&quote) {
+                return i + 3;
+            }
+            i += 1;
+        } else {
+            i += 1;
+        }
+    }
+    chars.len()
+}
+
+/// Scan a numeric literal starting at `start`, returning the index just past it
+pub(crate) fn scan_number(chars: &[char], start: usize) -> usize {
+    let mut i = start;
+    let digits = |i: &mut usize, pred: fn(&char) -> bool| {
+        while chars.get(*i).is_some_and(pred) {
+            *i += 1;
+        }
+    };
+
+    if chars[i] == '0' && matches!(chars.get(i + 1), Some('x') | Some('X')) {
+        i += 2;
+        digits(&mut i, char::is_ascii_hexdigit);
+    } else {
+        digits(&mut i, char::is_ascii_digit);
+        if chars.get(i) == Some(&'.') && chars.get(i + 1).is_some_and(char::is_ascii_digit) {
+            i += 1;
+            digits(&mut i, char::is_ascii_digit);
+        }
+        if matches!(chars.get(i), Some('e') | Some('E')) {
+            let mut j = i + 1;
+            if matches!(chars.get(j), Some('+') | Some('-')) {
+                j += 1;
+            }
+            if chars.get(j).is_some_and(char::is_ascii_digit) {
+                i = j;
+                digits(&mut i, char::is_ascii_digit);
+            }
+        }
+    }
+
+    if matches!(chars.get(i), Some('u') | Some('U')) {
+        i += 1;
+    }
+    i
+}
+
+/// The string argument of `duration(...)` for a duration
+pub(crate) fn duration_string(duration: Duration, style: DurationStyle) -> String {
+    let secs = duration.as_secs();
+    let fraction = fraction(duration.subsec_nanos());
+    match style {
+        DurationStyle::Seconds => format!("{}{}s", secs, fraction),
+        DurationStyle::Compact => {
+            let (hours, minutes, seconds) = (secs / 3600, secs / 60 % 60, secs % 60);
+            let mut out = String::new();
+            if hours > 0 {
+                out += &format!("{}h", hours);
+            }
+            if minutes > 0 {
+                out += &format!("{}m", minutes);
+            }
+            if seconds > 0 || !fraction.is_empty() || out.is_empty() {
+                out += &format!("{}{}s", seconds, fraction);
+            }
+            out
+        }
+    }
+}
+
+/// The argument of `timestamp(...)` for a timestamp: a quoted RFC 3339
+/// string, or with `TimestampStyle::EpochSeconds` an int when there is no
+/// fraction of a second
+pub(crate) fn timestamp_argument(timestamp: SystemTime, style: TimestampStyle) -> String {
+    let (secs, nanos) = match timestamp.duration_since(UNIX_EPOCH) {
+        Ok(after) => (after.as_secs() as i64, after.subsec_nanos()),
+        Err(before) => {
+            let before = before.duration();
+            match before.subsec_nanos() {
+                0 => (-(before.as_secs() as i64), 0),
+                nanos => (-(before.as_secs() as i64) - 1, 1_000_000_000 - nanos),
+            }
+        }
+    };
+    if style == TimestampStyle::EpochSeconds && nanos == 0 {
+        return secs.to_string();
+    }
+
+    let (year, month, day) = civil_date(secs.div_euclid(86400));
+    let time = secs.rem_euclid(86400);
+    format!(
+        "\"{:04}-{:02}-{:02}T{:02}:{:02}:{:02}{}Z\"",
+        year,
+        month,
+        day,
+        time / 3600,
+        time / 60 % 60,
+        time % 60,
+        fraction(nanos)
+    )
+}
+
+/// `.5` for half a second's nanoseconds, or nothing for none
+fn fraction(nanos: u32) -> String {
+    if nanos == 0 {
+        return String::new();
+    }
+    format!(".{:09}", nanos).trim_end_matches('0').to_string()
+}
+
+/// The year, month, and day of a count of days since 1970-01-01, in the
+/// proleptic Gregorian calendar
+fn civil_date(days: i64) -> (i64, i64, i64) {
+    // Shift to eras of 400 years starting on 0000-03-01, so leap days fall
+    // at the end of each year
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Encode bytes as standard base64, with padding
+pub(crate) fn encode_base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
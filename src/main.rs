@@ -1,9 +1,21 @@
 use clap::Parser;
+use notify::{RecursiveMode, Watcher};
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::fs;
 use std::io::{self, Read, Write};
-use std::path::PathBuf;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::Duration;
 
-use cel_fmt::{format_cel, FormatOptions};
+use cel_fmt::{
+    check_syntax, classify_change, format_cel_many_in_lines, format_cel_many_with_warnings,
+    line_changes, BoolCase, BytesLiteralStyle, ChangeKind, ClosingBracketStyle, ColonSpacing,
+    CommentStyle, DoubleExponentStyle, DurationStyle, FormatError, FormatOptions, KeyPresenceStyle,
+    LineEnding, OperatorBreak, TargetVersion, TimestampStyle,
+};
+use serde_json::json;
 
 #[derive(Parser, Debug)]
 #[command(
@@ -21,7 +33,21 @@ struct Args {
     #[arg(short = 'c', long = "check")]
     check: bool,
 
-    /// Maximum line width
+    /// Report how many lines formatting would change in each file, and in
+    /// total, without writing or printing anything else
+    #[arg(long = "stats")]
+    stats: bool,
+
+    /// With --check, say whether each change is whitespace-only or changes
+    /// tokens
+    #[arg(long = "classify-changes")]
+    classify_changes: bool,
+
+    /// Only check that the input parses, reporting errors as `path:line:col`
+    #[arg(long = "check-syntax")]
+    check_syntax: bool,
+
+    /// Maximum line width, or 0 to never wrap
     #[arg(short = 'w', long = "max-width", default_value = "80")]
     max_width: usize,
 
@@ -33,87 +59,1112 @@ struct Args {
     #[arg(long = "use-tabs")]
     use_tabs: bool,
 
+    /// Indent with this exact string instead of spaces or a tab
+    #[arg(long = "indent-string", value_name = "STRING")]
+    indent_string: Option<String>,
+
     /// Don't add trailing commas
     #[arg(long = "no-trailing-comma")]
     no_trailing_comma: bool,
 
+    /// Where to place `?` and `:` when a ternary breaks across lines
+    #[arg(long = "ternary-operator-break", value_enum, default_value = "before")]
+    ternary_operator_break: OperatorBreak,
+
+    /// Maximum consecutive blank lines kept between expressions
+    #[arg(long = "max-blank-lines", default_value = "1")]
+    max_blank_lines: usize,
+
+    /// Write numeric literals in canonical form even with
+    /// --preserve-literal-spellings
+    #[arg(long = "normalize-literals")]
+    normalize_literals: bool,
+
+    /// Keep numeric literals as spelled in the source, such as `0x10` or `1e3`
+    #[arg(long = "preserve-literal-spellings")]
+    preserve_literal_spellings: bool,
+
+    /// Spacing around the colon in map and struct entries
+    #[arg(long = "colon-spacing", value_enum, default_value = "after")]
+    colon_spacing: ColonSpacing,
+
+    /// Line up the values of map and struct entries on separate lines
+    #[arg(long = "align-values")]
+    align_values: bool,
+
+    /// Maximum nesting depth before an expression is rejected as too deep
+    #[arg(long = "max-depth", default_value = "256")]
+    max_depth: usize,
+
+    /// Reject an expression whose output would be larger than N bytes
+    #[arg(long = "max-output-bytes", value_name = "N")]
+    max_output_bytes: Option<usize>,
+
+    /// Rewrite map key presence tests to `has(m.k)` or `"k" in m`
+    #[arg(long = "key-presence-style", value_enum, default_value = "preserve")]
+    key_presence_style: KeyPresenceStyle,
+
+    /// Write `m["k"]` as `m.k` when `k` is a valid field name
+    #[arg(long = "prefer-dot-access")]
+    prefer_dot_access: bool,
+
+    /// How `//` comments are written
+    #[arg(long = "comment-style", value_enum, default_value = "preserve")]
+    comment_style: CommentStyle,
+
+    /// Always break calls with more than this many arguments
+    #[arg(long = "max-inline-args", value_name = "N")]
+    max_inline_args: Option<usize>,
+
+    /// Always break maps and structs with more than this many entries
+    #[arg(long = "break-map-threshold", value_name = "N")]
+    break_map_threshold: Option<usize>,
+
+    /// Write empty structs as `Foo {}`
+    #[arg(long = "empty-struct-space-before")]
+    empty_struct_space_before: bool,
+
+    /// Write empty structs as `Foo{ }`
+    #[arg(long = "empty-struct-space-inside")]
+    empty_struct_space_inside: bool,
+
+    /// Refuse to write output that doesn't parse back to the same expression
+    #[arg(long = "safe")]
+    safe: bool,
+
+    /// Line endings to write
+    #[arg(long = "line-ending", value_enum, default_value = "lf")]
+    line_ending: LineEnding,
+
+    /// Casing of boolean literals
+    #[arg(long = "bool-case", value_enum, default_value = "lower")]
+    bool_case: BoolCase,
+
+    /// Write `!(x in y)` as `x not in y`, for dialects with that operator
+    #[arg(long = "not-in-operator")]
+    not_in_operator: bool,
+
+    /// Write a binary operator as a word, such as `_&&_=and`, for dialects
+    /// that accept it
+    #[arg(long = "operator-alias", value_name = "OP=ALIAS", value_parser = parse_operator_alias)]
+    operator_aliases: Vec<(String, String)>,
+
+    /// Escape `/` in strings so output is safe inside HTML `<script>` tags
+    #[arg(long = "escape-forward-slash")]
+    escape_forward_slash: bool,
+
+    /// Write strings with the shortest escapes
+    #[arg(long = "minimize-escapes")]
+    minimize_escapes: bool,
+
+    /// Split string literals longer than N characters into pieces joined by `+`
+    #[arg(long = "wrap-long-strings", value_name = "N")]
+    wrap_long_strings: Option<usize>,
+
+    /// How to write bytes literals; `base64` needs the encoders extension
+    #[arg(long = "bytes-literal-style", value_enum, default_value = "escaped")]
+    bytes_literal_style: BytesLiteralStyle,
+
+    /// How to write the exponent of doubles such as `1e-3`
+    #[arg(long = "double-exponent-style", value_enum, default_value = "lower")]
+    double_exponent_style: DoubleExponentStyle,
+
+    /// How to write duration values
+    #[arg(long = "duration-style", value_enum, default_value = "compact")]
+    duration_style: DurationStyle,
+
+    /// How to write timestamp values
+    #[arg(long = "timestamp-style", value_enum, default_value = "rfc3339")]
+    timestamp_style: TimestampStyle,
+
+    /// Collapse double negations and negated comparisons, and drop redundant
+    /// boolean constants
+    #[arg(long = "simplify")]
+    simplify: bool,
+
+    /// Sort lists of constants of one type on the right of `in`
+    #[arg(long = "sort-list-elements")]
+    sort_list_elements: bool,
+
+    /// Keep parentheses around arithmetic even where they are redundant
+    #[arg(long = "preserve-arithmetic-parens")]
+    preserve_arithmetic_parens: bool,
+
+    /// The CEL grammar the output has to be accepted by
+    #[arg(long = "target-version", value_enum, default_value = "latest")]
+    target_version: TargetVersion,
+
+    /// Where the closing bracket of a broken list, map, or struct goes
+    #[arg(
+        long = "closing-bracket-style",
+        value_enum,
+        default_value = "same-column-as-open"
+    )]
+    closing_bracket_style: ClosingBracketStyle,
+
+    /// Put literals on the right of `<`, `<=`, `>`, and `>=` comparisons
+    #[arg(long = "normalize-comparisons")]
+    normalize_comparisons: bool,
+
+    /// Let method chains break before each `.call()`, one call per line
+    #[arg(long = "break-member-chains")]
+    break_member_chains: bool,
+
+    /// Keep the first call of a broken method chain on the receiver's line
+    #[arg(long = "chain-first-call-inline")]
+    chain_first_call_inline: bool,
+
+    /// Let long field paths break before each `.`, like method chains
+    #[arg(long = "break-long-selects")]
+    break_long_selects: bool,
+
+    /// Always break a root `&&`/`||` chain one operand per line, even if it fits
+    #[arg(long = "top-level-boolean-expand")]
+    top_level_boolean_expand: bool,
+
+    /// Set a `cel` parser option; the only one is `max_recursion_depth=N`
+    #[arg(long = "parser-option", value_name = "KEY=VALUE", value_parser = parse_parser_option)]
+    parser_options: Vec<ParserOption>,
+
+    /// Accept and drop one `;` at the end of each expression
+    #[arg(long = "strip-trailing-semicolon")]
+    strip_trailing_semicolon: bool,
+
+    /// Warn about expressions nested deeper than this, without failing
+    #[arg(long = "complexity-warn-depth", value_name = "DEPTH")]
+    complexity_warn_depth: Option<usize>,
+
     /// Print the formatted output (don't modify files)
     #[arg(short = 'p', long = "print")]
     print: bool,
+
+    /// Remove a leading UTF-8 byte order mark instead of preserving it
+    #[arg(long = "strip-bom")]
+    strip_bom: bool,
+
+    /// How to report the result for each file
+    #[arg(long = "reporter", value_enum, default_value = "human")]
+    reporter: Reporter,
+
+    /// Remember already-formatted files in this directory and skip them
+    #[arg(long = "cache-dir", value_name = "DIR")]
+    cache_dir: Option<PathBuf>,
+
+    /// Keep running and format files again when they change; directories are
+    /// watched for `.cel` files
+    #[arg(long = "watch")]
+    watch: bool,
+
+    /// Only format expressions on lines changed in the git index
+    #[arg(long = "staged", conflicts_with = "diff_base")]
+    staged: bool,
+
+    /// Only format expressions on lines changed since the git revision REF
+    #[arg(long = "diff-base", value_name = "REF")]
+    diff_base: Option<String>,
+}
+
+/// Output format for per-file results
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum Reporter {
+    /// Status lines such as `Would reformat: <path>`
+    Human,
+
+    /// One JSON object per file on stdout, without any file contents
+    Json,
+}
+
+/// A setting passed through to the `cel` parser with `--parser-option`
+#[derive(Debug, Clone, Copy)]
+enum ParserOption {
+    MaxRecursionDepth(u16),
+}
+
+fn parse_parser_option(arg: &str) -> Result<ParserOption, String> {
+    let (key, value) = arg
+        .split_once('=')
+        .ok_or_else(|| format!("expected KEY=VALUE, got `{}`", arg))?;
+    match key {
+        "max_recursion_depth" => value
+            .parse()
+            .map(ParserOption::MaxRecursionDepth)
+            .map_err(|e| format!("invalid max_recursion_depth `{}`: {}", value, e)),
+        _ => Err(format!(
+            "unknown parser option `{}` (supported: max_recursion_depth)",
+            key
+        )),
+    }
+}
+
+fn parse_operator_alias(arg: &str) -> Result<(String, String), String> {
+    let (op, alias) = arg
+        .split_once('=')
+        .ok_or_else(|| format!("expected OP=ALIAS, got `{}`", arg))?;
+    Ok((op.to_string(), alias.to_string()))
 }
 
-fn main() -> anyhow::Result<()> {
+/// UTF-8 byte order mark, as it appears at the start of a decoded file
+const BOM: char = '\u{feff}';
+
+fn main() {
+    // Returning the error from `main` would exit with 1, which means
+    // `EXIT_CHANGED`
+    if let Err(e) = run() {
+        eprintln!("Error: {:#}", e);
+        std::process::exit(EXIT_ERROR);
+    }
+}
+
+fn run() -> anyhow::Result<()> {
     let args = Args::parse();
 
     let options = FormatOptions::new()
         .with_max_width(args.max_width)
         .with_indent_width(args.indent_width)
-        .with_trailing_comma(!args.no_trailing_comma);
+        .with_trailing_comma(!args.no_trailing_comma)
+        .with_ternary_operator_break(args.ternary_operator_break)
+        .with_max_blank_lines(args.max_blank_lines)
+        .with_normalize_literals(args.normalize_literals)
+        .with_preserve_literal_spellings(args.preserve_literal_spellings)
+        .with_colon_spacing(args.colon_spacing)
+        .with_align_values(args.align_values)
+        .with_max_depth(args.max_depth)
+        .with_max_output_bytes(args.max_output_bytes)
+        .with_key_presence_style(args.key_presence_style)
+        .with_prefer_dot_access(args.prefer_dot_access)
+        .with_comment_style(args.comment_style)
+        .with_max_inline_args(args.max_inline_args)
+        .with_break_map_threshold(args.break_map_threshold)
+        .with_empty_struct_space_before(args.empty_struct_space_before)
+        .with_empty_struct_space_inside(args.empty_struct_space_inside)
+        .with_verify(args.safe)
+        .with_line_ending(args.line_ending)
+        .with_bool_case(args.bool_case)
+        .with_not_in_operator(args.not_in_operator)
+        .with_escape_forward_slash(args.escape_forward_slash)
+        .with_minimize_escapes(args.minimize_escapes)
+        .with_wrap_long_strings(args.wrap_long_strings)
+        .with_bytes_literal_style(args.bytes_literal_style)
+        .with_double_exponent_style(args.double_exponent_style)
+        .with_duration_style(args.duration_style)
+        .with_timestamp_style(args.timestamp_style)
+        .with_simplify(args.simplify)
+        .with_sort_list_elements(args.sort_list_elements)
+        .with_preserve_arithmetic_parens(args.preserve_arithmetic_parens)
+        .with_target_version(args.target_version)
+        .with_closing_bracket_style(args.closing_bracket_style)
+        .with_normalize_comparisons(args.normalize_comparisons)
+        .with_break_member_chains(args.break_member_chains)
+        .with_chain_first_call_inline(args.chain_first_call_inline)
+        .with_break_long_selects(args.break_long_selects)
+        .with_top_level_boolean_expand(args.top_level_boolean_expand)
+        .with_complexity_warn_depth(args.complexity_warn_depth)
+        .with_strip_trailing_semicolon(args.strip_trailing_semicolon);
 
-    let options = if args.use_tabs {
+    let mut options = if args.use_tabs {
         options.with_tabs()
     } else {
         options
     };
+    if let Some(indent) = &args.indent_string {
+        options = options.with_indent_string(indent.as_str());
+    }
+    for (op, alias) in &args.operator_aliases {
+        options = options.with_operator_alias(op.as_str(), alias.as_str());
+    }
+    for option in &args.parser_options {
+        match *option {
+            ParserOption::MaxRecursionDepth(max) => {
+                options = options.with_max_recursion_depth(Some(max));
+            }
+        }
+    }
+
+    if args.check_syntax {
+        return check_files_syntax(&args.files, &options, args.reporter);
+    }
+    if args.stats {
+        return report_stats(&args.files, &options, args.strip_bom);
+    }
+
+    if args.watch && args.files.is_empty() {
+        anyhow::bail!("--watch needs files or directories to watch");
+    }
+    let diff_base = match (args.staged, &args.diff_base) {
+        (true, _) => Some(DiffBase::Staged),
+        (false, Some(rev)) => Some(DiffBase::Rev(rev.clone())),
+        (false, None) => None,
+    };
+    if diff_base.is_some() && args.files.is_empty() {
+        anyhow::bail!("--staged and --diff-base need files to compare with git");
+    }
 
     if args.files.is_empty() {
         // Read from stdin
         let mut input = String::new();
         io::stdin().read_to_string(&mut input)?;
+        let input = input.strip_prefix(BOM).unwrap_or(&input);
 
-        match format_cel(&input, &options) {
-            Ok(formatted) => {
+        match format_cel_many_with_warnings(input, &options) {
+            Ok((formatted, warnings)) => {
+                for warning in warnings {
+                    eprintln!("Warning: {}", warning);
+                }
                 print!("{}", formatted);
                 Ok(())
             }
             Err(e) => {
                 eprintln!("Error: {}", e);
-                std::process::exit(1);
+                std::process::exit(EXIT_ERROR);
             }
         }
     } else {
         // Process files
-        let mut has_error = false;
-
-        for file_path in &args.files {
-            match process_file(file_path, &options, args.check || args.print) {
-                Ok(changed) => {
-                    if args.check && changed {
-                        println!("Would reformat: {}", file_path.display());
-                        has_error = true;
-                    } else if args.print {
-                        // Output was already printed
-                    } else if changed {
-                        println!("Formatted: {}", file_path.display());
-                    }
+        let mut summary = Summary::default();
+        // Partly formatted files aren't formatted, so they can't be cached
+        let cache_dir = args.cache_dir.as_deref().filter(|_| diff_base.is_none());
+        let mut cache = cache_dir.map(|dir| {
+            let key = format!("{:?} strip_bom={}", options, args.strip_bom);
+            Cache::load(dir, &key)
+        });
+
+        // With --watch, directories stand for the `.cel` files under them
+        let mut files = Vec::new();
+        for path in &args.files {
+            if args.watch && path.is_dir() {
+                files.extend(cel_files(path)?);
+            } else {
+                files.push(path.clone());
+            }
+        }
+        for file_path in &files {
+            let result = process_file(
+                file_path,
+                &options,
+                !(args.check || args.print),
+                print_output(&args),
+                args.strip_bom,
+                diff_base.as_ref(),
+                cache.as_mut(),
+            );
+
+            match &result {
+                Ok(Some(_)) => summary.changed += 1,
+                Ok(None) => summary.unchanged += 1,
+                Err(_) => summary.errors += 1,
+            }
+            report_file(&args, file_path, result);
+        }
+
+        if let Some(cache) = cache {
+            if let Err(e) = cache.save() {
+                eprintln!("Warning: could not write cache: {}", e);
+            }
+        }
+
+        if args.reporter == Reporter::Human {
+            eprintln!("{}", summary.describe(!args.check && !args.print));
+        }
+        if args.watch {
+            return watch(&args, &options, diff_base.as_ref());
+        }
+
+        match summary.exit_code() {
+            0 => Ok(()),
+            code => std::process::exit(code),
+        }
+    }
+}
+
+/// Whether formatted output is printed rather than only reported
+fn print_output(args: &Args) -> bool {
+    (args.check || args.print) && args.reporter == Reporter::Human
+}
+
+/// Report the outcome of `process_file` for one file
+fn report_file(args: &Args, file_path: &Path, result: anyhow::Result<Option<ChangeKind>>) {
+    match (args.reporter, result) {
+        (Reporter::Json, result) => println!("{}", json_report(file_path, &result)),
+        (Reporter::Human, Ok(change)) => {
+            if args.check && args.classify_changes && change.is_some() {
+                let kind = match change {
+                    Some(ChangeKind::Whitespace) => "whitespace only",
+                    _ => "tokens changed",
+                };
+                println!("Would reformat ({}): {}", kind, file_path.display());
+            } else if args.check && change.is_some() {
+                println!("Would reformat: {}", file_path.display());
+            } else if args.print {
+                // Output was already printed
+            } else if change.is_some() {
+                println!("Formatted: {}", file_path.display());
+            }
+        }
+        (Reporter::Human, Err(e)) => {
+            eprintln!("Error processing {}: {}", file_path.display(), e);
+        }
+    }
+}
+
+/// How long changes have to stop before watched files are formatted, so that
+/// a burst of writes from one save is handled once
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(200);
+
+/// Something `watch` waits for
+enum WatchEvent {
+    Change(notify::Result<notify::Event>),
+    Interrupt,
+}
+
+/// Watch `args.files` and format each file again when it changes, until
+/// Ctrl-C
+///
+/// The parent directory of each file is watched rather than the file, since
+/// editors often save by replacing the file. A file that is deleted is
+/// reported and picked up again if it comes back. Files whose contents are
+/// the same as when last formatted, such as after being written by this
+/// loop, are skipped.
+fn watch(args: &Args, options: &FormatOptions, diff_base: Option<&DiffBase>) -> anyhow::Result<()> {
+    let (sender, receiver) = mpsc::channel();
+    let interrupt = sender.clone();
+    ctrlc::set_handler(move || {
+        let _ = interrupt.send(WatchEvent::Interrupt);
+    })?;
+    let mut watcher = notify::recommended_watcher(move |event| {
+        let _ = sender.send(WatchEvent::Change(event));
+    })?;
+
+    let mut watched = WatchedPaths::default();
+    for path in &args.files {
+        if path.is_dir() {
+            let dir = path.canonicalize()?;
+            watcher.watch(&dir, RecursiveMode::Recursive)?;
+            watched.dirs.push(dir);
+        } else {
+            let name = path
+                .file_name()
+                .ok_or_else(|| anyhow::anyhow!("Can't watch {}", path.display()))?;
+            let parent = match path.parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => parent,
+                _ => Path::new("."),
+            };
+            let parent = parent.canonicalize()?;
+            watcher.watch(&parent, RecursiveMode::NonRecursive)?;
+            watched.files.insert(parent.join(name), path.clone());
+        }
+    }
+    eprintln!("Watching for changes; press Ctrl-C to stop");
+
+    let mut last_seen: HashMap<PathBuf, String> = HashMap::new();
+    loop {
+        let mut changed = BTreeSet::new();
+        let mut event = receiver.recv()?;
+        loop {
+            match event {
+                WatchEvent::Interrupt => {
+                    eprintln!("Stopped watching");
+                    return Ok(());
                 }
-                Err(e) => {
-                    eprintln!("Error processing {}: {}", file_path.display(), e);
-                    has_error = true;
+                WatchEvent::Change(Ok(event)) => changed.extend(
+                    event
+                        .paths
+                        .iter()
+                        .filter_map(|path| watched.report_path(path)),
+                ),
+                WatchEvent::Change(Err(e)) => eprintln!("Watch error: {}", e),
+            }
+            event = match receiver.recv_timeout(WATCH_DEBOUNCE) {
+                Ok(event) => event,
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => return Ok(()),
+            };
+        }
+
+        for path in changed {
+            match watch_action(&mut last_seen, &path) {
+                WatchAction::Format => {}
+                WatchAction::Skip => continue,
+                WatchAction::Removed => {
+                    println!("Removed: {}", path.display());
+                    continue;
                 }
             }
+
+            let result = process_file(
+                &path,
+                options,
+                !(args.check || args.print),
+                print_output(args),
+                args.strip_bom,
+                diff_base,
+                None,
+            );
+            report_file(args, &path, result);
+            if let Ok(content) = fs::read_to_string(&path) {
+                last_seen.insert(path, content);
+            }
         }
+    }
+}
 
-        if has_error {
-            std::process::exit(1);
+/// The paths `watch` reacts to
+#[derive(Default)]
+struct WatchedPaths {
+    /// Watched files by absolute path, with the path to report them by
+    files: HashMap<PathBuf, PathBuf>,
+    /// Absolute paths of directories watched for `.cel` files
+    dirs: Vec<PathBuf>,
+}
+
+impl WatchedPaths {
+    /// The path to report a changed absolute `path` by, or `None` if it isn't
+    /// watched
+    fn report_path(&self, path: &Path) -> Option<PathBuf> {
+        self.files.get(path).cloned().or_else(|| {
+            let in_dir = self.dirs.iter().any(|dir| path.starts_with(dir));
+            (in_dir && is_cel_file(path)).then(|| path.to_path_buf())
+        })
+    }
+}
+
+/// What `watch` does with a file that changed
+#[derive(Debug, PartialEq)]
+enum WatchAction {
+    Format,
+    /// Nothing to do, such as when the contents are the same as when last
+    /// formatted
+    Skip,
+    Removed,
+}
+
+/// Decide what to do with a changed `path`, forgetting it in `last_seen` if
+/// it can no longer be read
+fn watch_action(last_seen: &mut HashMap<PathBuf, String>, path: &Path) -> WatchAction {
+    match fs::read_to_string(path) {
+        Ok(content) if last_seen.get(path) == Some(&content) => WatchAction::Skip,
+        Ok(_) => WatchAction::Format,
+        Err(_) if last_seen.remove(path).is_some() || !path.exists() => WatchAction::Removed,
+        Err(_) => WatchAction::Skip,
+    }
+}
+
+fn is_cel_file(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "cel")
+}
+
+/// The `.cel` files under `dir`, recursively, in sorted order
+fn cel_files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            files.extend(cel_files(&path)?);
+        } else if is_cel_file(&path) {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Report the lines formatting would add and remove in each changed file and
+/// in total, exiting with `EXIT_ERROR` if any file can't be formatted
+///
+/// Files are only read, never written, and nothing but the counts is printed.
+fn report_stats(files: &[PathBuf], options: &FormatOptions, strip_bom: bool) -> anyhow::Result<()> {
+    let mut summary = Summary::default();
+    let (mut added, mut removed) = (0, 0);
+
+    for path in files {
+        let result = fs::read_to_string(path)
+            .map_err(anyhow::Error::from)
+            .and_then(|content| {
+                let (_, output) = format_contents(path, &content, options, strip_bom, None)?;
+                Ok((content != output).then(|| line_changes(&content, &output)))
+            });
+
+        match result {
+            Ok(Some(changes)) => {
+                summary.changed += 1;
+                added += changes.added;
+                removed += changes.removed;
+                println!(
+                    "{}: +{} -{}",
+                    path.display(),
+                    changes.added,
+                    changes.removed
+                );
+            }
+            Ok(None) => summary.unchanged += 1,
+            Err(e) => {
+                summary.errors += 1;
+                eprintln!("Error processing {}: {}", path.display(), e);
+            }
         }
+    }
 
-        Ok(())
+    println!(
+        "{}; {} lines added, {} removed",
+        summary.describe(false),
+        added,
+        removed
+    );
+    if summary.errors > 0 {
+        std::process::exit(EXIT_ERROR);
     }
+    Ok(())
 }
 
-fn process_file(path: &PathBuf, options: &FormatOptions, dry_run: bool) -> anyhow::Result<bool> {
+/// Check that each file, or stdin without files, parses, exiting with
+/// `EXIT_ERROR` if any doesn't
+///
+/// Files are only read, never formatted or written.
+fn check_files_syntax(
+    files: &[PathBuf],
+    options: &FormatOptions,
+    reporter: Reporter,
+) -> anyhow::Result<()> {
+    let check = |content: &str| check_syntax(content.strip_prefix(BOM).unwrap_or(content), options);
+
+    let results = if files.is_empty() {
+        let mut input = String::new();
+        io::stdin().read_to_string(&mut input)?;
+        vec![(PathBuf::from("<stdin>"), check(&input))]
+    } else {
+        files
+            .iter()
+            .map(|path| {
+                let result = fs::read_to_string(path)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|content| check(&content));
+                (path.clone(), result)
+            })
+            .collect()
+    };
+
+    let mut failed = false;
+    for (path, result) in results {
+        failed |= result.is_err();
+        match (reporter, result) {
+            (Reporter::Json, result) => {
+                println!("{}", json_report(&path, &result.map(|()| None)))
+            }
+            (Reporter::Human, Ok(())) => {}
+            (Reporter::Human, Err(e)) => eprintln!("{}", syntax_error(&path, &e)),
+        }
+    }
+
+    if failed {
+        std::process::exit(EXIT_ERROR);
+    }
+    Ok(())
+}
+
+/// Describe an error as `path:line:col: message`, or `path: message` when it
+/// has no position
+fn syntax_error(path: &Path, error: &anyhow::Error) -> String {
+    match error.downcast_ref::<FormatError>() {
+        Some(FormatError::Parse {
+            line,
+            column,
+            message,
+        }) => format!("{}:{}:{}: {}", path.display(), line, column, message),
+        _ => format!("{}: {}", path.display(), error),
+    }
+}
+
+/// Exit code when some file was (or, without writing, would be) reformatted
+const EXIT_CHANGED: i32 = 1;
+
+/// Exit code when some input couldn't be formatted; takes precedence over
+/// `EXIT_CHANGED`
+const EXIT_ERROR: i32 = 2;
+
+/// Per-run counts of file outcomes, for the final summary line
+#[derive(Default)]
+struct Summary {
+    changed: usize,
+    unchanged: usize,
+    errors: usize,
+}
+
+impl Summary {
+    /// Describe the counts, e.g. `3 files reformatted, 12 unchanged, 1 error`
+    fn describe(&self, written: bool) -> String {
+        let files = if self.changed == 1 { "file" } else { "files" };
+        let verb = if written {
+            "reformatted"
+        } else {
+            "would be reformatted"
+        };
+        let errors = if self.errors == 1 { "error" } else { "errors" };
+        format!(
+            "{} {} {}, {} unchanged, {} {}",
+            self.changed, files, verb, self.unchanged, self.errors, errors
+        )
+    }
+
+    fn exit_code(&self) -> i32 {
+        if self.errors > 0 {
+            EXIT_ERROR
+        } else if self.changed > 0 {
+            EXIT_CHANGED
+        } else {
+            0
+        }
+    }
+}
+
+/// Format a single file, returning how its contents would change, if at all.
+///
+/// The file is only rewritten when `write` is set, and the formatted text is
+/// only printed when `print` is set. A leading BOM is stripped before parsing
+/// and re-emitted on write unless `strip_bom` is set, so BOM-prefixed files
+/// compare equal once formatted. Printed output never carries the BOM.
+///
+/// Files whose contents are in `cache` are known to be formatted already and
+/// aren't parsed again; files that end up formatted are added to it.
+fn process_file(
+    path: &PathBuf,
+    options: &FormatOptions,
+    write: bool,
+    print: bool,
+    strip_bom: bool,
+    diff_base: Option<&DiffBase>,
+    mut cache: Option<&mut Cache>,
+) -> anyhow::Result<Option<ChangeKind>> {
     let content = fs::read_to_string(path)?;
-    let formatted = format_cel(&content, options)?;
+    let ranges = match diff_base {
+        Some(base) => changed_lines(path, base)?,
+        None => None,
+    };
 
-    if dry_run {
+    if cache.as_ref().is_some_and(|cache| cache.contains(&content)) {
+        // A formatted file formats to itself
+        if print {
+            let source = content.strip_prefix(BOM).unwrap_or(&content);
+            io::stdout().write_all(source.as_bytes())?;
+        }
+        return Ok(None);
+    }
+
+    let (formatted, output) =
+        format_contents(path, &content, options, strip_bom, ranges.as_deref())?;
+    let change = classify_change(&content, &output);
+    let changed = change.is_some();
+
+    if print {
         io::stdout().write_all(formatted.as_bytes())?;
-        Ok(content != formatted)
+    }
+    if write && changed {
+        fs::write(path, &output)?;
+    }
+
+    if let Some(cache) = cache.as_mut() {
+        if !changed || write {
+            cache.insert(&output);
+        }
+    }
+    Ok(change)
+}
+
+/// Format a file's contents, returning the formatted text and the contents to
+/// write back, which keep a leading BOM unless `strip_bom` is set
+///
+/// With `ranges`, only expressions on those lines are formatted. Warnings are
+/// printed to stderr.
+fn format_contents(
+    path: &Path,
+    content: &str,
+    options: &FormatOptions,
+    strip_bom: bool,
+    ranges: Option<&[Range<usize>]>,
+) -> anyhow::Result<(String, String)> {
+    let (has_bom, source) = match content.strip_prefix(BOM) {
+        Some(rest) => (true, rest),
+        None => (false, content),
+    };
+
+    let (formatted, warnings) = match ranges {
+        Some(ranges) => format_cel_many_in_lines(source, options, ranges)?,
+        None => format_cel_many_with_warnings(source, options)?,
+    };
+    for warning in warnings {
+        eprintln!("Warning: {}: {}", path.display(), warning);
+    }
+
+    let output = if has_bom && !strip_bom {
+        format!("{}{}", BOM, formatted)
     } else {
-        let changed = content != formatted;
-        if changed {
-            fs::write(path, formatted)?;
+        formatted.clone()
+    };
+    Ok((formatted, output))
+}
+
+/// What `--staged` and `--diff-base` compare files with
+enum DiffBase {
+    /// The file's lines in the git index, against `HEAD`
+    Staged,
+
+    /// The file in the working tree, against a revision
+    Rev(String),
+}
+
+/// The lines of a file that changed since `base`, from `git diff`, or `None`
+/// if git doesn't track the file and all of it is new
+///
+/// Each hunk header `@@ -a,b +c,d @@` of a diff without context marks lines
+/// `c..c + d` of the new file as changed. A hunk that only removes lines
+/// (`d` is 0) marks line `c`, the line before the removal, so the expression
+/// the lines were taken out of is formatted. With `Staged`, the line numbers
+/// are those of the staged file, which only match the working tree when the
+/// file has no unstaged changes, so a file with some is refused.
+///
+/// Files outside any git repository count as untracked.
+fn changed_lines(path: &Path, base: &DiffBase) -> anyhow::Result<Option<Vec<Range<usize>>>> {
+    let dir = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let name = path
+        .file_name()
+        .ok_or_else(|| anyhow::anyhow!("Not a file: {}", path.display()))?;
+    let git = |args: &[&str]| {
+        Command::new("git")
+            .arg("-C")
+            .arg(dir)
+            .args(args)
+            .arg("--")
+            .arg(name)
+            .output()
+            .map_err(|e| anyhow::anyhow!("Could not run git: {}", e))
+    };
+
+    if !git(&["ls-files", "--error-unmatch"])?.status.success() {
+        return Ok(None);
+    }
+    if let DiffBase::Staged = base {
+        let unstaged = git(&["diff", "--quiet", "--no-ext-diff"])?;
+        match unstaged.status.code() {
+            Some(0) => {}
+            Some(1) => anyhow::bail!(
+                "{} has unstaged changes; stage or stash them to use --staged",
+                path.display()
+            ),
+            _ => anyhow::bail!(
+                "git diff failed: {}",
+                String::from_utf8_lossy(&unstaged.stderr).trim()
+            ),
+        }
+    }
+    let diff = match base {
+        DiffBase::Staged => git(&["diff", "--no-color", "--no-ext-diff", "-U0", "--cached"])?,
+        DiffBase::Rev(rev) => git(&["diff", "--no-color", "--no-ext-diff", "-U0", rev])?,
+    };
+    if !diff.status.success() {
+        anyhow::bail!(
+            "git diff failed: {}",
+            String::from_utf8_lossy(&diff.stderr).trim()
+        );
+    }
+
+    let mut ranges = Vec::new();
+    for line in String::from_utf8_lossy(&diff.stdout).lines() {
+        let Some(hunk) = line.strip_prefix("@@ ") else {
+            continue;
+        };
+        let Some(new) = hunk
+            .split_whitespace()
+            .find_map(|part| part.strip_prefix('+'))
+        else {
+            continue;
+        };
+        let (start, count) = match new.split_once(',') {
+            Some((start, count)) => (start.parse::<usize>()?, count.parse::<usize>()?),
+            None => (new.parse::<usize>()?, 1),
+        };
+        ranges.push(if count == 0 {
+            start.max(1)..start.max(1) + 1
+        } else {
+            start..start + count
+        });
+    }
+    Ok(Some(ranges))
+}
+
+/// Name of the cache file inside `--cache-dir`
+const CACHE_FILE: &str = "cel-fmt-cache";
+
+/// Hashes of file contents that are already formatted, for `--cache-dir`
+///
+/// The cache file is plain text. Its first line is `cel-fmt <version> <key>`,
+/// where `<key>` is a hash of the formatting options. Every following line is
+/// the 16-digit hex hash of a formatted file's contents. When the first line
+/// doesn't match the current version and options, the whole cache is ignored
+/// and rewritten.
+struct Cache {
+    path: PathBuf,
+    header: String,
+    clean: HashSet<u64>,
+    dirty: bool,
+}
+
+impl Cache {
+    /// Load the cache in `dir` for a run with the given options key
+    fn load(dir: &Path, key: &str) -> Self {
+        let path = dir.join(CACHE_FILE);
+        let header = format!(
+            "cel-fmt {} {:016x}",
+            env!("CARGO_PKG_VERSION"),
+            stable_hash(key.as_bytes())
+        );
+
+        let mut clean = HashSet::new();
+        if let Ok(text) = fs::read_to_string(&path) {
+            let mut lines = text.lines();
+            if lines.next() == Some(header.as_str()) {
+                clean.extend(lines.filter_map(|line| u64::from_str_radix(line, 16).ok()));
+            }
         }
-        Ok(changed)
+
+        Self {
+            path,
+            header,
+            clean,
+            dirty: false,
+        }
+    }
+
+    fn contains(&self, content: &str) -> bool {
+        self.clean.contains(&stable_hash(content.as_bytes()))
+    }
+
+    fn insert(&mut self, content: &str) {
+        self.dirty |= self.clean.insert(stable_hash(content.as_bytes()));
+    }
+
+    /// Write the cache back if anything was added
+    fn save(&self) -> io::Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let mut text = self.header.clone();
+        for hash in &self.clean {
+            text.push_str(&format!("\n{:016x}", hash));
+        }
+        text.push('\n');
+        fs::write(&self.path, text)
+    }
+}
+
+/// 64-bit FNV-1a, which unlike `DefaultHasher` is stable across Rust versions
+fn stable_hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Describe one file's result as a JSON object for `--reporter json`
+///
+/// `change` is `"whitespace"` or `"tokens"` for a changed file, as in
+/// `--classify-changes`, and `null` otherwise. Parse errors also carry the
+/// 1-based `line` and `column` of the error; both are `null` otherwise.
+fn json_report(path: &Path, result: &anyhow::Result<Option<ChangeKind>>) -> serde_json::Value {
+    let (change, error) = match result {
+        Ok(change) => (*change, None),
+        Err(e) => (None, Some(e)),
+    };
+    let position = error.and_then(|e| match e.downcast_ref::<FormatError>() {
+        Some(FormatError::Parse { line, column, .. }) => Some((*line, *column)),
+        _ => None,
+    });
+
+    json!({
+        "path": path.display().to_string(),
+        "changed": change.is_some(),
+        "change": change.map(|kind| match kind {
+            ChangeKind::Whitespace => "whitespace",
+            ChangeKind::Tokens => "tokens",
+        }),
+        "error": error.map(|e| e.to_string()),
+        "line": position.map(|(line, _)| line),
+        "column": position.map(|(_, column)| column),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("cel-fmt-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("nested")).unwrap();
+        dir.canonicalize().unwrap()
+    }
+
+    #[test]
+    fn test_cel_files() {
+        let dir = temp_dir("cel-files");
+        for name in ["b.cel", "a.cel", "notes.txt", "nested/c.cel"] {
+            fs::write(dir.join(name), "1").unwrap();
+        }
+        let files = cel_files(&dir).unwrap();
+        assert_eq!(
+            files,
+            [
+                dir.join("a.cel"),
+                dir.join("b.cel"),
+                dir.join("nested/c.cel")
+            ]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_watched_report_path() {
+        let dir = temp_dir("watched");
+        let mut watched = WatchedPaths::default();
+        watched
+            .files
+            .insert(dir.join("rule.txt"), PathBuf::from("rule.txt"));
+        watched.dirs.push(dir.join("nested"));
+
+        assert_eq!(
+            watched.report_path(&dir.join("rule.txt")),
+            Some(PathBuf::from("rule.txt"))
+        );
+        assert_eq!(watched.report_path(&dir.join("other.cel")), None);
+        assert_eq!(
+            watched.report_path(&dir.join("nested/a.cel")),
+            Some(dir.join("nested/a.cel"))
+        );
+        assert_eq!(watched.report_path(&dir.join("nested/a.cel.swp")), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_watch_action() {
+        let dir = temp_dir("watch-action");
+        let path = dir.join("a.cel");
+        let mut last_seen = HashMap::new();
+
+        fs::write(&path, "1 + 2").unwrap();
+        assert_eq!(watch_action(&mut last_seen, &path), WatchAction::Format);
+        last_seen.insert(path.clone(), "1 + 2".to_string());
+        assert_eq!(watch_action(&mut last_seen, &path), WatchAction::Skip);
+        fs::write(&path, "1+2").unwrap();
+        assert_eq!(watch_action(&mut last_seen, &path), WatchAction::Format);
+
+        fs::remove_file(&path).unwrap();
+        assert_eq!(watch_action(&mut last_seen, &path), WatchAction::Removed);
+        assert!(last_seen.is_empty());
+        // A directory can't be read but isn't removed
+        assert_eq!(watch_action(&mut last_seen, &dir), WatchAction::Skip);
+        fs::remove_dir_all(&dir).unwrap();
     }
 }
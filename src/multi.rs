@@ -0,0 +1,457 @@
+use crate::comments::format_comment;
+use crate::depth::check_depth;
+use crate::error::{FormatError, FormatWarning};
+use crate::formatter::{
+    apply_line_ending, complexity_warning, format_cel, format_expression, new_parser,
+    strip_semicolon,
+};
+use crate::options::FormatOptions;
+use std::ops::Range;
+
+/// Format a source containing several CEL expressions, one after another
+///
+/// Lines are only split into separate expressions where the source can't be
+/// read as one: a blank line ends an expression unless what follows it only
+/// parses together with what came before, and within a run of lines an
+/// expression ends just before the line where parsing fails. So `a\n- b` is
+/// the single expression `a - b`, as the parser reads it. Full-line `//`
+/// comments between expressions are kept verbatim, and runs of blank lines
+/// between them are collapsed to at most `options.max_blank_lines`. Lines are
+/// separated according to `options.line_ending`.
+pub fn format_cel_many(source: &str, options: &FormatOptions) -> anyhow::Result<String> {
+    format_cel_many_with_warnings(source, options).map(|(output, _)| output)
+}
+
+/// Format several CEL expressions like `format_cel_many`, also returning
+/// warnings about them
+///
+/// See `format_cel_with_warnings`; each warning carries the line its
+/// expression starts on.
+pub fn format_cel_many_with_warnings(
+    source: &str,
+    options: &FormatOptions,
+) -> anyhow::Result<(String, Vec<FormatWarning>)> {
+    let lines: Vec<&str> = source.lines().collect();
+    let mut output: Vec<String> = Vec::new();
+    let mut warnings = Vec::new();
+    let mut blank_run = 0;
+    let mut start = 0;
+
+    while start < lines.len() {
+        let line = lines[start].trim();
+        if line.is_empty() {
+            blank_run += 1;
+            start += 1;
+            continue;
+        }
+
+        // Blank lines are only kept between items, never leading or trailing
+        if !output.is_empty() {
+            for _ in 0..blank_run.min(options.max_blank_lines) {
+                output.push(String::new());
+            }
+        }
+        blank_run = 0;
+
+        if is_comment(line) {
+            output.push(format_comment(line, options));
+            start += 1;
+            continue;
+        }
+
+        let end = expression_end(&lines, start, options).map_err(|e| offset_error(e, start))?;
+        let expression = lines[start..end].join("\n");
+        let formatted =
+            format_expression(&expression, options).map_err(|e| offset_error(e, start))?;
+        warnings.extend(complexity_warning(&expression, start + 1, options));
+        output.push(formatted);
+        start = end;
+    }
+
+    let output = apply_line_ending(output.join("\n"), source, options);
+    Ok((output, warnings))
+}
+
+/// Format only the expressions of a source that overlap the given lines,
+/// returning the output with warnings about the formatted expressions
+///
+/// The source is split into expressions as in `format_cel_many`. Lines are
+/// numbered from 1 and ranges exclude their end, so `3..5` is lines 3 and 4.
+/// The parser doesn't keep positions inside an expression, so an expression
+/// is formatted whole if any of its lines is in a range. Everything else,
+/// including comments, blank lines, and line endings, is kept as it is.
+pub fn format_cel_many_in_lines(
+    source: &str,
+    options: &FormatOptions,
+    ranges: &[Range<usize>],
+) -> anyhow::Result<(String, Vec<FormatWarning>)> {
+    let lines: Vec<&str> = source.lines().collect();
+    // The same lines with their line endings
+    let raw_lines: Vec<&str> = source.split_inclusive('\n').collect();
+    let mut output = String::with_capacity(source.len());
+    let mut warnings = Vec::new();
+    let mut start = 0;
+
+    while start < lines.len() {
+        if is_blank_or_comment(lines[start]) {
+            output.push_str(raw_lines[start]);
+            start += 1;
+            continue;
+        }
+
+        let end = expression_end(&lines, start, options).map_err(|e| offset_error(e, start))?;
+        let touched = ranges
+            .iter()
+            .any(|range| range.start <= end && range.end > start + 1);
+        if !touched {
+            output.extend(raw_lines[start..end].iter().copied());
+            start = end;
+            continue;
+        }
+
+        let expression = lines[start..end].join("\n");
+        let formatted =
+            format_expression(&expression, options).map_err(|e| offset_error(e, start))?;
+        warnings.extend(complexity_warning(&expression, start + 1, options));
+        let ending = &raw_lines[end - 1][lines[end - 1].len()..];
+        if ending == "\r\n" {
+            output.push_str(&formatted.replace('\n', "\r\n"));
+        } else {
+            output.push_str(&formatted);
+        }
+        output.push_str(ending);
+        start = end;
+    }
+
+    Ok((output, warnings))
+}
+
+/// Format independent CEL expressions, returning a result for each
+///
+/// Each source is formatted on its own, as by `format_cel`, so an error in one
+/// doesn't stop the others and its position is within that source. The `cel`
+/// parser is consumed by each parse, so there is no parser to share between
+/// them; options are `Sync`, so a large batch can be split across threads.
+pub fn format_all(sources: &[&str], options: &FormatOptions) -> Vec<Result<String, FormatError>> {
+    sources
+        .iter()
+        .map(|source| {
+            format_cel(source, options).map_err(|error| {
+                error
+                    .downcast::<FormatError>()
+                    .unwrap_or_else(|error| FormatError::Other(error.to_string()))
+            })
+        })
+        .collect()
+}
+
+/// Check that a source of CEL expressions, split as in `format_cel_many`,
+/// parses, without formatting it
+///
+/// The error for the first expression that doesn't parse is positioned in the
+/// whole source.
+pub fn check_syntax(source: &str, options: &FormatOptions) -> anyhow::Result<()> {
+    let lines: Vec<&str> = source.lines().collect();
+    let mut start = 0;
+    while start < lines.len() {
+        if is_blank_or_comment(lines[start]) {
+            start += 1;
+            continue;
+        }
+        start = expression_end(&lines, start, options).map_err(|e| offset_error(e, start))?;
+    }
+    Ok(())
+}
+
+/// Find the exclusive end line of the expression starting at `start`
+///
+/// The lines up to the next blank line are parsed as one expression. If they
+/// don't parse, the expression is the longest run of them, ending at or before
+/// the line the parser stopped at, that does; if none does, the lines after
+/// the blank line are added and tried again. Each line is parsed a bounded
+/// number of times, rather than once for every prefix.
+fn expression_end(lines: &[&str], start: usize, options: &FormatOptions) -> anyhow::Result<usize> {
+    let parses = |end: usize| {
+        new_parser(options)
+            .parse(strip_semicolon(&lines[start..end].join("\n"), options))
+            .map_err(|e| FormatError::from_parse_errors(&lines[start..end].join("\n"), &e))
+    };
+    let next_blank = |from: usize| {
+        (from..lines.len())
+            .find(|&i| lines[i].trim().is_empty())
+            .unwrap_or(lines.len())
+    };
+
+    let mut end = next_blank(start);
+    loop {
+        check_depth(&lines[start..end].join("\n"), options)?;
+        let error_line = match parses(end) {
+            Ok(_) => return Ok(trim_end(lines, start, end)),
+            Err(FormatError::Parse { line, .. }) => start + line,
+            Err(error) => return Err(error.into()),
+        };
+
+        let candidates = (start + 1..=error_line.min(end - 1)).rev();
+        for candidate in candidates.filter(|&i| !is_blank_or_comment(lines[i - 1])) {
+            if parses(candidate).is_ok() {
+                return Ok(candidate);
+            }
+        }
+
+        if end == lines.len() {
+            // Nothing parses, so report the error for the remaining input
+            return parses(end).map(|_| end).map_err(Into::into);
+        }
+        let after_blanks = (end..lines.len())
+            .find(|&i| !lines[i].trim().is_empty())
+            .unwrap_or(lines.len());
+        end = next_blank(after_blanks);
+    }
+}
+
+/// Drop the comment lines at the end of an expression's lines, which belong
+/// between expressions
+fn trim_end(lines: &[&str], start: usize, mut end: usize) -> usize {
+    while end > start + 1 && is_blank_or_comment(lines[end - 1]) {
+        end -= 1;
+    }
+    end
+}
+
+/// Shift a parse error's line from the expression's first line to the file's
+fn offset_error(error: anyhow::Error, start: usize) -> anyhow::Error {
+    match error.downcast::<FormatError>() {
+        Ok(FormatError::Parse {
+            line,
+            column,
+            message,
+        }) => FormatError::Parse {
+            line: line + start,
+            column,
+            message,
+        }
+        .into(),
+        Ok(other) => other.into(),
+        Err(error) => error,
+    }
+}
+
+/// Check if a trimmed line is a full-line comment
+fn is_comment(line: &str) -> bool {
+    line.starts_with("//")
+}
+
+/// Check if a line holds no code
+fn is_blank_or_comment(line: &str) -> bool {
+    let line = line.trim();
+    line.is_empty() || is_comment(line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::options::{CommentStyle, LineEnding};
+
+    #[test]
+    fn test_one_expression_per_line() {
+        let options = FormatOptions::default();
+        assert_eq!(
+            format_cel_many("1+2\n3*4\n[1,2]", &options).unwrap(),
+            "1 + 2\n3 * 4\n[1, 2]"
+        );
+    }
+
+    #[test]
+    fn test_multi_line_expressions_and_comments() {
+        let source = "// Adults only\nuser.age>=18 &&\n  user.active\n\nlist\n  .size()\n";
+        let options = FormatOptions::default();
+        assert_eq!(
+            format_cel_many(source, &options).unwrap(),
+            "// Adults only\nuser.age >= 18 && user.active\n\nlist.size()"
+        );
+    }
+
+    #[test]
+    fn test_max_blank_lines() {
+        let source = "\n\nx>5\n\n\ny<10\n\n";
+
+        let options = FormatOptions::default();
+        assert_eq!(
+            format_cel_many(source, &options).unwrap(),
+            "x > 5\n\ny < 10"
+        );
+
+        let options = options.with_max_blank_lines(2);
+        assert_eq!(
+            format_cel_many(source, &options).unwrap(),
+            "x > 5\n\n\ny < 10"
+        );
+
+        let options = options.with_max_blank_lines(0);
+        assert_eq!(format_cel_many(source, &options).unwrap(), "x > 5\ny < 10");
+    }
+
+    #[test]
+    fn test_ambiguous_line_starts() {
+        let options = FormatOptions::default();
+        // Lines are joined as the parser reads them, not split at a line that
+        // could start an expression of its own
+        assert_eq!(format_cel_many("a\n- b", &options).unwrap(), "a - b");
+        assert_eq!(format_cel_many("f\n(x)", &options).unwrap(), "f(x)");
+        assert_eq!(format_cel_many("a\n\n-b", &options).unwrap(), "a\n\n-b");
+        assert_eq!(
+            format_cel_many("{'a': 1,\n\n 'b': 2}\nc", &options).unwrap(),
+            "{\"a\": 1, \"b\": 2}\nc"
+        );
+
+        // Formatting the formatter's own broken chains gives the same output
+        let options = options.with_max_width(20);
+        let chain = (0..8)
+            .map(|i| format!("x{}", i))
+            .collect::<Vec<_>>()
+            .join(" - ");
+        let formatted = format_cel_many(&chain, &options).unwrap();
+        assert!(formatted.contains("\n"), "{}", formatted);
+        assert_eq!(format_cel_many(&formatted, &options).unwrap(), formatted);
+    }
+
+    #[test]
+    fn test_parse_error() {
+        let options = FormatOptions::default();
+        assert!(format_cel_many("x > 5\na +\n", &options).is_err());
+    }
+
+    #[test]
+    fn test_parse_error_position() {
+        let options = FormatOptions::default();
+        let err = format_cel_many("x > 5\n\n// next\nf(a,\n  , b)\n", &options).unwrap_err();
+        match err.downcast_ref::<FormatError>() {
+            Some(FormatError::Parse { line, column, .. }) => assert_eq!((*line, *column), (5, 3)),
+            other => panic!("expected a parse error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_line_endings() {
+        let source = "// Check\r\nx>5\r\n\r\ny<10\r\n";
+        let options = FormatOptions::default().with_line_ending(LineEnding::Auto);
+        assert_eq!(
+            format_cel_many(source, &options).unwrap(),
+            "// Check\r\nx > 5\r\n\r\ny < 10"
+        );
+    }
+
+    #[test]
+    fn test_complexity_warnings() {
+        let source = "x\n\n// nested\nf(g(\n  h(x)))\n";
+        let options = FormatOptions::default().with_complexity_warn_depth(Some(2));
+        let (output, warnings) = format_cel_many_with_warnings(source, &options).unwrap();
+        assert_eq!(output, "x\n\n// nested\nf(g(h(x)))");
+        assert_eq!(
+            warnings,
+            [FormatWarning::TooComplex {
+                line: 4,
+                depth: 4,
+                limit: 2,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_check_syntax() {
+        let options = FormatOptions::default();
+        assert!(check_syntax("// ok\nx > 5\n\na &&\n  b\n", &options).is_ok());
+        assert!(check_syntax("", &options).is_ok());
+
+        let err = check_syntax("x > 5\n\nf(a,\n  , b)\n", &options).unwrap_err();
+        match err.downcast_ref::<FormatError>() {
+            Some(FormatError::Parse { line, column, .. }) => assert_eq!((*line, *column), (4, 3)),
+            other => panic!("expected a parse error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_strip_trailing_semicolon() {
+        let options = FormatOptions::default().with_strip_trailing_semicolon(true);
+        assert_eq!(
+            format_cel_many("x>5;\na &&\n  b;\n", &options).unwrap(),
+            "x > 5\na && b"
+        );
+        assert!(check_syntax("x>5;\ny;;\n", &options).is_err());
+    }
+
+    #[test]
+    fn test_comment_style() {
+        let options = FormatOptions::default().with_comment_style(CommentStyle::Normalize);
+        assert_eq!(
+            format_cel_many("//  first\n1+2\n//second\n3", &options).unwrap(),
+            "// first\n1 + 2\n// second\n3"
+        );
+    }
+
+    #[test]
+    fn test_format_all() {
+        let options = FormatOptions::default().with_max_depth(5);
+        let deep = "f(f(f(f(f(f(x))))))";
+        let results = format_all(&["a&&b", "a +", "[1,2]", "\n  x ==\n  )", deep], &options);
+
+        assert_eq!(results[0], Ok("a && b".to_string()));
+        assert!(matches!(
+            results[1],
+            Err(FormatError::Parse {
+                line: 1,
+                column: 4,
+                ..
+            })
+        ));
+        assert_eq!(results[2], Ok("[1, 2]".to_string()));
+        assert!(matches!(
+            results[3],
+            Err(FormatError::Parse {
+                line: 3,
+                column: 3,
+                ..
+            })
+        ));
+        assert!(matches!(
+            results[4],
+            Err(FormatError::TooDeep { limit: 5, .. })
+        ));
+        assert!(format_all(&[], &options).is_empty());
+    }
+
+    #[test]
+    fn test_format_in_lines() {
+        let options = FormatOptions::default();
+        let source = "a+b\n\n\n// keep  \nx &&\n  y\n[1,2]\r\n{'k':1}\n";
+        // Each range as (start, end), lines numbered from 1
+        let format = |ranges: &[(usize, usize)]| {
+            let ranges: Vec<Range<usize>> = ranges.iter().map(|&(start, end)| start..end).collect();
+            format_cel_many_in_lines(source, &options, &ranges)
+                .unwrap()
+                .0
+        };
+
+        // Only expressions with a line in a range change
+        assert_eq!(format(&[]), source);
+        assert_eq!(
+            format(&[(1, 2)]),
+            "a + b\n\n\n// keep  \nx &&\n  y\n[1,2]\r\n{'k':1}\n"
+        );
+        assert_eq!(
+            format(&[(6, 8)]),
+            "a+b\n\n\n// keep  \nx && y\n[1, 2]\r\n{'k':1}\n"
+        );
+        assert_eq!(
+            format(&[(2, 6), (8, 20)]),
+            "a+b\n\n\n// keep  \nx && y\n[1,2]\r\n{\"k\": 1}\n"
+        );
+
+        // Errors are positioned in the whole source, even outside the ranges
+        let err = format_cel_many_in_lines("a\nb +", &options, &[1..2, 5..6]).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<FormatError>(),
+            Some(FormatError::Parse { line: 2, .. })
+        ));
+    }
+}
@@ -1,7 +1,7 @@
 /// Configuration options for the CEL formatter
 #[derive(Debug, Clone)]
 pub struct FormatOptions {
-    /// Maximum line width before wrapping
+    /// Maximum line width before wrapping; 0 means never wrap
     pub max_width: usize,
 
     /// Number of spaces per indentation level
@@ -10,8 +10,435 @@ pub struct FormatOptions {
     /// Use spaces for indentation (vs tabs)
     pub use_spaces: bool,
 
-    /// Add trailing commas in multi-line lists/maps
+    /// Exact string for one level of indentation, overriding `indent_width`
+    /// and `use_spaces`; it must not contain a line break
+    pub indent_string: Option<alloc::string::String>,
+
+    /// Add trailing commas in multi-line lists/maps with more than one element
     pub trailing_comma: bool,
+
+    /// Where the `?` and `:` of a broken ternary are placed
+    pub ternary_operator_break: OperatorBreak,
+
+    /// Maximum consecutive blank lines kept between expressions in a file
+    pub max_blank_lines: usize,
+
+    /// Emit numeric literals in one canonical form whatever their source
+    /// spelling (`0x10` becomes `16`, `1.50` becomes `1.5`), even when
+    /// `preserve_literal_spellings` is set
+    ///
+    /// Literals are already canonical unless `preserve_literal_spellings` is
+    /// set, so this only matters to override that option, such as from a
+    /// shared configuration.
+    pub normalize_literals: bool,
+
+    /// Keep numeric literals as spelled in the source (`0x10`, `1.50`,
+    /// `1e3`) instead of writing them in canonical form
+    ///
+    /// Has no effect when `normalize_literals` is set.
+    pub preserve_literal_spellings: bool,
+
+    /// Spacing around the `:` between keys and values in maps and structs
+    pub colon_spacing: ColonSpacing,
+
+    /// Pad the entries of maps and structs that are split over several lines
+    /// so their values start in the same column
+    pub align_values: bool,
+
+    /// Maximum nesting depth of brackets and operators before formatting
+    /// gives up with `FormatError::TooDeep` instead of overflowing the stack
+    pub max_depth: usize,
+
+    /// Maximum size in bytes of the formatted output of one expression,
+    /// beyond which formatting stops with `FormatError::OutputTooLarge`
+    pub max_output_bytes: Option<usize>,
+
+    /// Rewrite map key presence tests between `has(m.k)` and `"k" in m`
+    pub key_presence_style: KeyPresenceStyle,
+
+    /// Write `m["k"]` as `m.k` when `k` is a valid field name
+    ///
+    /// Like `key_presence_style`, this only agrees for maps and messages, so
+    /// it is only safe when every such index in the input is on one. Operands
+    /// that can't be maps (list literals, operator results) keep brackets.
+    pub prefer_dot_access: bool,
+
+    /// How `//` comments are written
+    pub comment_style: CommentStyle,
+
+    /// Break the arguments of calls with more than this many arguments onto
+    /// separate lines, even if the call fits
+    pub max_inline_args: Option<usize>,
+
+    /// Put each entry of maps and structs with more than this many entries on
+    /// its own line, even if the collection fits
+    pub break_map_threshold: Option<usize>,
+
+    /// Write a space between the type name and braces of an empty struct
+    /// (`Foo {}`)
+    pub empty_struct_space_before: bool,
+
+    /// Write a space inside the braces of an empty struct (`Foo{ }`)
+    pub empty_struct_space_inside: bool,
+
+    /// Re-parse the output and fail with `FormatError::ChangedMeaning` unless
+    /// it is the same expression as the source
+    pub verify: bool,
+
+    /// Line ending written between output lines
+    pub line_ending: LineEnding,
+
+    /// Casing of boolean literals
+    pub bool_case: BoolCase,
+
+    /// Write `!(x in y)` as `x not in y`, for dialects with a `not in`
+    /// operator
+    ///
+    /// CEL itself has no such operator, so the output only parses in those
+    /// dialects, and `verify` rejects it.
+    pub not_in_operator: bool,
+
+    /// Words to write binary operators as, keyed by the operator's function
+    /// name, for dialects that accept them: `_&&_` to `and` writes `a and b`
+    ///
+    /// Aliases that aren't plain words, or that are CEL keywords, are
+    /// ignored. `verify` maps aliases back to the operators before reparsing.
+    pub operator_aliases:
+        alloc::collections::BTreeMap<alloc::string::String, alloc::string::String>,
+
+    /// Escape `/` in string literals so output can be embedded in HTML
+    /// `<script>` tags without a `</` sequence
+    pub escape_forward_slash: bool,
+
+    /// Write strings with the shortest escapes: characters that don't need
+    /// escaping are written as themselves, however the source spelled them,
+    /// and other control characters below U+0100 use `\xNN` rather than
+    /// `\uNNNN`
+    pub minimize_escapes: bool,
+
+    /// Split string literals longer than this many characters, quotes
+    /// included, into pieces joined by `+`, breaking after spaces where
+    /// possible; the pieces go on separate lines when they don't fit on one
+    ///
+    /// CEL has no implicit concatenation of adjacent literals (`"a" "b"` is a
+    /// syntax error), so the pieces are added with `+`, which gives the same
+    /// string and formats to itself.
+    pub wrap_long_strings: Option<usize>,
+
+    /// How bytes literals are written
+    pub bytes_literal_style: BytesLiteralStyle,
+
+    /// How the exponent of a double written in scientific notation is
+    /// spelled, such as `1e-3` or `1E-03`
+    ///
+    /// This applies to exponents that `preserve_literal_spellings` keeps from
+    /// the source. Canonical doubles only have an exponent when they are very
+    /// large or small, and it is always written like `1e300`.
+    pub double_exponent_style: DoubleExponentStyle,
+
+    /// How duration values are written as `duration("...")`
+    pub duration_style: DurationStyle,
+
+    /// How timestamp values are written as `timestamp(...)`
+    pub timestamp_style: TimestampStyle,
+
+    /// Where the closing bracket of a broken list, map, or struct goes
+    pub closing_bracket_style: ClosingBracketStyle,
+
+    /// Flip ordering comparisons with a literal on the left so the literal
+    /// goes on the right (`5 < x` becomes `x > 5`)
+    ///
+    /// Only `<`, `<=`, `>`, and `>=` are flipped, and only when the left
+    /// operand is a literal and the right one isn't. CEL expressions have no
+    /// side effects, so operand order never changes the result.
+    pub normalize_comparisons: bool,
+
+    /// Recursion limit passed to the `cel` parser; `None` keeps the parser's
+    /// default
+    ///
+    /// This is the only parser setting the `cel` crate exposes. Macros are
+    /// always expanded and optional syntax (`a.?b`) isn't supported.
+    pub max_recursion_depth: Option<u16>,
+
+    /// Collapse double negations (`!!(a < b)`, `-(-(5))`) and negated
+    /// comparisons (`!(a == b)` becomes `a != b`), and drop redundant boolean
+    /// constants (`(a < b) && true` becomes `a < b`)
+    ///
+    /// `!!` and constants are only dropped next to operands known to be
+    /// bools, and `-(-x)` only around int and double literals, since on
+    /// anything else the operators can be an error.
+    pub simplify: bool,
+
+    /// Sort the list on the right of `in` when its elements are all constants
+    /// of one type: `x in [3, 1, 2]` becomes `x in [1, 2, 3]`
+    ///
+    /// Other lists keep their order, since they may be indexed or compared.
+    pub sort_list_elements: bool,
+
+    /// Keep the source's parentheses around arithmetic (`+`, `-`, `*`, `/`,
+    /// `%`) even where precedence makes them redundant, as in `(a + b) + c`,
+    /// since the grouping can change floating-point rounding
+    pub preserve_arithmetic_parens: bool,
+
+    /// The CEL grammar the output has to be accepted by
+    pub target_version: TargetVersion,
+
+    /// Let chains of two or more method calls, such as
+    /// `xs.filter(x, x > 0).map(x, x * 2)`, break before each `.call()`
+    /// instead of only inside their arguments
+    pub break_member_chains: bool,
+
+    /// When a chain of method calls breaks, keep the first call on the
+    /// receiver's line (`users.filter(...)`) and break only before the rest
+    ///
+    /// Only has an effect with `break_member_chains`.
+    pub chain_first_call_inline: bool,
+
+    /// Let long field paths such as `a.b.c.d` break before each `.`, like
+    /// method chains, instead of keeping them on one line
+    pub break_long_selects: bool,
+
+    /// Always break a root `&&` or `||` chain one operand per line, even when
+    /// the whole expression fits in `max_width`; the operands keep the usual
+    /// layout
+    pub top_level_boolean_expand: bool,
+
+    /// Accept a source ending in one `;`, which is dropped before parsing
+    pub strip_trailing_semicolon: bool,
+
+    /// Warn, without failing, about expressions whose syntax tree nests deeper
+    /// than this; see `format_cel_with_warnings`
+    pub complexity_warn_depth: Option<usize>,
+
+    /// Custom layouts for calls to particular functions
+    #[cfg(feature = "std")]
+    pub custom_formatters: CustomFormatters,
+}
+
+/// A custom layout for calls to one function; `None` falls back to the
+/// formatter's own layout
+#[cfg(feature = "std")]
+pub type CustomFormatter =
+    dyn Fn(&cel::common::ast::CallExpr) -> Option<crate::doc::Doc> + Send + Sync;
+
+/// Custom formatters registered with `FormatOptions::with_custom_formatter`,
+/// keyed by function name
+///
+/// Formatters are shared behind an `Arc`, so cloning options is cheap. They
+/// must be `Send + Sync` so options can be shared between threads that format
+/// concurrently.
+#[cfg(feature = "std")]
+#[derive(Clone, Default)]
+pub struct CustomFormatters(std::collections::HashMap<String, std::sync::Arc<CustomFormatter>>);
+
+#[cfg(feature = "std")]
+impl CustomFormatters {
+    /// Get the formatter registered for a function name
+    pub fn get(&self, name: &str) -> Option<&CustomFormatter> {
+        self.0.get(name).map(|f| f.as_ref())
+    }
+}
+
+#[cfg(feature = "std")]
+impl core::fmt::Debug for CustomFormatters {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let mut names: Vec<&String> = self.0.keys().collect();
+        names.sort();
+        f.debug_set().entries(names).finish()
+    }
+}
+
+/// Placement of the closing bracket of a broken collection
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum ClosingBracketStyle {
+    /// On its own line, lined up with the line that opened it
+    #[default]
+    SameColumnAsOpen,
+
+    /// Right after the last element, with no trailing comma
+    Hugging,
+}
+
+/// Casing of boolean literals
+///
+/// CEL itself only accepts lowercase `true` and `false`; `Upper` is for
+/// dialects that spell them `True` and `False`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum BoolCase {
+    /// `true` and `false`
+    #[default]
+    Lower,
+
+    /// `True` and `False`
+    Upper,
+}
+
+/// How bytes literals are written
+///
+/// CEL has no base64 literal syntax, so `Base64` writes a call to
+/// `base64.decode` from the encoders extension. Only use it where that
+/// extension is enabled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum BytesLiteralStyle {
+    /// `b"..."` with `\xNN` escapes for unprintable bytes
+    #[default]
+    Escaped,
+
+    /// `base64.decode("...")` when that is shorter than the escaped literal
+    Base64,
+}
+
+/// How the exponent of a double in scientific notation is spelled
+///
+/// The sign of the exponent is kept as written; padding writes at least two
+/// exponent digits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum DoubleExponentStyle {
+    /// `1e-3`
+    #[default]
+    Lower,
+
+    /// `1E-3`
+    Upper,
+
+    /// `1e-03`
+    LowerPadded,
+
+    /// `1E-03`
+    UpperPadded,
+}
+
+/// How duration values are written
+///
+/// The parser turns `duration("1h")` into a call, so these only come from
+/// expressions built with duration values in them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum DurationStyle {
+    /// Hours, minutes, and seconds: `duration("1h30m")`
+    #[default]
+    Compact,
+
+    /// Seconds only: `duration("5400s")`
+    Seconds,
+}
+
+/// How timestamp values are written
+///
+/// Like durations, these only come from expressions built with timestamp
+/// values in them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum TimestampStyle {
+    /// An RFC 3339 string in UTC: `timestamp("2023-01-01T00:00:00Z")`
+    #[default]
+    Rfc3339,
+
+    /// Seconds since the Unix epoch: `timestamp(1672531200)`; a timestamp
+    /// with a fraction of a second is written as RFC 3339, since an int
+    /// can't hold it
+    EpochSeconds,
+}
+
+/// The CEL grammar that formatted output has to be accepted by
+///
+/// Macros missing from an older grammar are rewritten with ones it has.
+/// Optional syntax has no older spelling, so a source using it can't be
+/// formatted for a target without it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum TargetVersion {
+    /// Only `has`, `all`, `exists`, `filter`, and two-argument `map`:
+    /// `r.exists_one(v, p)` is written `r.filter(v, p).size() == 1` and
+    /// `r.map(v, p, e)` is written `r.filter(v, p).map(v, e)`
+    Core,
+
+    /// All the standard macros, but no optional syntax
+    Standard,
+
+    /// Everything, including optional selects, indexes, and entries
+    #[default]
+    Latest,
+}
+
+/// Line ending style of the formatted output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum LineEnding {
+    /// `\n`
+    #[default]
+    Lf,
+
+    /// `\r\n`
+    Crlf,
+
+    /// Whichever of the two is more common in the input
+    Auto,
+}
+
+/// How presence tests on map keys are written
+///
+/// `has(m.k)` and `"k" in m` only agree when `m` is a map: on a message
+/// `in` is an error, and on a list it tests membership. The formatter can't
+/// see types, so only pick a rewriting style when every presence test in the
+/// input is on a map. Operands that can't be maps (literals, operator results)
+/// are always left alone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum KeyPresenceStyle {
+    /// Keep presence tests as written
+    #[default]
+    Preserve,
+
+    /// Rewrite `has(m.k)` to `"k" in m`
+    In,
+
+    /// Rewrite `"k" in m` to `has(m.k)` when `k` is a valid field name
+    Has,
+}
+
+/// How `//` comments are written
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum CommentStyle {
+    /// Keep comments as written, apart from trailing whitespace
+    #[default]
+    Preserve,
+
+    /// Put exactly one space between the slashes and the text: `//note` and
+    /// `//   note` become `// note`
+    Normalize,
+}
+
+/// Spacing around the colon in map and struct entries
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum ColonSpacing {
+    /// `key: value`
+    #[default]
+    After,
+
+    /// `key:value`
+    None,
+
+    /// `key : value`
+    Around,
+}
+
+/// Placement of an operator when the expression around it breaks
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum OperatorBreak {
+    /// Operator leads the continuation line
+    #[default]
+    Before,
+
+    /// Operator trails the previous line
+    After,
 }
 
 impl Default for FormatOptions {
@@ -20,7 +447,50 @@ impl Default for FormatOptions {
             max_width: 80,
             indent_width: 2,
             use_spaces: true,
+            indent_string: None,
             trailing_comma: true,
+            ternary_operator_break: OperatorBreak::Before,
+            max_blank_lines: 1,
+            normalize_literals: false,
+            preserve_literal_spellings: false,
+            colon_spacing: ColonSpacing::After,
+            align_values: false,
+            max_depth: 256,
+            max_output_bytes: None,
+            key_presence_style: KeyPresenceStyle::Preserve,
+            prefer_dot_access: false,
+            comment_style: CommentStyle::Preserve,
+            max_inline_args: None,
+            break_map_threshold: None,
+            empty_struct_space_before: false,
+            empty_struct_space_inside: false,
+            verify: false,
+            line_ending: LineEnding::Lf,
+            bool_case: BoolCase::Lower,
+            not_in_operator: false,
+            operator_aliases: alloc::collections::BTreeMap::new(),
+            bytes_literal_style: BytesLiteralStyle::Escaped,
+            double_exponent_style: DoubleExponentStyle::Lower,
+            duration_style: DurationStyle::Compact,
+            timestamp_style: TimestampStyle::Rfc3339,
+            escape_forward_slash: false,
+            minimize_escapes: false,
+            wrap_long_strings: None,
+            simplify: false,
+            sort_list_elements: false,
+            preserve_arithmetic_parens: false,
+            target_version: TargetVersion::default(),
+            closing_bracket_style: ClosingBracketStyle::SameColumnAsOpen,
+            normalize_comparisons: false,
+            max_recursion_depth: None,
+            break_member_chains: false,
+            chain_first_call_inline: false,
+            break_long_selects: false,
+            top_level_boolean_expand: false,
+            complexity_warn_depth: None,
+            strip_trailing_semicolon: false,
+            #[cfg(feature = "std")]
+            custom_formatters: CustomFormatters::default(),
         }
     }
 }
@@ -45,8 +515,235 @@ impl FormatOptions {
         self
     }
 
+    pub fn with_indent_string(mut self, indent: impl Into<alloc::string::String>) -> Self {
+        self.indent_string = Some(indent.into());
+        self
+    }
+
     pub fn with_trailing_comma(mut self, enabled: bool) -> Self {
         self.trailing_comma = enabled;
         self
     }
+
+    pub fn with_ternary_operator_break(mut self, placement: OperatorBreak) -> Self {
+        self.ternary_operator_break = placement;
+        self
+    }
+
+    pub fn with_max_blank_lines(mut self, lines: usize) -> Self {
+        self.max_blank_lines = lines;
+        self
+    }
+
+    pub fn with_normalize_literals(mut self, enabled: bool) -> Self {
+        self.normalize_literals = enabled;
+        self
+    }
+
+    pub fn with_preserve_literal_spellings(mut self, enabled: bool) -> Self {
+        self.preserve_literal_spellings = enabled;
+        self
+    }
+
+    pub fn with_colon_spacing(mut self, spacing: ColonSpacing) -> Self {
+        self.colon_spacing = spacing;
+        self
+    }
+
+    pub fn with_align_values(mut self, enabled: bool) -> Self {
+        self.align_values = enabled;
+        self
+    }
+
+    pub fn with_max_depth(mut self, depth: usize) -> Self {
+        self.max_depth = depth;
+        self
+    }
+
+    pub fn with_max_output_bytes(mut self, limit: Option<usize>) -> Self {
+        self.max_output_bytes = limit;
+        self
+    }
+
+    pub fn with_key_presence_style(mut self, style: KeyPresenceStyle) -> Self {
+        self.key_presence_style = style;
+        self
+    }
+
+    pub fn with_prefer_dot_access(mut self, enabled: bool) -> Self {
+        self.prefer_dot_access = enabled;
+        self
+    }
+
+    pub fn with_comment_style(mut self, style: CommentStyle) -> Self {
+        self.comment_style = style;
+        self
+    }
+
+    pub fn with_max_inline_args(mut self, max: Option<usize>) -> Self {
+        self.max_inline_args = max;
+        self
+    }
+
+    pub fn with_break_map_threshold(mut self, max: Option<usize>) -> Self {
+        self.break_map_threshold = max;
+        self
+    }
+
+    pub fn with_empty_struct_space_before(mut self, enabled: bool) -> Self {
+        self.empty_struct_space_before = enabled;
+        self
+    }
+
+    pub fn with_empty_struct_space_inside(mut self, enabled: bool) -> Self {
+        self.empty_struct_space_inside = enabled;
+        self
+    }
+
+    pub fn with_verify(mut self, enabled: bool) -> Self {
+        self.verify = enabled;
+        self
+    }
+
+    pub fn with_line_ending(mut self, ending: LineEnding) -> Self {
+        self.line_ending = ending;
+        self
+    }
+
+    pub fn with_bool_case(mut self, case: BoolCase) -> Self {
+        self.bool_case = case;
+        self
+    }
+
+    pub fn with_not_in_operator(mut self, enabled: bool) -> Self {
+        self.not_in_operator = enabled;
+        self
+    }
+
+    /// Write the binary operator `op`, named by its function name such as
+    /// `_&&_`, as `alias`
+    pub fn with_operator_alias(
+        mut self,
+        op: impl Into<alloc::string::String>,
+        alias: impl Into<alloc::string::String>,
+    ) -> Self {
+        self.operator_aliases.insert(op.into(), alias.into());
+        self
+    }
+
+    pub fn with_escape_forward_slash(mut self, enabled: bool) -> Self {
+        self.escape_forward_slash = enabled;
+        self
+    }
+
+    pub fn with_minimize_escapes(mut self, enabled: bool) -> Self {
+        self.minimize_escapes = enabled;
+        self
+    }
+
+    pub fn with_wrap_long_strings(mut self, limit: Option<usize>) -> Self {
+        self.wrap_long_strings = limit;
+        self
+    }
+
+    pub fn with_bytes_literal_style(mut self, style: BytesLiteralStyle) -> Self {
+        self.bytes_literal_style = style;
+        self
+    }
+
+    pub fn with_double_exponent_style(mut self, style: DoubleExponentStyle) -> Self {
+        self.double_exponent_style = style;
+        self
+    }
+
+    pub fn with_duration_style(mut self, style: DurationStyle) -> Self {
+        self.duration_style = style;
+        self
+    }
+
+    pub fn with_timestamp_style(mut self, style: TimestampStyle) -> Self {
+        self.timestamp_style = style;
+        self
+    }
+
+    pub fn with_simplify(mut self, enabled: bool) -> Self {
+        self.simplify = enabled;
+        self
+    }
+
+    pub fn with_sort_list_elements(mut self, enabled: bool) -> Self {
+        self.sort_list_elements = enabled;
+        self
+    }
+
+    pub fn with_preserve_arithmetic_parens(mut self, enabled: bool) -> Self {
+        self.preserve_arithmetic_parens = enabled;
+        self
+    }
+
+    pub fn with_target_version(mut self, version: TargetVersion) -> Self {
+        self.target_version = version;
+        self
+    }
+
+    pub fn with_closing_bracket_style(mut self, style: ClosingBracketStyle) -> Self {
+        self.closing_bracket_style = style;
+        self
+    }
+
+    pub fn with_normalize_comparisons(mut self, enabled: bool) -> Self {
+        self.normalize_comparisons = enabled;
+        self
+    }
+
+    pub fn with_max_recursion_depth(mut self, max: Option<u16>) -> Self {
+        self.max_recursion_depth = max;
+        self
+    }
+
+    pub fn with_strip_trailing_semicolon(mut self, enabled: bool) -> Self {
+        self.strip_trailing_semicolon = enabled;
+        self
+    }
+
+    pub fn with_complexity_warn_depth(mut self, depth: Option<usize>) -> Self {
+        self.complexity_warn_depth = depth;
+        self
+    }
+
+    pub fn with_break_member_chains(mut self, enabled: bool) -> Self {
+        self.break_member_chains = enabled;
+        self
+    }
+
+    pub fn with_chain_first_call_inline(mut self, enabled: bool) -> Self {
+        self.chain_first_call_inline = enabled;
+        self
+    }
+
+    pub fn with_break_long_selects(mut self, enabled: bool) -> Self {
+        self.break_long_selects = enabled;
+        self
+    }
+
+    pub fn with_top_level_boolean_expand(mut self, enabled: bool) -> Self {
+        self.top_level_boolean_expand = enabled;
+        self
+    }
+
+    /// Lay out calls to `name` with `formatter` whenever it returns `Some`
+    ///
+    /// `name` is matched against `CallExpr::func_name`, which for a receiver
+    /// call like `ip.inRange(cidr)` is just the method name (`inRange`). The
+    /// formatter runs before any built-in handling, operators included.
+    #[cfg(feature = "std")]
+    pub fn with_custom_formatter<F>(mut self, name: impl Into<String>, formatter: F) -> Self
+    where
+        F: Fn(&cel::common::ast::CallExpr) -> Option<crate::doc::Doc> + Send + Sync + 'static,
+    {
+        self.custom_formatters
+            .0
+            .insert(name.into(), std::sync::Arc::new(formatter));
+        self
+    }
 }
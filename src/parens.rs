@@ -0,0 +1,122 @@
+use cel::common::ast::{Expr, IdedExpr};
+
+use crate::literals::{scan_number, skip_string};
+use crate::simplify::rewrite;
+
+/// Name of the function written before parentheses to keep them
+const MARKER: &str = "__parens__";
+
+/// Name of the call that stands for a pair of parentheses kept from the
+/// source; like `@in`, no source can spell it
+pub(crate) const PARENS: &str = "@parens";
+
+/// Mark the parentheses in `source` that may enclose arithmetic, so that they
+/// survive parsing
+///
+/// The parser drops parentheses, so the name of a marker function is written
+/// before each `(` that opens a group (not a call) with a `+`, `-`, `*`, `/`,
+/// or `%` directly inside it: `(a + b) + c` becomes `__parens__(a + b) + c`.
+/// Returns `None` if there is nothing to mark, or if the source already uses
+/// the marker's name.
+pub(crate) fn mark_arithmetic_parens(source: &str) -> Option<String> {
+    if source.contains(MARKER) {
+        return None;
+    }
+
+    let chars: Vec<char> = source.chars().collect();
+    let mut marks = Vec::new();
+    // For each open bracket: where it is if it opens a group, and whether an
+    // arithmetic operator has been seen directly inside it
+    let mut open: Vec<(Option<usize>, bool)> = Vec::new();
+    let mut after_operand = false;
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+
+        if c == '/' && next == Some('/') {
+            while i < chars.len() && chars[i] != '\n' {
+                i += 1;
+            }
+        } else if c == '"' || c == '\'' {
+            i = skip_string(&chars, i, false);
+            after_operand = true;
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            let word: String = chars[start..i].iter().collect::<String>().to_lowercase();
+            let is_prefix = matches!(word.as_str(), "r" | "b" | "rb" | "br");
+            if is_prefix && matches!(chars.get(i), Some('"') | Some('\'')) {
+                i = skip_string(&chars, i, word.contains('r'));
+            }
+            after_operand = word != "in";
+        } else if c.is_ascii_digit() || (c == '.' && next.is_some_and(|n| n.is_ascii_digit())) {
+            i = scan_number(&chars, i);
+            after_operand = true;
+        } else if matches!(c, '(' | '[' | '{') {
+            open.push(((c == '(' && !after_operand).then_some(i), false));
+            i += 1;
+            after_operand = false;
+        } else if matches!(c, ')' | ']' | '}') {
+            if let Some((Some(start), true)) = open.pop() {
+                marks.push(start);
+            }
+            i += 1;
+            after_operand = true;
+        } else {
+            // `-` is only arithmetic after an operand; before one it negates
+            if matches!(c, '+' | '*' | '/' | '%') || (c == '-' && after_operand) {
+                if let Some((_, arithmetic)) = open.last_mut() {
+                    *arithmetic = true;
+                }
+            }
+            if !c.is_whitespace() {
+                after_operand = false;
+            }
+            i += 1;
+        }
+    }
+
+    if marks.is_empty() {
+        return None;
+    }
+    marks.sort_unstable();
+    let mut marked = String::with_capacity(source.len() + marks.len() * MARKER.len());
+    let mut marks = marks.into_iter().peekable();
+    for (i, c) in chars.into_iter().enumerate() {
+        if marks.next_if_eq(&i).is_some() {
+            marked.push_str(MARKER);
+        }
+        marked.push(c);
+    }
+    Some(marked)
+}
+
+/// Turn the marker calls of a marked source back into parentheses
+///
+/// A marker around arithmetic becomes a `PARENS` call. Any other is replaced
+/// with its argument, leaving those parentheses to the usual precedence rules.
+pub(crate) fn unwrap_parens(expr: &mut IdedExpr) {
+    rewrite(expr, &mut |expr| {
+        let Expr::Call(call) = &mut expr.expr else {
+            return;
+        };
+        if call.func_name != MARKER || call.target.is_some() || call.args.len() != 1 {
+            return;
+        }
+        let keep = matches!(
+            &call.args[0].expr,
+            Expr::Call(inner) if inner.target.is_none()
+                && inner.args.len() == 2
+                && matches!(inner.func_name.as_str(), "_+_" | "_-_" | "_*_" | "_/_" | "_%_")
+        );
+        if keep {
+            call.func_name = PARENS.to_string();
+        } else {
+            *expr = call.args.remove(0);
+        }
+    });
+}
@@ -0,0 +1,270 @@
+//! Property tests that format generated expressions, parse the output back,
+//! and check that it is the expression that was formatted
+
+use cel::common::ast::{
+    CallExpr, ComprehensionExpr, EntryExpr, Expr, IdedEntryExpr, IdedExpr, ListExpr, MapEntryExpr,
+    MapExpr, SelectExpr, StructExpr, StructFieldExpr,
+};
+use cel::common::value::CelVal;
+use cel::parser::Parser;
+use proptest::prelude::*;
+use proptest::sample::select;
+
+use crate::formatter::format_expr;
+use crate::options::FormatOptions;
+
+const NAMES: [&str; 4] = ["a", "b", "x", "request"];
+const FIELDS: [&str; 3] = ["f", "name", "items"];
+const FUNCTIONS: [&str; 3] = ["f", "size", "contains"];
+const BINARY_OPS: [&str; 14] = [
+    "_||_", "_&&_", "_==_", "_!=_", "_<_", "_<=_", "_>_", "_>=_", "@in", "_+_", "_-_", "_*_",
+    "_/_", "_%_",
+];
+const MACROS: [&str; 5] = ["all", "exists", "exists_one", "map", "filter"];
+
+proptest! {
+    #[test]
+    fn format_round_trips(expr in expr(), width in select(vec![0, 20, 80])) {
+        let options = FormatOptions::default().with_max_width(width);
+        let output = format_expr(&expr, &options).render(options.max_width, "  ");
+        let parsed = Parser::new().parse(&output);
+        prop_assert!(parsed.is_ok(), "output doesn't parse:\n{}", output);
+        prop_assert_eq!(shape(&parsed.unwrap()), shape(&expr), "output:\n{}", output);
+    }
+}
+
+/// Expressions of every kind the formatter handles, with macros built the
+/// way the parser expands them
+fn expr() -> impl Strategy<Value = IdedExpr> {
+    leaf().prop_recursive(4, 48, 3, |inner| {
+        prop_oneof![
+            (select(&BINARY_OPS[..]), inner.clone(), inner.clone()).prop_map(|(op, a, b)| call(
+                op,
+                None,
+                vec![a, b]
+            )),
+            (select(&["!_", "-_"][..]), inner.clone()).prop_map(|(op, a)| call(op, None, vec![a])),
+            (inner.clone(), inner.clone(), inner.clone()).prop_map(|(a, b, c)| call(
+                "_?_:_",
+                None,
+                vec![a, b, c]
+            )),
+            (inner.clone(), select(&FIELDS[..]), any::<bool>()).prop_map(
+                |(operand, field, test)| {
+                    node(Expr::Select(SelectExpr {
+                        operand: Box::new(operand),
+                        field: field.to_string(),
+                        test,
+                    }))
+                }
+            ),
+            (inner.clone(), inner.clone()).prop_map(|(a, b)| call("_[_]", None, vec![a, b])),
+            (
+                select(&FUNCTIONS[..]),
+                prop::collection::vec(inner.clone(), 0..3)
+            )
+                .prop_map(|(name, args)| call(name, None, args)),
+            (
+                inner.clone(),
+                select(&FUNCTIONS[..]),
+                prop::collection::vec(inner.clone(), 0..3)
+            )
+                .prop_map(|(target, name, args)| call(name, Some(target), args)),
+            prop::collection::vec(inner.clone(), 0..4)
+                .prop_map(|elements| node(Expr::List(ListExpr { elements }))),
+            prop::collection::vec((inner.clone(), inner.clone()), 0..3).prop_map(|entries| {
+                let entries = entries
+                    .into_iter()
+                    .map(|(key, value)| {
+                        entry(EntryExpr::MapEntry(MapEntryExpr {
+                            key,
+                            value,
+                            optional: false,
+                        }))
+                    })
+                    .collect();
+                node(Expr::Map(MapExpr { entries }))
+            }),
+            prop::collection::vec((select(&FIELDS[..]), inner.clone()), 0..3).prop_map(|fields| {
+                let entries = fields
+                    .into_iter()
+                    .map(|(field, value)| {
+                        entry(EntryExpr::StructField(StructFieldExpr {
+                            field: field.to_string(),
+                            value,
+                            optional: false,
+                        }))
+                    })
+                    .collect();
+                node(Expr::Struct(StructExpr {
+                    type_name: "Msg".to_string(),
+                    entries,
+                }))
+            }),
+            (select(&MACROS[..]), inner.clone(), inner)
+                .prop_map(|(name, range, body)| expand_macro(name, range, body)),
+        ]
+    })
+}
+
+fn leaf() -> impl Strategy<Value = IdedExpr> {
+    prop_oneof![
+        select(&NAMES[..]).prop_map(|name| node(Expr::Ident(name.to_string()))),
+        (-1000i64..1000).prop_map(|n| literal(CelVal::Int(n))),
+        (0u64..1000).prop_map(|n| literal(CelVal::UInt(n))),
+        (-1e6f64..1e6).prop_map(|d| literal(CelVal::Double(d))),
+        prop::collection::vec(any::<char>(), 0..6)
+            .prop_map(|chars| literal(CelVal::String(chars.into_iter().collect()))),
+        prop::collection::vec(any::<u8>(), 0..6).prop_map(|b| literal(CelVal::Bytes(b))),
+        any::<bool>().prop_map(|b| literal(CelVal::Boolean(b))),
+        Just(literal(CelVal::Null)),
+    ]
+}
+
+/// The comprehension that `range.name(v, body)` expands to
+fn expand_macro(name: &str, range: IdedExpr, body: IdedExpr) -> IdedExpr {
+    let accu = || node(Expr::Ident("@result".to_string()));
+    let v = || node(Expr::Ident("v".to_string()));
+    let list = |elements| node(Expr::List(ListExpr { elements }));
+    let (init, cond, step, result) = match name {
+        "all" => (
+            literal(CelVal::Boolean(true)),
+            call("@not_strictly_false", None, vec![accu()]),
+            call("_&&_", None, vec![accu(), body]),
+            accu(),
+        ),
+        "exists" => (
+            literal(CelVal::Boolean(false)),
+            call(
+                "@not_strictly_false",
+                None,
+                vec![call("!_", None, vec![accu()])],
+            ),
+            call("_||_", None, vec![accu(), body]),
+            accu(),
+        ),
+        "exists_one" => (
+            literal(CelVal::Int(0)),
+            literal(CelVal::Boolean(true)),
+            call(
+                "_?_:_",
+                None,
+                vec![
+                    body,
+                    call("_+_", None, vec![accu(), literal(CelVal::Int(1))]),
+                    accu(),
+                ],
+            ),
+            call("_==_", None, vec![accu(), literal(CelVal::Int(1))]),
+        ),
+        "map" => (
+            list(vec![]),
+            literal(CelVal::Boolean(true)),
+            call("_+_", None, vec![accu(), list(vec![body])]),
+            accu(),
+        ),
+        _ => (
+            list(vec![]),
+            literal(CelVal::Boolean(true)),
+            call(
+                "_?_:_",
+                None,
+                vec![
+                    body,
+                    call("_+_", None, vec![accu(), list(vec![v()])]),
+                    accu(),
+                ],
+            ),
+            accu(),
+        ),
+    };
+    node(Expr::Comprehension(Box::new(ComprehensionExpr {
+        iter_range: range,
+        iter_var: "v".to_string(),
+        iter_var2: None,
+        accu_var: "@result".to_string(),
+        accu_init: init,
+        loop_cond: cond,
+        loop_step: step,
+        result,
+    })))
+}
+
+/// An unambiguous rendering of an expression's structure
+///
+/// Chains of `&&` or `||` are flattened: the parser balances them, and the
+/// formatter doesn't keep their grouping, which doesn't affect the result.
+fn shape(expr: &IdedExpr) -> String {
+    let shapes = |exprs: &[IdedExpr]| exprs.iter().map(shape).collect::<Vec<_>>().join(" ");
+    match &expr.expr {
+        Expr::Unspecified => "?".to_string(),
+        Expr::Ident(name) => name.clone(),
+        Expr::Literal(value) => format!("{:?}", value),
+        Expr::Call(call) if matches!(call.func_name.as_str(), "_&&_" | "_||_") => {
+            let mut operands = Vec::new();
+            logical_operands(&call.func_name, expr, &mut operands);
+            format!("({} {})", call.func_name, operands.join(" "))
+        }
+        Expr::Call(call) => {
+            let target = call.target.as_deref().map_or("-".to_string(), shape);
+            format!("({} {} {})", call.func_name, target, shapes(&call.args))
+        }
+        Expr::Select(s) => format!("(select {} {} {})", s.test, shape(&s.operand), s.field),
+        Expr::List(list) => format!("[{}]", shapes(&list.elements)),
+        Expr::Map(map) => format!("{{{}}}", entry_shapes(&map.entries)),
+        Expr::Struct(s) => format!("{}{{{}}}", s.type_name, entry_shapes(&s.entries)),
+        Expr::Comprehension(c) => format!(
+            "(fold {} {} {} {} {} {} {})",
+            c.iter_var,
+            shape(&c.iter_range),
+            c.accu_var,
+            shape(&c.accu_init),
+            shape(&c.loop_cond),
+            shape(&c.loop_step),
+            shape(&c.result)
+        ),
+    }
+}
+
+fn entry_shapes(entries: &[IdedEntryExpr]) -> String {
+    entries
+        .iter()
+        .map(|entry| match &entry.expr {
+            EntryExpr::MapEntry(e) => format!("{}: {}", shape(&e.key), shape(&e.value)),
+            EntryExpr::StructField(f) => format!("{}: {}", f.field, shape(&f.value)),
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// The shapes of the operands of a chain of `op`
+fn logical_operands(op: &str, expr: &IdedExpr, operands: &mut Vec<String>) {
+    match &expr.expr {
+        Expr::Call(call) if call.func_name == op && call.target.is_none() => {
+            for arg in &call.args {
+                logical_operands(op, arg, operands);
+            }
+        }
+        _ => operands.push(shape(expr)),
+    }
+}
+
+fn call(name: &str, target: Option<IdedExpr>, args: Vec<IdedExpr>) -> IdedExpr {
+    node(Expr::Call(CallExpr {
+        func_name: name.to_string(),
+        target: target.map(Box::new),
+        args,
+    }))
+}
+
+fn literal(value: CelVal) -> IdedExpr {
+    node(Expr::Literal(value))
+}
+
+fn entry(expr: EntryExpr) -> IdedEntryExpr {
+    IdedEntryExpr { id: 0, expr }
+}
+
+fn node(expr: Expr) -> IdedExpr {
+    IdedExpr { id: 0, expr }
+}
@@ -0,0 +1,497 @@
+use std::cmp::Ordering;
+
+use cel::common::ast::{
+    CallExpr, ComprehensionExpr, EntryExpr, Expr, IdedExpr, ListExpr, SelectExpr,
+};
+use cel::common::value::CelVal;
+
+use crate::formatter::{is_field_name, may_be_map};
+use crate::options::{KeyPresenceStyle, TargetVersion};
+
+/// Rewrite redundant constructs in place
+///
+/// `!!x` becomes `x` when `x` is plainly a bool, `-(-x)` becomes `x` when `x`
+/// is an int or double literal, and `!(a == b)` and `!(a != b)` become
+/// `a != b` and `a == b`. Redundant boolean constants are dropped too:
+/// `x && true` and `x || false` become `x`, and so do `x == true` and
+/// `x != false`, when `x` is plainly a bool. Children are simplified before
+/// their parents, so a single pass leaves nothing more to simplify.
+///
+/// The guards keep every rewrite exact: `!!x` is an error when `x` isn't a
+/// bool, and `-(-x)` when `x` is a uint or the smallest int, and neither is
+/// rewritten where that could happen.
+pub(crate) fn simplify(expr: &mut IdedExpr) {
+    rewrite(expr, &mut |expr| {
+        if let Some(simplified) = simplify_unary(expr).or_else(|| simplify_constant(expr)) {
+            *expr = simplified;
+        }
+    });
+}
+
+/// Flip ordering comparisons with a literal on the left and something else
+/// on the right, so `5 < x` becomes `x > 5`
+///
+/// CEL expressions have no side effects, so operand order never changes the
+/// result.
+pub(crate) fn normalize_comparisons(expr: &mut IdedExpr) {
+    rewrite(expr, &mut |expr| {
+        let Expr::Call(call) = &mut expr.expr else {
+            return;
+        };
+        let flipped = match call.func_name.as_str() {
+            "_<_" => "_>_",
+            "_<=_" => "_>=_",
+            "_>_" => "_<_",
+            "_>=_" => "_<=_",
+            _ => return,
+        };
+        if let [left @ IdedExpr {
+            expr: Expr::Literal(_),
+            ..
+        }, right] = call.args.as_mut_slice()
+        {
+            if !matches!(right.expr, Expr::Literal(_)) {
+                std::mem::swap(left, right);
+                call.func_name = flipped.to_string();
+            }
+        }
+    });
+}
+
+/// Sort the list literal on the right of each `in` whose elements are all
+/// constants of one type, such as `x in [3, 1, 2]` or `x in ["b", "a"]`
+///
+/// Only there is a list used as a set: anywhere else it may be indexed or
+/// compared, where its order matters. Lists with any other element are left
+/// alone, since the order of expressions can matter. Sorting a sorted list
+/// keeps it as it is.
+pub(crate) fn sort_lists(expr: &mut IdedExpr) {
+    rewrite(expr, &mut |expr| {
+        if let Some(list) = in_list(expr) {
+            sort_list(list);
+        }
+    });
+}
+
+/// The list literal on the right of an `in`
+pub(crate) fn in_list(expr: &mut IdedExpr) -> Option<&mut ListExpr> {
+    match &mut expr.expr {
+        Expr::Call(call) if call.func_name == "@in" && call.target.is_none() => {
+            match call.args.as_mut_slice() {
+                [_, IdedExpr {
+                    expr: Expr::List(list),
+                    ..
+                }] => Some(list),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Sort a list literal if its elements are all constants of one type
+pub(crate) fn sort_list(list: &mut ListExpr) {
+    let comparable = list.elements.windows(2).all(|pair| {
+        matches!(
+            (&pair[0].expr, &pair[1].expr),
+            (Expr::Literal(a), Expr::Literal(b)) if compare_literals(a, b).is_some()
+        )
+    });
+    if comparable {
+        list.elements.sort_by(|a, b| match (&a.expr, &b.expr) {
+            (Expr::Literal(a), Expr::Literal(b)) => {
+                compare_literals(a, b).unwrap_or(Ordering::Equal)
+            }
+            _ => Ordering::Equal,
+        });
+    }
+}
+
+/// Rewrite presence tests on maps into `style`
+///
+/// `KeyPresenceStyle::In` turns `has(m.k)` into `"k" in m`, and
+/// `KeyPresenceStyle::Has` turns `"k" in m` into `has(m.k)` when `k` is a
+/// field name. Tests on operands that can't be maps are left alone.
+pub(crate) fn rewrite_key_presence(expr: &mut IdedExpr, style: KeyPresenceStyle) {
+    rewrite(expr, &mut |expr| {
+        let rewritten = match (&expr.expr, style) {
+            (Expr::Select(select), KeyPresenceStyle::In)
+                if select.test && may_be_map(&select.operand.expr) =>
+            {
+                // has(m.`k-1`) -> "k-1" in m
+                let field = &select.field;
+                let field = match field.strip_prefix('`').and_then(|f| f.strip_suffix('`')) {
+                    Some(unquoted) if !unquoted.is_empty() => unquoted,
+                    _ => field,
+                };
+                let key = node(Expr::Literal(CelVal::String(field.to_string())));
+                Some(call("@in", None, vec![key, (*select.operand).clone()]))
+            }
+            (Expr::Call(call), KeyPresenceStyle::Has)
+                if call.func_name == "@in" && call.target.is_none() =>
+            {
+                match call.args.as_slice() {
+                    [IdedExpr {
+                        expr: Expr::Literal(CelVal::String(key)),
+                        ..
+                    }, map]
+                        if is_field_name(key) && may_be_map(&map.expr) =>
+                    {
+                        Some(node(Expr::Select(SelectExpr {
+                            operand: Box::new(map.clone()),
+                            field: key.to_string(),
+                            test: true,
+                        })))
+                    }
+                    _ => None,
+                }
+            }
+            _ => None,
+        };
+        if let Some(rewritten) = rewritten {
+            *expr = rewritten;
+        }
+    });
+}
+
+/// Rewrite macros that `target` doesn't have with ones it does
+///
+/// Under `TargetVersion::Core`, `r.exists_one(v, p)` becomes
+/// `r.filter(v, p).size() == 1` and `r.map(v, p, e)` becomes
+/// `r.filter(v, p).map(v, e)`. Optional syntax can't be rewritten, so the
+/// first use of it that `target` lacks is returned as an error.
+pub(crate) fn lower_to_target(
+    expr: &mut IdedExpr,
+    target: TargetVersion,
+) -> Result<(), &'static str> {
+    let mut unsupported = None;
+    rewrite(expr, &mut |expr| {
+        if target != TargetVersion::Latest && unsupported.is_none() {
+            unsupported = optional_syntax(expr);
+        }
+        if target != TargetVersion::Core {
+            return;
+        }
+        let Expr::Comprehension(comp) = &expr.expr else {
+            return;
+        };
+        if let Some(lowered) = lower_exists_one(comp).or_else(|| lower_map_filter(comp)) {
+            *expr = lowered;
+        }
+    });
+    unsupported.map_or(Ok(()), Err)
+}
+
+/// Name the optional syntax an expression is written with, if any
+fn optional_syntax(expr: &IdedExpr) -> Option<&'static str> {
+    match &expr.expr {
+        Expr::Call(call) if call.func_name == "_?._" => {
+            Some("optional field selection in the target CEL version")
+        }
+        Expr::Call(call) if call.func_name == "_[?_]" => {
+            Some("an optional index in the target CEL version")
+        }
+        Expr::Map(map) => map
+            .entries
+            .iter()
+            .any(|entry| matches!(&entry.expr, EntryExpr::MapEntry(e) if e.optional))
+            .then_some("an optional map entry in the target CEL version"),
+        Expr::Struct(s) => s
+            .entries
+            .iter()
+            .any(|entry| matches!(&entry.expr, EntryExpr::StructField(f) if f.optional))
+            .then_some("an optional struct field in the target CEL version"),
+        _ => None,
+    }
+}
+
+/// `r.exists_one(v, p)` as `r.filter(v, p).size() == 1`
+fn lower_exists_one(comp: &ComprehensionExpr) -> Option<IdedExpr> {
+    let (predicate, added) = conditional_step(comp)?;
+    let is_one = match &comp.result.expr {
+        Expr::Call(result) if result.func_name == "_==_" => matches!(
+            result.args.as_slice(),
+            [count, one] if is_accu(count, comp) && is_int(one, 1)
+        ),
+        _ => false,
+    };
+    if !is_one || !is_int(&comp.accu_init, 0) || !is_int(added, 1) {
+        return None;
+    }
+
+    let size = call("size", Some(filter(comp, predicate.clone())), vec![]);
+    let one = node(Expr::Literal(CelVal::Int(1)));
+    Some(call("_==_", None, vec![size, one]))
+}
+
+/// `r.map(v, p, e)` as `r.filter(v, p).map(v, e)`
+fn lower_map_filter(comp: &ComprehensionExpr) -> Option<IdedExpr> {
+    let (predicate, added) = conditional_step(comp)?;
+    let Expr::List(list) = &added.expr else {
+        return None;
+    };
+    let [element] = list.elements.as_slice() else {
+        return None;
+    };
+    // `r.map(v, p, v)` is the same comprehension as `r.filter(v, p)`
+    let is_filter = matches!(&element.expr, Expr::Ident(name) if *name == comp.iter_var);
+    if is_filter || !is_empty_list(&comp.accu_init) || !is_accu(&comp.result, comp) {
+        return None;
+    }
+
+    let range = filter(comp, predicate.clone());
+    let step = call("_+_", None, vec![accu(), list_of(element.clone())]);
+    Some(comprehension(range, &comp.iter_var, step))
+}
+
+/// The predicate and the added expression of a loop step
+/// `predicate ? @result + added : @result`, for a comprehension over one
+/// variable with a `true` loop condition
+fn conditional_step(comp: &ComprehensionExpr) -> Option<(&IdedExpr, &IdedExpr)> {
+    let plain = comp.accu_var == "@result"
+        && comp.iter_var2.is_none()
+        && matches!(comp.loop_cond.expr, Expr::Literal(CelVal::Boolean(true)));
+    let Expr::Call(step) = &comp.loop_step.expr else {
+        return None;
+    };
+    let [predicate, then, otherwise] = step.args.as_slice() else {
+        return None;
+    };
+    if !plain || step.func_name != "_?_:_" || !is_accu(otherwise, comp) {
+        return None;
+    }
+    let Expr::Call(add) = &then.expr else {
+        return None;
+    };
+    match add.args.as_slice() {
+        [accu, added] if add.func_name == "_+_" && is_accu(accu, comp) => Some((predicate, added)),
+        _ => None,
+    }
+}
+
+/// `r.filter(v, predicate)` over the range and variable of `comp`
+fn filter(comp: &ComprehensionExpr, predicate: IdedExpr) -> IdedExpr {
+    let variable = node(Expr::Ident(comp.iter_var.clone()));
+    let add = call("_+_", None, vec![accu(), list_of(variable)]);
+    let step = call("_?_:_", None, vec![predicate, add, accu()]);
+    comprehension(comp.iter_range.clone(), &comp.iter_var, step)
+}
+
+/// A comprehension collecting a list in `@result`, as `map` and `filter`
+/// expand to
+fn comprehension(range: IdedExpr, iter_var: &str, step: IdedExpr) -> IdedExpr {
+    node(Expr::Comprehension(Box::new(ComprehensionExpr {
+        iter_range: range,
+        iter_var: iter_var.to_string(),
+        iter_var2: None,
+        accu_var: "@result".to_string(),
+        accu_init: node(Expr::List(ListExpr { elements: vec![] })),
+        loop_cond: node(Expr::Literal(CelVal::Boolean(true))),
+        loop_step: step,
+        result: accu(),
+    })))
+}
+
+fn call(name: &str, target: Option<IdedExpr>, args: Vec<IdedExpr>) -> IdedExpr {
+    node(Expr::Call(CallExpr {
+        func_name: name.to_string(),
+        target: target.map(Box::new),
+        args,
+    }))
+}
+
+fn list_of(element: IdedExpr) -> IdedExpr {
+    node(Expr::List(ListExpr {
+        elements: vec![element],
+    }))
+}
+
+fn accu() -> IdedExpr {
+    node(Expr::Ident("@result".to_string()))
+}
+
+/// A new node; ids are only used for source positions, which rewritten
+/// nodes don't have
+fn node(expr: Expr) -> IdedExpr {
+    IdedExpr { id: 0, expr }
+}
+
+fn is_accu(expr: &IdedExpr, comp: &ComprehensionExpr) -> bool {
+    matches!(&expr.expr, Expr::Ident(name) if *name == comp.accu_var)
+}
+
+fn is_int(expr: &IdedExpr, value: i64) -> bool {
+    matches!(expr.expr, Expr::Literal(CelVal::Int(n)) if n == value)
+}
+
+fn is_empty_list(expr: &IdedExpr) -> bool {
+    matches!(&expr.expr, Expr::List(list) if list.elements.is_empty())
+}
+
+/// Order two literals of the same type, or `None` for different types
+fn compare_literals(a: &CelVal, b: &CelVal) -> Option<Ordering> {
+    match (a, b) {
+        (CelVal::Int(a), CelVal::Int(b)) => Some(a.cmp(b)),
+        (CelVal::UInt(a), CelVal::UInt(b)) => Some(a.cmp(b)),
+        (CelVal::Double(a), CelVal::Double(b)) => Some(a.total_cmp(b)),
+        (CelVal::String(a), CelVal::String(b)) => Some(a.cmp(b)),
+        (CelVal::Bytes(a), CelVal::Bytes(b)) => Some(a.cmp(b)),
+        (CelVal::Boolean(a), CelVal::Boolean(b)) => Some(a.cmp(b)),
+        _ => None,
+    }
+}
+
+/// Apply `f` to every node of an expression, children before their parents
+pub(crate) fn rewrite(expr: &mut IdedExpr, f: &mut impl FnMut(&mut IdedExpr)) {
+    match &mut expr.expr {
+        Expr::Unspecified | Expr::Ident(_) | Expr::Literal(_) => {}
+        Expr::Select(select) => rewrite(&mut select.operand, f),
+        Expr::Call(call) => {
+            if let Some(target) = &mut call.target {
+                rewrite(target, f);
+            }
+            for arg in &mut call.args {
+                rewrite(arg, f);
+            }
+        }
+        Expr::List(list) => {
+            for element in &mut list.elements {
+                rewrite(element, f);
+            }
+        }
+        Expr::Map(map) => {
+            for entry in &mut map.entries {
+                if let EntryExpr::MapEntry(entry) = &mut entry.expr {
+                    rewrite(&mut entry.key, f);
+                    rewrite(&mut entry.value, f);
+                }
+            }
+        }
+        Expr::Struct(s) => {
+            for entry in &mut s.entries {
+                if let EntryExpr::StructField(field) = &mut entry.expr {
+                    rewrite(&mut field.value, f);
+                }
+            }
+        }
+        Expr::Comprehension(comp) => {
+            for part in [
+                &mut comp.iter_range,
+                &mut comp.accu_init,
+                &mut comp.loop_cond,
+                &mut comp.loop_step,
+                &mut comp.result,
+            ] {
+                rewrite(part, f);
+            }
+        }
+    }
+
+    f(expr);
+}
+
+/// Simplify a unary operator applied to an operator it cancels or inverts
+fn simplify_unary(expr: &mut IdedExpr) -> Option<IdedExpr> {
+    let Expr::Call(outer) = &mut expr.expr else {
+        return None;
+    };
+    if outer.target.is_some() || outer.args.len() != 1 {
+        return None;
+    }
+    let Expr::Call(inner) = &mut outer.args[0].expr else {
+        return None;
+    };
+    if inner.target.is_some() {
+        return None;
+    }
+
+    match (outer.func_name.as_str(), inner.func_name.as_str()) {
+        // `!!x` is an error rather than `x` when `x` isn't a bool
+        ("!_", "!_") if inner.args.len() == 1 && is_bool(&inner.args[0]) => inner.args.pop(),
+        // So is `-(-x)` when `x` is a uint or the smallest int
+        ("-_", "-_") if inner.args.len() == 1 && is_negatable(&inner.args[0]) => inner.args.pop(),
+        ("!_", "_==_") | ("!_", "_!=_") if inner.args.len() == 2 => {
+            inner.func_name = if inner.func_name == "_==_" {
+                "_!=_".to_string()
+            } else {
+                "_==_".to_string()
+            };
+            outer.args.pop()
+        }
+        _ => None,
+    }
+}
+
+/// Drop a boolean constant that doesn't change the value of the operator it
+/// is an operand of, keeping the other operand
+fn simplify_constant(expr: &mut IdedExpr) -> Option<IdedExpr> {
+    let Expr::Call(call) = &mut expr.expr else {
+        return None;
+    };
+    if call.target.is_some() || call.args.len() != 2 {
+        return None;
+    }
+
+    // The constant that can be dropped. The other operand has to be known to
+    // be a bool: `1 == true` is false and `"s" && true` is an error, neither
+    // of them the other operand
+    let identity = match call.func_name.as_str() {
+        "_&&_" | "_==_" => true,
+        "_||_" | "_!=_" => false,
+        _ => return None,
+    };
+    let is_identity =
+        |arg: &IdedExpr| matches!(arg.expr, Expr::Literal(CelVal::Boolean(b)) if b == identity);
+    let keep = if is_identity(&call.args[1]) {
+        0
+    } else if is_identity(&call.args[0]) {
+        1
+    } else {
+        return None;
+    };
+    if !is_bool(&call.args[keep]) {
+        return None;
+    }
+    Some(call.args.swap_remove(keep))
+}
+
+/// Check if an expression is an int or double literal that negates without
+/// overflow
+fn is_negatable(expr: &IdedExpr) -> bool {
+    match expr.expr {
+        Expr::Literal(CelVal::Int(n)) => n != i64::MIN,
+        Expr::Literal(CelVal::Double(_)) => true,
+        _ => false,
+    }
+}
+
+/// Check if an expression can only evaluate to a bool (or an error)
+fn is_bool(expr: &IdedExpr) -> bool {
+    match &expr.expr {
+        Expr::Literal(CelVal::Boolean(_)) => true,
+        Expr::Select(select) => select.test,
+        Expr::Call(call) => matches!(
+            call.func_name.as_str(),
+            "!_" | "_&&_"
+                | "_||_"
+                | "_==_"
+                | "_!=_"
+                | "_<_"
+                | "_<=_"
+                | "_>_"
+                | "_>=_"
+                | "@in"
+                | "startsWith"
+                | "endsWith"
+                | "contains"
+                | "matches"
+        ),
+        // `all` and `exists` accumulate a bool; `exists_one` ends in `==`
+        Expr::Comprehension(comp) => {
+            is_bool(&comp.result)
+                || (is_accu(&comp.result, comp)
+                    && matches!(comp.accu_init.expr, Expr::Literal(CelVal::Boolean(_))))
+        }
+        _ => false,
+    }
+}
@@ -0,0 +1,149 @@
+use cel::common::ast::{CallExpr, EntryExpr, Expr, IdedEntryExpr, IdedExpr};
+use cel::common::value::CelVal;
+
+use crate::literals::encode_base64;
+use crate::options::{BytesLiteralStyle, FormatOptions};
+use crate::simplify::{in_list, sort_list};
+
+/// Check if two parsed expressions have the same structure
+///
+/// Node ids depend on how the source was laid out, so they are ignored;
+/// everything else (names, literal values, argument order) must match, except
+/// where an option rewrites `a` into an equivalent `b`:
+///
+/// - with `BytesLiteralStyle::Base64`, a bytes literal matches the
+///   `base64.decode("...")` call written for it
+/// - with `prefer_dot_access`, an index `m["k"]` matches the select `m.k`
+/// - with `wrap_long_strings`, a string literal matches the `+` of its pieces
+/// - with `sort_list_elements`, the list on the right of an `in` matches the
+///   sorted list
+pub(crate) fn same_expr(a: &IdedExpr, b: &IdedExpr, options: &FormatOptions) -> bool {
+    match (&a.expr, &b.expr) {
+        (Expr::Unspecified, Expr::Unspecified) => true,
+        (Expr::Ident(a), Expr::Ident(b)) => a == b,
+        (Expr::Literal(a), Expr::Literal(b)) => a == b,
+        (Expr::Literal(CelVal::Bytes(bytes)), Expr::Call(call))
+            if options.bytes_literal_style == BytesLiteralStyle::Base64 =>
+        {
+            is_base64_decode(call, bytes)
+        }
+        (Expr::Literal(CelVal::String(s)), Expr::Call(_))
+            if options.wrap_long_strings.is_some() =>
+        {
+            concatenated_string(b).is_some_and(|joined| joined == **s)
+        }
+        (Expr::Call(call), Expr::Select(select)) if options.prefer_dot_access && !select.test => {
+            is_string_index(call, &select.field, &select.operand, options)
+        }
+        (Expr::Select(a), Expr::Select(b)) => {
+            a.field == b.field && a.test == b.test && same_expr(&a.operand, &b.operand, options)
+        }
+        (Expr::Call(call), Expr::Call(_))
+            if options.sort_list_elements && call.func_name == "@in" =>
+        {
+            let mut sorted = a.clone();
+            match in_list(&mut sorted) {
+                Some(list) if !list.elements.is_empty() => {
+                    sort_list(list);
+                    same_call(&sorted, b, options)
+                }
+                _ => same_call(a, b, options),
+            }
+        }
+        (Expr::Call(_), Expr::Call(_)) => same_call(a, b, options),
+        (Expr::List(a), Expr::List(b)) => same_exprs(&a.elements, &b.elements, options),
+        (Expr::Map(a), Expr::Map(b)) => same_entries(&a.entries, &b.entries, options),
+        (Expr::Struct(a), Expr::Struct(b)) => {
+            a.type_name == b.type_name && same_entries(&a.entries, &b.entries, options)
+        }
+        (Expr::Comprehension(a), Expr::Comprehension(b)) => {
+            a.iter_var == b.iter_var
+                && a.iter_var2 == b.iter_var2
+                && a.accu_var == b.accu_var
+                && same_expr(&a.iter_range, &b.iter_range, options)
+                && same_expr(&a.accu_init, &b.accu_init, options)
+                && same_expr(&a.loop_cond, &b.loop_cond, options)
+                && same_expr(&a.loop_step, &b.loop_step, options)
+                && same_expr(&a.result, &b.result, options)
+        }
+        _ => false,
+    }
+}
+
+/// Check if two calls have the same function, target, and arguments
+fn same_call(a: &IdedExpr, b: &IdedExpr, options: &FormatOptions) -> bool {
+    let (Expr::Call(a), Expr::Call(b)) = (&a.expr, &b.expr) else {
+        return false;
+    };
+    let same_target = match (&a.target, &b.target) {
+        (Some(a), Some(b)) => same_expr(a, b, options),
+        (None, None) => true,
+        _ => false,
+    };
+    a.func_name == b.func_name && same_target && same_exprs(&a.args, &b.args, options)
+}
+
+/// Check if a call is `base64.decode("...")` of exactly `bytes`
+fn is_base64_decode(call: &CallExpr, bytes: &[u8]) -> bool {
+    let is_base64 = call
+        .target
+        .as_ref()
+        .is_some_and(|target| matches!(&target.expr, Expr::Ident(name) if name == "base64"));
+    match call.args.as_slice() {
+        [arg] if is_base64 && call.func_name == "decode" => {
+            matches!(&arg.expr, Expr::Literal(CelVal::String(s)) if *s == encode_base64(bytes))
+        }
+        _ => false,
+    }
+}
+
+/// The value of a string literal or a `+` of string literals
+fn concatenated_string(expr: &IdedExpr) -> Option<String> {
+    match &expr.expr {
+        Expr::Literal(CelVal::String(s)) => Some(s.to_string()),
+        Expr::Call(call) if call.func_name == "_+_" && call.target.is_none() => {
+            match call.args.as_slice() {
+                [left, right] => Some(concatenated_string(left)? + &concatenated_string(right)?),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Check if a call is `operand["field"]`
+fn is_string_index(
+    call: &CallExpr,
+    field: &str,
+    operand: &IdedExpr,
+    options: &FormatOptions,
+) -> bool {
+    match call.args.as_slice() {
+        [target, key] if call.func_name == "_[_]" && call.target.is_none() => {
+            matches!(&key.expr, Expr::Literal(CelVal::String(s)) if **s == *field)
+                && same_expr(target, operand, options)
+        }
+        _ => false,
+    }
+}
+
+fn same_exprs(a: &[IdedExpr], b: &[IdedExpr], options: &FormatOptions) -> bool {
+    a.len() == b.len() && a.iter().zip(b).all(|(a, b)| same_expr(a, b, options))
+}
+
+fn same_entries(a: &[IdedEntryExpr], b: &[IdedEntryExpr], options: &FormatOptions) -> bool {
+    a.len() == b.len()
+        && a.iter().zip(b).all(|(a, b)| match (&a.expr, &b.expr) {
+            (EntryExpr::MapEntry(a), EntryExpr::MapEntry(b)) => {
+                a.optional == b.optional
+                    && same_expr(&a.key, &b.key, options)
+                    && same_expr(&a.value, &b.value, options)
+            }
+            (EntryExpr::StructField(a), EntryExpr::StructField(b)) => {
+                a.field == b.field
+                    && a.optional == b.optional
+                    && same_expr(&a.value, &b.value, options)
+            }
+            _ => false,
+        })
+}
@@ -1,6 +1,7 @@
+use serde::{Deserialize, Serialize};
 use wasm_bindgen::prelude::*;
 
-use crate::{format_cel, FormatOptions};
+use crate::{analyze, format_cel, FormatError, FormatOptions};
 
 /// Initialize the WASM module with panic hook for better error messages
 #[wasm_bindgen(start)]
@@ -36,6 +37,119 @@ pub fn format_with_options(
     format_cel(source, &options).map_err(|e| e.to_string())
 }
 
+/// Options accepted by `format_rich`; missing fields keep their defaults
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct RichConfig {
+    max_width: Option<usize>,
+    indent_width: Option<usize>,
+    use_tabs: bool,
+    trailing_comma: Option<bool>,
+}
+
+impl RichConfig {
+    fn options(&self) -> FormatOptions {
+        let mut options = FormatOptions::default();
+        if let Some(width) = self.max_width {
+            options = options.with_max_width(width);
+        }
+        if let Some(width) = self.indent_width {
+            options = options.with_indent_width(width);
+        }
+        if let Some(enabled) = self.trailing_comma {
+            options = options.with_trailing_comma(enabled);
+        }
+        if self.use_tabs {
+            options = options.with_tabs();
+        }
+        options
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+struct RichResult {
+    formatted: Option<String>,
+    changed: bool,
+    errors: Vec<RichError>,
+    stats: Option<RichStats>,
+}
+
+#[derive(Debug, Serialize)]
+struct RichError {
+    message: String,
+    line: Option<usize>,
+    column: Option<usize>,
+}
+
+impl RichError {
+    fn new(error: &anyhow::Error) -> Self {
+        let (line, column) = match error.downcast_ref::<FormatError>() {
+            Some(FormatError::Parse { line, column, .. }) => (Some(*line), Some(*column)),
+            _ => (None, None),
+        };
+        Self {
+            message: error.to_string(),
+            line,
+            column,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct RichStats {
+    node_count: usize,
+    wrapped: bool,
+}
+
+/// Format a CEL expression, returning the output along with diagnostics
+///
+/// `config` is an object with optional `maxWidth`, `indentWidth`, `useTabs`,
+/// and `trailingComma` fields, or `undefined` for the defaults. The result is
+/// always an object; nothing is thrown:
+///
+/// ```text
+/// {
+///   formatted: string | null,  // null when formatting failed
+///   changed: boolean,          // formatted differs from source
+///   errors: [{ message: string, line: number | null, column: number | null }],
+///   stats: { nodeCount: number, wrapped: boolean } | null,
+/// }
+/// ```
+///
+/// `line` and `column` are 1-based and only set for parse errors. `wrapped`
+/// tells whether the output spans more than one line.
+#[wasm_bindgen]
+pub fn format_rich(source: &str, config: JsValue) -> JsValue {
+    let mut result = RichResult::default();
+    let config = if config.is_undefined() || config.is_null() {
+        Ok(RichConfig::default())
+    } else {
+        serde_wasm_bindgen::from_value::<RichConfig>(config)
+    };
+
+    match config {
+        Ok(config) => match format_cel(source, &config.options()) {
+            Ok(formatted) => {
+                result.changed = formatted != source;
+                result.stats = analyze(source).ok().map(|stats| RichStats {
+                    node_count: stats.nodes.total(),
+                    wrapped: formatted.contains('\n'),
+                });
+                result.formatted = Some(formatted);
+            }
+            Err(e) => result.errors.push(RichError::new(&e)),
+        },
+        Err(e) => result.errors.push(RichError {
+            message: format!("Invalid config: {}", e),
+            line: None,
+            column: None,
+        }),
+    }
+
+    serde_wasm_bindgen::to_value(&result).unwrap_or(JsValue::NULL)
+}
+
 /// Get the version of the formatter
 #[wasm_bindgen]
 pub fn version() -> String {
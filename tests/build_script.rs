@@ -0,0 +1,55 @@
+//! Formatting the way a build script or proc macro would: through the public
+//! API only, turning errors into diagnostics instead of exiting
+#![cfg(feature = "std")]
+
+use cel_fmt::{format_cel, FormatError, FormatOptions};
+
+/// What a build script does with an embedded expression: format it, or
+/// describe why it can't be formatted
+fn check(source: &str) -> Result<String, String> {
+    let options = FormatOptions::default().with_verify(true);
+    format_cel(source, &options).map_err(|err| match err.downcast_ref::<FormatError>() {
+        Some(FormatError::Parse { line, column, .. }) => {
+            format!("invalid CEL at {}:{}: {}", line, column, err)
+        }
+        _ => err.to_string(),
+    })
+}
+
+#[test]
+fn test_format_literal() {
+    assert_eq!(
+        check("request.auth.claims['group']=='admin'&&size(request.path)>0").unwrap(),
+        "request.auth.claims[\"group\"] == \"admin\" && size(request.path) > 0"
+    );
+}
+
+#[test]
+fn test_invalid_source() {
+    for source in ["a &&", "'unterminated", "[1, 2", "a ? b", "has(a)"] {
+        let message = check(source).unwrap_err();
+        assert!(message.starts_with("invalid CEL at 1:"), "{}", message);
+    }
+}
+
+#[test]
+fn test_send_sync() {
+    fn assert_send_sync<T: Send + Sync + 'static>() {}
+    assert_send_sync::<FormatOptions>();
+    assert_send_sync::<FormatError>();
+    assert_send_sync::<anyhow::Error>();
+
+    // A proc macro may expand on any thread, sharing one set of options
+    let options = std::sync::Arc::new(FormatOptions::default());
+    let handles: Vec<_> = ["a+b", "[1,2,3]", "a &&"]
+        .into_iter()
+        .map(|source| {
+            let options = options.clone();
+            std::thread::spawn(move || format_cel(source, &options).map_err(|e| e.to_string()))
+        })
+        .collect();
+    let results: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+    assert_eq!(results[0].as_deref(), Ok("a + b"));
+    assert_eq!(results[1].as_deref(), Ok("[1, 2, 3]"));
+    assert!(results[2].is_err());
+}
@@ -0,0 +1,105 @@
+//! `--staged` against a real git repository, through the command-line tool
+#![cfg(feature = "cli")]
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+/// A new git repository in the temporary directory, removed when dropped
+struct Repo(PathBuf);
+
+impl Repo {
+    fn new(name: &str) -> Repo {
+        let dir = std::env::temp_dir().join(format!("cel-fmt-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let repo = Repo(dir);
+        repo.git(&["init", "-q"]);
+        repo.git(&["config", "user.name", "cel-fmt"]);
+        repo.git(&["config", "user.email", "cel-fmt@example.com"]);
+        repo
+    }
+
+    fn git(&self, args: &[&str]) {
+        let status = Command::new("git")
+            .arg("-C")
+            .arg(&self.0)
+            .args(args)
+            .status()
+            .unwrap();
+        assert!(status.success(), "git {:?}", args);
+    }
+
+    fn write(&self, name: &str, contents: &str) {
+        fs::write(self.0.join(name), contents).unwrap();
+    }
+
+    fn cel_fmt(&self, args: &[&str]) -> Output {
+        Command::new(env!("CARGO_BIN_EXE_cel-fmt"))
+            .current_dir(&self.0)
+            .args(args)
+            .output()
+            .unwrap()
+    }
+
+    fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for Repo {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+const COMMITTED: &str = "a==b\n\nc==d\n\ne==f\n";
+
+#[test]
+fn test_staged_formats_staged_lines() {
+    let repo = Repo::new("staged");
+    repo.write("rules.cel", COMMITTED);
+    repo.git(&["add", "rules.cel"]);
+    repo.git(&["commit", "-q", "-m", "rules"]);
+
+    repo.write("rules.cel", "a==b\n\nc==x\n\ne==f\n");
+    repo.git(&["add", "rules.cel"]);
+    let output = repo.cel_fmt(&["--staged", "--print", "rules.cel"]);
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains(" 0 errors"), "{}", stderr);
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "a==b\n\nc == x\n\ne==f\n"
+    );
+}
+
+#[test]
+fn test_staged_refuses_unstaged_changes() {
+    let repo = Repo::new("unstaged");
+    repo.write("rules.cel", COMMITTED);
+    repo.git(&["add", "rules.cel"]);
+    repo.git(&["commit", "-q", "-m", "rules"]);
+
+    // Line 3 is staged, but a line added above it moves it to line 4 in the
+    // working tree
+    repo.write("rules.cel", "a==b\n\nc==x\n\ne==f\n");
+    repo.git(&["add", "rules.cel"]);
+    repo.write("rules.cel", "// new\na==b\n\nc==x\n\ne==f\n");
+    let output = repo.cel_fmt(&["--staged", "rules.cel"]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("has unstaged changes"), "{}", stderr);
+    assert_eq!(
+        fs::read_to_string(repo.path().join("rules.cel")).unwrap(),
+        "// new\na==b\n\nc==x\n\ne==f\n"
+    );
+}
+
+#[test]
+fn test_staged_needs_files() {
+    let repo = Repo::new("no-files");
+    let output = repo.cel_fmt(&["--staged"]);
+    assert_eq!(output.status.code(), Some(2));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("need files"), "{}", stderr);
+}